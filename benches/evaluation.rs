@@ -0,0 +1,84 @@
+//! STAR Randomness web service benchmarks
+//!
+//! Measures PPOPRF evaluation throughput for a range of batch sizes.
+//! Run with `cargo bench`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use curve25519_dalek::ristretto::RistrettoPoint;
+use rand::rngs::OsRng;
+
+use std::sync::{Arc, RwLock};
+
+use star_randsrv::eval::{
+    decode_points, evaluate_batch, evaluate_chunked, evaluate_point, evaluate_points, CHUNK_SIZE,
+};
+use star_randsrv::state::OPRFServer;
+use star_randsrv::{Config, MAX_POINTS};
+
+/// Generate random compressed points to evaluate
+fn make_points(count: usize) -> Vec<Vec<u8>> {
+    (0..count)
+        .map(|_| {
+            RistrettoPoint::random(&mut OsRng)
+                .compress()
+                .to_bytes()
+                .to_vec()
+        })
+        .collect()
+}
+
+fn evaluation(c: &mut Criterion) {
+    let config = Config::try_load_from(["star-randsrv"]).expect("Default config is valid");
+    let server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+    let epoch = server.current_epoch();
+
+    // Evaluation of a single, already-decoded point
+    let point = ppoprf::ppoprf::Point::from(make_points(1)[0].as_slice());
+    let mut group = c.benchmark_group("evaluate_point");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("single", |b| {
+        b.iter(|| evaluate_point(&server.server, 0, &point, epoch).unwrap())
+    });
+    group.finish();
+
+    // Batches of already-decoded points
+    // This is the baseline for any batched ppoprf evaluation.
+    let mut group = c.benchmark_group("evaluate_points");
+    for size in [1, 16, 128, MAX_POINTS] {
+        let points = decode_points(&make_points(size)).unwrap();
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &points, |b, points| {
+            b.iter(|| evaluate_points(&server.server, points, epoch).unwrap())
+        });
+    }
+    group.finish();
+
+    // Whole batches, including point validation
+    let mut group = c.benchmark_group("evaluate_batch");
+    for size in [1, 16, 128, MAX_POINTS] {
+        let points = make_points(size);
+        // Report points per second.
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &points, |b, points| {
+            b.iter(|| evaluate_batch(&server, points, None).unwrap())
+        });
+    }
+    group.finish();
+
+    // Whole batches as the handler evaluates them, locking the
+    // state and checking the epoch again for each chunk. Compare
+    // with `evaluate_batch`, which locks once.
+    let state = Arc::new(RwLock::new(server));
+    let mut group = c.benchmark_group("evaluate_chunked");
+    for size in [1, CHUNK_SIZE, CHUNK_SIZE + 1, MAX_POINTS] {
+        let points = make_points(size);
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &points, |b, points| {
+            b.iter(|| evaluate_chunked(&state, points, None).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, evaluation);
+criterion_main!(benches);
@@ -0,0 +1,68 @@
+//! Feed arbitrary bytes to the /randomness endpoint
+//!
+//! The handler must never panic, and must always answer with
+//! either evaluated points or a well-formed error envelope.
+
+#![no_main]
+
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use axum::Router;
+use libfuzzer_sys::fuzz_target;
+use serde_json::Value;
+use std::sync::{Arc, RwLock};
+use tokio::runtime::Runtime;
+use tower::ServiceExt;
+
+use star_randsrv::state::OPRFServer;
+use star_randsrv::Config;
+
+thread_local! {
+    /// Runtime and app shared across fuzz iterations
+    /// Generating a fresh key for every input would dominate the run.
+    static APP: (Runtime, Router) = {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("Could not start runtime");
+        let config = Config::try_load_from(["star-randsrv"]).expect("Default config is valid");
+        let server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+        let app = star_randsrv::app(Arc::new(RwLock::new(server)), &config);
+        (runtime, app)
+    };
+}
+
+fuzz_target!(|data: &[u8]| {
+    let request = Request::builder()
+        .uri("/randomness")
+        .method("POST")
+        .header("Content-Type", "application/json")
+        .body(Body::from(data.to_vec()))
+        .unwrap();
+    let (status, body) = APP.with(|(runtime, app)| {
+        runtime.block_on(async {
+            let response = app.clone().oneshot(request).await.unwrap();
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            (status, body)
+        })
+    });
+
+    let json: Value = serde_json::from_slice(&body).expect("response should be json");
+    match status {
+        StatusCode::OK => {
+            assert!(json["points"].is_array(), "missing points: {json}");
+            assert!(json["epoch"].is_u64(), "missing epoch: {json}");
+        }
+        StatusCode::BAD_REQUEST
+        | StatusCode::PAYLOAD_TOO_LARGE
+        | StatusCode::UNPROCESSABLE_ENTITY => {
+            assert!(json["error"]["code"].is_string(), "missing code: {json}");
+            assert!(
+                json["error"]["message"].is_string(),
+                "missing message: {json}"
+            );
+        }
+        _ => panic!("unexpected status {status}: {json}"),
+    }
+});
@@ -0,0 +1,96 @@
+//! STAR Randomness web service
+//! Access logging in common log format
+
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::{header, Method, Request, StatusCode, Uri, Version};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
+use tracing::info;
+
+use crate::ratelimit::client_addr;
+
+/// Summary of a completed request for the access log
+pub struct AccessRecord {
+    /// Address of the client, if known
+    pub client: Option<IpAddr>,
+    /// When the request was received
+    pub time: OffsetDateTime,
+    pub method: Method,
+    pub uri: Uri,
+    pub version: Version,
+    pub status: StatusCode,
+    /// Length of the response body, if known in advance
+    /// Streamed responses don't have one.
+    pub bytes: Option<u64>,
+    /// Time taken to produce the response headers
+    pub duration: Duration,
+}
+
+impl fmt::Display for AccessRecord {
+    /// Format as a common log format line, followed by the
+    /// duration in seconds
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let time = self.time.to_offset(time::UtcOffset::UTC);
+        match self.client {
+            Some(client) => write!(f, "{client}")?,
+            None => f.write_str("-")?,
+        }
+        write!(
+            f,
+            " - - [{:02}/{}/{}:{:02}:{:02}:{:02} +0000] \"{} {} {:?}\" {} ",
+            time.day(),
+            &time.month().to_string()[..3],
+            time.year(),
+            time.hour(),
+            time.minute(),
+            time.second(),
+            self.method,
+            self.uri,
+            self.version,
+            self.status.as_u16(),
+        )?;
+        match self.bytes {
+            Some(bytes) => write!(f, "{bytes}")?,
+            None => f.write_str("-")?,
+        }
+        write!(f, " {:.6}", self.duration.as_secs_f64())
+    }
+}
+
+/// Middleware logging a line for each request
+/// The state says whether to trust `X-Forwarded-For` for the
+/// client address, as with rate limiting.
+pub async fn log(
+    State(trust_proxy): State<bool>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let time = OffsetDateTime::now_utc();
+    let started = Instant::now();
+    let client = client_addr(&request, trust_proxy);
+    let method = request.method().clone();
+    let uri = request.uri().clone();
+    let version = request.version();
+    let response = next.run(request).await;
+    let record = AccessRecord {
+        client,
+        time,
+        method,
+        uri,
+        version,
+        status: response.status(),
+        bytes: response
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok()),
+        duration: started.elapsed(),
+    };
+    info!(target: "access", "{record}");
+    response
+}
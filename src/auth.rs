@@ -0,0 +1,49 @@
+//! STAR Randomness web service
+//! Bearer token authentication for administrative endpoints
+
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::{header, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+use tracing::debug;
+
+use crate::handler::Error;
+
+/// Compare a provided credential against the expected one
+/// The comparison takes the same time wherever the first mismatch
+/// occurs, so response timing doesn't reveal a matching prefix.
+/// Only the length of the expected token can leak.
+pub fn token_matches(expected: &str, provided: &str) -> bool {
+    expected.as_bytes().ct_eq(provided.as_bytes()).into()
+}
+
+/// Extract the credential from an `Authorization: Bearer` header
+fn bearer_token(request: &Request<Body>) -> Option<&str> {
+    request
+        .headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+/// Middleware rejecting requests without the expected bearer token
+/// Missing, malformed and incorrect credentials are all rejected
+/// the same way.
+pub async fn require_token(
+    State(token): State<Arc<str>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Result<Response, Error> {
+    let authorized = bearer_token(&request)
+        .map(|provided| token_matches(&token, provided))
+        .unwrap_or(false);
+    if !authorized {
+        debug!("rejecting unauthorized request for {}", request.uri());
+        return Err(Error::Unauthorized("Bearer"));
+    }
+    Ok(next.run(request).await)
+}
@@ -0,0 +1,110 @@
+//! STAR Randomness web service
+//! Circuit breaker for repeated evaluation failures
+
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{error, info};
+
+use crate::handler::{Error, ErrorCode};
+use crate::telemetry;
+
+/// Error codes which indicate evaluation itself is failing
+/// Anything else is the client's fault, or unrelated to the
+/// state of the OPRF.
+const FAILURE_CODES: &[&str] = &["evaluation_failed", "internal_error"];
+
+/// Mutable state of the breaker
+struct Failures {
+    /// Consecutive evaluation failures
+    count: u32,
+    /// End of the cooldown, if the breaker is open
+    open_until: Option<Instant>,
+}
+
+/// Rejects requests for a cooldown after consecutive failures
+///
+/// Once the cooldown ends, requests are let through again, but the
+/// failure count isn't reset until one succeeds. So a single further
+/// failure reopens the breaker.
+pub struct CircuitBreaker {
+    /// Consecutive failures which open the breaker
+    threshold: u32,
+    /// How long the breaker stays open
+    cooldown: Duration,
+    failures: Mutex<Failures>,
+}
+
+impl CircuitBreaker {
+    /// Create a breaker opening after `threshold` consecutive failures
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            failures: Mutex::new(Failures {
+                count: 0,
+                open_until: None,
+            }),
+        }
+    }
+
+    /// Check whether a request may proceed at time `now`
+    /// Returns the remaining cooldown if the breaker is open.
+    pub fn check(&self, now: Instant) -> Result<(), Duration> {
+        let mut failures = self.failures.lock().expect("circuit breaker lock poisoned");
+        match failures.open_until {
+            Some(until) if now < until => Err(until - now),
+            Some(_) => {
+                info!("circuit breaker cooldown ended, retrying evaluation");
+                failures.open_until = None;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Account for the outcome of a request at time `now`
+    pub fn record(&self, failed: bool, now: Instant) {
+        let mut failures = self.failures.lock().expect("circuit breaker lock poisoned");
+        if !failed {
+            failures.count = 0;
+            return;
+        }
+        failures.count = failures.count.saturating_add(1);
+        if failures.count >= self.threshold && failures.open_until.is_none() {
+            error!(
+                "{} consecutive evaluation failures, rejecting requests for {} seconds",
+                failures.count,
+                self.cooldown.as_secs()
+            );
+            metrics::increment_counter!(telemetry::CIRCUIT_BREAKER_TRIPS);
+            failures.open_until = Some(now + self.cooldown);
+        }
+    }
+}
+
+/// Middleware applying the circuit breaker to a route
+pub async fn guard(
+    State(breaker): State<Arc<CircuitBreaker>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Result<Response, Error> {
+    if let Err(wait) = breaker.check(Instant::now()) {
+        // Round up so clients don't retry too early.
+        let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+        return Err(Error::CircuitOpen(secs.max(1)));
+    }
+    let response = next.run(request).await;
+    // Client errors say nothing about whether evaluation works.
+    match response.extensions().get::<ErrorCode>() {
+        None => breaker.record(false, Instant::now()),
+        Some(code) if FAILURE_CODES.contains(&code.0) => breaker.record(true, Instant::now()),
+        Some(_) => {}
+    }
+    Ok(response)
+}
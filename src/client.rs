@@ -0,0 +1,134 @@
+//! STAR Randomness web service
+//! Typed client for the HTTP API
+//!
+//! This is only built with the `client` feature. It reuses the
+//! server's own response types, so the wire format is defined in
+//! one place. Responses are expected in the default camelCase, so
+//! it can't talk to a server run with `--json-field-case snake-case`.
+
+use base64::prelude::{Engine as _, BASE64_STANDARD as BASE64};
+use reqwest::{StatusCode, Url};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::handler::{InfoResponse, RandomnessResponse};
+use ppoprf::ppoprf;
+
+/// Failure to complete a request to the randomness server
+#[derive(thiserror::Error, Debug)]
+pub enum ClientError {
+    #[error("Request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    /// The server reported an error in the usual format
+    #[error("Server returned {status} {code}: {message}")]
+    Server {
+        status: StatusCode,
+        /// Stable identifier for the kind of error
+        code: String,
+        message: String,
+    },
+    #[error("Invalid public key: {0}")]
+    PublicKey(String),
+}
+
+/// Error response body, as sent by `handler::Error`
+#[derive(Deserialize)]
+struct ErrorBody {
+    error: ErrorFields,
+}
+
+/// Fields of an error response the client reports
+#[derive(Deserialize)]
+struct ErrorFields {
+    code: String,
+    message: String,
+}
+
+/// Async client for a randomness server
+#[derive(Clone, Debug)]
+pub struct RandsrvClient {
+    http: reqwest::Client,
+    /// Address of the server, e.g. `http://localhost:8080/`
+    base_url: Url,
+}
+
+impl RandsrvClient {
+    /// Create a client for the server at `base_url`
+    pub fn new(base_url: Url) -> Self {
+        Self::with_client(reqwest::Client::new(), base_url)
+    }
+
+    /// Create a client sharing an existing `reqwest::Client`
+    /// This lets callers configure timeouts or proxies themselves.
+    pub fn with_client(http: reqwest::Client, base_url: Url) -> Self {
+        RandsrvClient { http, base_url }
+    }
+
+    /// Fetch the current epoch and key information
+    pub async fn info(&self) -> Result<InfoResponse, ClientError> {
+        let response = self.http.get(self.url("info")).send().await?;
+        parse(response).await
+    }
+
+    /// Fetch and decode the server's current public key
+    pub async fn public_key(&self) -> Result<ppoprf::ServerPublicKey, ClientError> {
+        let info = self.info().await?;
+        let bytes = BASE64
+            .decode(&info.public_key)
+            .map_err(|e| ClientError::PublicKey(e.to_string()))?;
+        ppoprf::ServerPublicKey::load_from_bincode(&bytes)
+            .map_err(|e| ClientError::PublicKey(e.to_string()))
+    }
+
+    /// Evaluate blinded points, in the given epoch or the current one
+    pub async fn randomness(
+        &self,
+        points: &[ppoprf::Point],
+        epoch: Option<u8>,
+    ) -> Result<RandomnessResponse, ClientError> {
+        let points: Vec<String> = points
+            .iter()
+            .map(|point| BASE64.encode(point.as_bytes()))
+            .collect();
+        let mut body = json!({ "points": points });
+        if let Some(epoch) = epoch {
+            body["epoch"] = json!(epoch);
+        }
+        let response = self
+            .http
+            .post(self.url("randomness"))
+            .json(&body)
+            .send()
+            .await?;
+        parse(response).await
+    }
+
+    /// Resolve an endpoint path against the base URL
+    fn url(&self, path: &str) -> Url {
+        self.base_url
+            .join(path)
+            .expect("endpoint paths should be valid relative URLs")
+    }
+}
+
+/// Decode a successful response body, or the server's error
+async fn parse<T: DeserializeOwned>(response: reqwest::Response) -> Result<T, ClientError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response.json().await?);
+    }
+    match response.json::<ErrorBody>().await {
+        Ok(ErrorBody { error }) => Err(ClientError::Server {
+            status,
+            code: error.code,
+            message: error.message,
+        }),
+        // Errors from outside our handlers may not be in our format.
+        Err(_) => Err(ClientError::Server {
+            status,
+            code: String::new(),
+            message: status.to_string(),
+        }),
+    }
+}
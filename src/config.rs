@@ -0,0 +1,595 @@
+//! STAR Randomness web service
+//! Configuration from the command line and config files
+
+use base64::engine::{general_purpose, GeneralPurpose};
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::metadata::LevelFilter;
+use tracing_subscriber::EnvFilter;
+
+use crate::signature::ClientKeys;
+
+/// Command line switches
+///
+/// These may also be given in a TOML file passed with `--config`,
+/// using the long option names with underscores, e.g.
+/// `epoch_seconds = 10`. Options given on the command line take
+/// precedence over the file.
+#[derive(Parser, Serialize, Deserialize, Debug, Clone)]
+#[command(author, version, about, long_about = None)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Load options from a TOML file
+    #[arg(long, value_name = "PATH")]
+    #[serde(skip)]
+    pub config: Option<PathBuf>,
+    /// Check that evaluation works under this configuration, then
+    /// exit without serving requests.
+    #[arg(long, default_value_t = false)]
+    pub self_test: bool,
+    /// Host and port to listen for http connections
+    #[arg(long, default_value = "127.0.0.1:8080", value_parser = parse_address)]
+    #[serde(deserialize_with = "deserialize_address")]
+    pub listen: SocketAddr,
+    /// Duration of each randomness epoch
+    #[arg(long, default_value_t = 5)]
+    pub epoch_seconds: u32,
+    /// Keep accepting the previous epoch for this long after each
+    /// rotation, so requests in flight at a boundary don't fail.
+    #[arg(long, value_name = "SECONDS", default_value_t = 0)]
+    pub epoch_grace_seconds: u32,
+    /// Reject requests for epochs more than this many behind the
+    /// current one, even if they're still accepted otherwise.
+    /// Unlimited if not given.
+    #[arg(long, value_name = "EPOCHS")]
+    pub max_epoch_lag: Option<u8>,
+    /// First epoch tag to make available
+    #[arg(long, default_value_t = 0)]
+    pub first_epoch: u8,
+    /// Last epoch tag to make available
+    /// Epoch tags are `u8` in the ppoprf library, so at most 256
+    /// epochs can be served before the key must be rotated.
+    #[arg(long, default_value_t = 255)]
+    pub last_epoch: u8,
+    /// Explicit list of epoch tags to make available, in order
+    /// This replaces `--first-epoch` and `--last-epoch`, so a
+    /// schedule which isn't a contiguous range can be served.
+    #[arg(long, value_name = "EPOCHS", value_delimiter = ',')]
+    pub epochs: Option<Vec<u8>>,
+    /// Keep the public key from before the last key rotation, and
+    /// report it from `/info` as `previousPublicKey`.
+    #[arg(long, default_value_t = false)]
+    pub retain_previous_key: bool,
+    /// Optional absolute time at which to anchor the first epoch
+    /// This can be used to align the epoch sequence across different
+    /// invocations.
+    #[arg(long, value_name = "RFC 3339 timestamp", value_parser = parse_timestamp)]
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub epoch_base_time: Option<OffsetDateTime>,
+    /// Rotate the key at this time regardless of the epochs remaining
+    /// The epoch sequence starts over from the rotation, so servers
+    /// given the same time rotate in lockstep.
+    #[arg(long, value_name = "RFC 3339 timestamp", value_parser = parse_timestamp)]
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub rotate_key_at: Option<OffsetDateTime>,
+    /// Increases OS nofile limit to 65535, so the server can handle
+    /// more concurrent connections. If the limit can't be raised, a
+    /// warning is logged and the existing limit is kept.
+    #[arg(long, default_value_t = false)]
+    pub increase_nofile_limit: bool,
+    /// Send TCP keepalive probes on connections idle this long, so
+    /// connections to vanished clients are eventually closed.
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub http_keepalive_seconds: Option<u64>,
+    /// Maximum number of connections waiting to be accepted
+    /// The kernel may cap this lower, e.g. at `net.core.somaxconn`
+    /// on Linux.
+    #[arg(long, value_name = "CONNECTIONS", default_value_t = 1024, value_parser = clap::value_parser!(u32).range(1..))]
+    pub tcp_backlog: u32,
+    /// Accept new connections no faster than this, leaving the rest
+    /// waiting in the backlog. Unlimited if not given.
+    #[arg(long, value_name = "CONNECTIONS", value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_new_connections_per_second: Option<u32>,
+    /// Set TCP_NODELAY on accepted connections, sending small
+    /// responses immediately instead of coalescing them
+    #[arg(long, default_value_t = false)]
+    pub tcp_nodelay: bool,
+    /// Also accept HTTP/2 without TLS (h2c) from clients with prior
+    /// knowledge. Only HTTP/1.1 is spoken otherwise.
+    #[arg(long, default_value_t = false)]
+    pub http2: bool,
+    /// Abort requests which take longer than this to complete,
+    /// responding with 408 Request Timeout. Unlimited if not given.
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub request_timeout_seconds: Option<u64>,
+    /// Give up on evaluating a randomness request after this long,
+    /// responding with 504 Gateway Timeout. Unlimited if not given.
+    #[arg(long, value_name = "MILLISECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub eval_timeout_ms: Option<u64>,
+    /// Number of tokio worker threads handling requests. Defaults to
+    /// the number of CPUs.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub worker_threads: Option<u32>,
+    /// Base64 alphabet and padding for points and keys
+    /// This applies to requests as well as responses.
+    #[arg(long, value_enum, default_value_t = Base64Variant::Standard)]
+    pub base64_variant: Base64Variant,
+    /// Naming convention for multi-word JSON response fields
+    #[arg(long, value_enum, default_value_t = FieldCase::CamelCase)]
+    pub json_field_case: FieldCase,
+    /// Seconds before the next epoch at which cached `/info`
+    /// responses expire
+    #[arg(long, value_name = "SECONDS", default_value_t = 5)]
+    pub info_cache_margin_seconds: u32,
+    /// Enable prometheus metric reporting and listen on specified address.
+    #[arg(long, value_parser = parse_address)]
+    #[serde(default, deserialize_with = "deserialize_optional_address")]
+    pub prometheus_listen: Option<SocketAddr>,
+    /// Send metrics to a statsd server at this address over UDP, in
+    /// the dogstatsd format. This can be combined with prometheus.
+    #[arg(long, value_parser = parse_address)]
+    #[serde(default, deserialize_with = "deserialize_optional_address")]
+    pub statsd_addr: Option<SocketAddr>,
+    /// Refuse to start if the prometheus address can't be bound,
+    /// rather than serving randomness without metrics.
+    #[arg(long, default_value_t = false)]
+    pub require_metrics: bool,
+    /// Require this bearer token to read the prometheus metrics
+    /// and the `/stats` endpoint, and enable the `/admin` endpoints.
+    /// Clients must send `Authorization: Bearer <token>`.
+    #[arg(long, value_name = "TOKEN")]
+    pub admin_token: Option<Secret>,
+    /// Sign randomness responses with HMAC-SHA256 under this key,
+    /// returning the base64 code in an `X-Response-MAC` header.
+    #[arg(long, value_name = "KEY")]
+    pub response_hmac_key: Option<Secret>,
+    /// Only accept randomness requests signed by a client whose
+    /// key is listed in `--client-keys`.
+    #[arg(long, default_value_t = false)]
+    pub require_signed_requests: bool,
+    /// File of base64-encoded Ed25519 public keys, one per line,
+    /// allowed to sign requests.
+    #[arg(long, value_name = "PATH")]
+    pub client_keys: Option<PathBuf>,
+    /// Replay the response to a randomness request repeated with the
+    /// same `Idempotency-Key` header and body within this long.
+    /// Disabled if not given.
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub idempotency_ttl_seconds: Option<u64>,
+    /// Maximum number of responses kept for replay
+    #[arg(long, default_value_t = 1024)]
+    pub idempotency_cache_size: usize,
+    /// Reject randomness requests for a while after this many
+    /// consecutive evaluation failures. Disabled if not given.
+    #[arg(long, value_name = "FAILURES", value_parser = clap::value_parser!(u32).range(1..))]
+    pub circuit_breaker_threshold: Option<u32>,
+    /// How long to reject requests once the circuit breaker trips
+    #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+    pub circuit_breaker_cooldown_seconds: u64,
+    /// Queue up to this many randomness evaluations for a pool of
+    /// worker threads, rejecting requests once it's full. Requests
+    /// evaluate on their own if not given.
+    #[arg(long, value_name = "REQUESTS", value_parser = clap::value_parser!(u32).range(1..))]
+    pub eval_queue_size: Option<u32>,
+    /// Number of threads servicing the evaluation queue. Defaults to
+    /// the number of CPUs.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub eval_queue_workers: Option<u32>,
+    /// Reject queued evaluations which wait longer than this for a
+    /// worker thread
+    #[arg(long, value_name = "MILLISECONDS", default_value_t = 1000)]
+    pub eval_queue_deadline_ms: u64,
+    /// Maximum sustained rate of randomness requests from a single
+    /// client address. Unlimited if not given.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub rate_limit_per_second: Option<u32>,
+    /// Identify clients by the X-Forwarded-For header when rate
+    /// limiting and access logging. Only enable this behind a proxy
+    /// which sets it.
+    #[arg(long, default_value_t = false)]
+    pub trust_proxy: bool,
+    /// Log a line for each request in common log format, followed
+    /// by the time taken in seconds
+    #[arg(long, default_value_t = false)]
+    pub access_log: bool,
+    /// Log filter directives, e.g. `info,star_randsrv::state=debug`,
+    /// in the same syntax as `RUST_LOG`, which this overrides.
+    #[arg(long, value_name = "DIRECTIVES")]
+    pub log_filter: Option<String>,
+    /// Answer `/` with this fixed body, `OK` if none is given,
+    /// for load balancers which health check the root path.
+    #[arg(long, value_name = "BODY", num_args = 0..=1, default_missing_value = "OK")]
+    pub root_health_check: Option<String>,
+    /// Don't serve anything at `/`, leaving only the functional
+    /// endpoints.
+    #[arg(long, default_value_t = false, conflicts_with = "root_health_check")]
+    pub disable_welcome: bool,
+    /// Comma-separated list of the endpoints to serve. Requests
+    /// for any others get `404 Not Found`.
+    #[arg(long, value_enum, value_delimiter = ',', default_values_t = Route::ALL)]
+    pub enable_routes: Vec<Route>,
+    /// Serve every endpoint under this path, e.g. `/randsrv` for
+    /// `/randsrv/info`, rather than at the root
+    #[arg(long, value_name = "PATH")]
+    pub path_prefix: Option<String>,
+    /// Save the epoch schedule to this file on shutdown, and resume
+    /// from it on startup if `--epoch-base-time` isn't given.
+    #[arg(long, value_name = "PATH")]
+    pub state_file: Option<PathBuf>,
+    /// Serve as a replica which derives the current epoch from the
+    /// clock instead of advancing and puncturing epochs itself.
+    /// Requires `--epoch-base-time`. Since its key is never
+    /// punctured, the replica stops evaluating after one pass through
+    /// the epochs rather than reuse their tags.
+    #[arg(long, default_value_t = false)]
+    pub no_rotate: bool,
+    /// What to do if the epoch rotation task fails unexpectedly
+    #[arg(long, value_enum, default_value_t = RotationFailurePolicy::Restart)]
+    pub on_rotation_failure: RotationFailurePolicy,
+    /// What to do once every epoch under the current key has ended
+    #[arg(long, value_enum, default_value_t = EpochExhaustionPolicy::Rotate)]
+    pub on_epoch_exhaustion: EpochExhaustionPolicy,
+}
+
+/// Naming convention for JSON response fields
+#[derive(clap::ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FieldCase {
+    /// e.g. `currentEpoch`, as in the original implementation
+    CamelCase,
+    /// e.g. `current_epoch`
+    SnakeCase,
+}
+
+/// Base64 alphabet and padding for points and keys
+#[derive(clap::ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Base64Variant {
+    /// Standard alphabet with padding, as in the original implementation
+    Standard,
+    /// Standard alphabet without padding
+    StandardNoPad,
+    /// URL and filename safe alphabet with padding
+    UrlSafe,
+    /// URL and filename safe alphabet without padding
+    UrlSafeNoPad,
+}
+
+impl Base64Variant {
+    /// Engine encoding and decoding this variant
+    pub fn engine(self) -> &'static GeneralPurpose {
+        match self {
+            Base64Variant::Standard => &general_purpose::STANDARD,
+            Base64Variant::StandardNoPad => &general_purpose::STANDARD_NO_PAD,
+            Base64Variant::UrlSafe => &general_purpose::URL_SAFE,
+            Base64Variant::UrlSafeNoPad => &general_purpose::URL_SAFE_NO_PAD,
+        }
+    }
+}
+
+/// Endpoint of the main service which can be enabled
+#[derive(clap::ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Route {
+    /// `/`, identifying the site or answering health checks
+    Welcome,
+    /// `/randomness`
+    Randomness,
+    /// `/info`
+    Info,
+    /// `/batch`, combining `/randomness` and `/info`
+    Batch,
+    /// `/epoch/:epoch`
+    Epoch,
+    /// `/stats`
+    Stats,
+    /// `/verify`
+    Verify,
+    /// `/openapi.json`
+    Openapi,
+    /// `/ready`
+    Ready,
+    /// `/admin/drain` and `/admin/undrain`, which are only served
+    /// with `--admin-token`
+    Admin,
+}
+
+impl Route {
+    /// Every route, which are all enabled by default
+    pub const ALL: [Route; 10] = [
+        Route::Welcome,
+        Route::Randomness,
+        Route::Info,
+        Route::Batch,
+        Route::Epoch,
+        Route::Stats,
+        Route::Verify,
+        Route::Openapi,
+        Route::Ready,
+        Route::Admin,
+    ];
+}
+
+/// Response to an unexpected failure of epoch rotation
+#[derive(clap::ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RotationFailurePolicy {
+    /// Restart rotation under a fresh key, resuming the schedule
+    Restart,
+    /// Shut the server down and exit with an error
+    Exit,
+}
+
+/// Response to running out of epochs under the current key
+#[derive(clap::ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EpochExhaustionPolicy {
+    /// Rotate to a fresh key and start over at the first epoch
+    Rotate,
+    /// Stop evaluating, then shut the server down and exit with an
+    /// error
+    Exit,
+    /// Stop evaluating, answering requests with `503 Service
+    /// Unavailable`, but keep serving until shut down
+    Halt,
+}
+
+/// Sensitive option value
+///
+/// This is redacted when formatted with `Debug`, so the config can
+/// be logged safely. It still serializes as the real value, which
+/// config file merging relies on.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    /// The secret value itself
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(value: &str) -> Self {
+        Secret(value.to_owned())
+    }
+}
+
+impl std::str::FromStr for Secret {
+    type Err = std::convert::Infallible;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(value.into())
+    }
+}
+
+/// Configuration error conditions
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Cli(#[from] clap::Error),
+    #[error("Couldn't read config file {}: {1}", .0.display())]
+    Io(PathBuf, std::io::Error),
+    #[error("Invalid config file: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("Couldn't merge config file options: {0}")]
+    Merge(#[from] toml::ser::Error),
+    #[error("Invalid configuration: {0}")]
+    Invalid(&'static str),
+    #[error("Invalid client key in {} at line {1}", .0.display())]
+    ClientKey(PathBuf, usize),
+}
+
+impl Config {
+    /// Load the configuration for this process
+    /// Exits with a usage message if it isn't valid.
+    pub fn load() -> Self {
+        match Config::try_load_from(std::env::args_os()) {
+            Ok(config) => config,
+            Err(Error::Cli(e)) => e.exit(),
+            Err(e) => {
+                eprintln!("error: {e}");
+                std::process::exit(2);
+            }
+        }
+    }
+
+    /// Load a configuration from the given command line
+    /// If a `--config` file is named, its contents are merged in
+    /// beneath any options given explicitly on the command line.
+    pub fn try_load_from<I, T>(args: I) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let matches = Config::command().try_get_matches_from(args)?;
+        let mut config = Config::from_arg_matches(&matches)?;
+        if let Some(path) = config.config.take() {
+            let contents =
+                std::fs::read_to_string(&path).map_err(|e| Error::Io(path.clone(), e))?;
+            let file: toml::Table = toml::from_str(&contents)?;
+            // Round-trip through a table so the file can override
+            // any field without listing them all here.
+            let mut merged = toml::Table::try_from(&config)?;
+            for (key, value) in file {
+                let known = Config::command()
+                    .get_arguments()
+                    .any(|arg| arg.get_id() == key.as_str());
+                // Unknown keys are left in place to be rejected
+                // during deserialization.
+                if !known || matches.value_source(&key) != Some(ValueSource::CommandLine) {
+                    merged.insert(key, value);
+                }
+            }
+            config = merged.try_into()?;
+            config.config = Some(path);
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Whether `app` should serve the given route
+    /// This follows `--enable-routes`, except that `--disable-welcome`
+    /// always removes the welcome route.
+    pub fn route_enabled(&self, route: Route) -> bool {
+        if route == Route::Welcome && self.disable_welcome {
+            return false;
+        }
+        self.enable_routes.contains(&route)
+    }
+
+    /// Epoch tags to make available under each key, in order
+    /// This is `--epochs` if given, otherwise the range from
+    /// `--first-epoch` to `--last-epoch`.
+    pub fn epoch_list(&self) -> Vec<u8> {
+        match &self.epochs {
+            Some(epochs) => epochs.clone(),
+            None => (self.first_epoch..=self.last_epoch).collect(),
+        }
+    }
+
+    /// Filter for log output
+    /// This comes from `--log-filter` if given, otherwise the
+    /// `RUST_LOG` environment variable. Anything not matched by a
+    /// directive is logged at info level.
+    pub fn log_filter(&self) -> EnvFilter {
+        let builder = EnvFilter::builder().with_default_directive(LevelFilter::INFO.into());
+        match &self.log_filter {
+            Some(directives) => builder
+                .parse(directives)
+                .expect("validated config has a valid log filter"),
+            None => builder
+                .from_env()
+                .expect("RUST_LOG should be a valid log filter"),
+        }
+    }
+
+    /// Path under which the endpoints are served, if not the root
+    /// A trailing `/` is dropped, so `/randsrv/` is the same as
+    /// `/randsrv`, and `/` alone is no prefix at all.
+    pub fn path_prefix(&self) -> Option<&str> {
+        self.path_prefix
+            .as_deref()
+            .map(|prefix| prefix.trim_end_matches('/'))
+            .filter(|prefix| !prefix.is_empty())
+    }
+
+    /// Check for inconsistent options
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.epoch_seconds == 0 {
+            return Err(Error::Invalid("epoch-seconds must be positive"));
+        }
+        if self.epoch_grace_seconds >= self.epoch_seconds {
+            return Err(Error::Invalid(
+                "epoch-grace-seconds must be less than epoch-seconds",
+            ));
+        }
+        if self.first_epoch > self.last_epoch {
+            return Err(Error::Invalid(
+                "first-epoch must not be greater than last-epoch",
+            ));
+        }
+        if let Some(epochs) = &self.epochs {
+            if epochs.is_empty() {
+                return Err(Error::Invalid("epochs must list at least one epoch"));
+            }
+            // Each tag can only be punctured once under a key.
+            let unique: BTreeSet<u8> = epochs.iter().copied().collect();
+            if unique.len() != epochs.len() {
+                return Err(Error::Invalid("epochs must not repeat an epoch"));
+            }
+        }
+        if let Some(base_time) = self.epoch_base_time {
+            // The schedule counts elapsed epochs in a u32.
+            let elapsed = (OffsetDateTime::now_utc() - base_time).whole_seconds();
+            if elapsed / i64::from(self.epoch_seconds) >= i64::from(u32::MAX) {
+                return Err(Error::Invalid(
+                    "epoch-base-time is too far in the past for epoch-seconds; \
+                     use a later base time or longer epochs",
+                ));
+            }
+        }
+        if self.no_rotate && self.epoch_base_time.is_none() {
+            return Err(Error::Invalid("no-rotate requires epoch-base-time"));
+        }
+        if self.no_rotate && self.epoch_base_time > Some(OffsetDateTime::now_utc()) {
+            return Err(Error::Invalid(
+                "epoch-base-time must not be in the future with no-rotate",
+            ));
+        }
+        if self.no_rotate && self.rotate_key_at.is_some() {
+            return Err(Error::Invalid("rotate-key-at can't be used with no-rotate"));
+        }
+        if let Some(directives) = &self.log_filter {
+            if EnvFilter::builder().parse(directives).is_err() {
+                return Err(Error::Invalid("log-filter is not a valid filter"));
+            }
+        }
+        if self.enable_routes.is_empty() {
+            return Err(Error::Invalid("enable-routes must name at least one route"));
+        }
+        if let Some(prefix) = &self.path_prefix {
+            if !prefix.starts_with('/') || prefix.contains([':', '*']) {
+                return Err(Error::Invalid(
+                    "path-prefix must start with / and can't contain : or *",
+                ));
+            }
+        }
+        if self.root_health_check.is_some() && !self.route_enabled(Route::Welcome) {
+            return Err(Error::Invalid(
+                "root-health-check requires the welcome route, \
+                 so can't be used with disable-welcome",
+            ));
+        }
+        if self.require_signed_requests {
+            let Some(path) = &self.client_keys else {
+                return Err(Error::Invalid(
+                    "require-signed-requests requires client-keys",
+                ));
+            };
+            ClientKeys::load(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse a timestamp given as a config option
+fn parse_timestamp(stamp: &str) -> Result<OffsetDateTime, &'static str> {
+    OffsetDateTime::parse(stamp, &Rfc3339).map_err(|_| "Try something like '2023-05-15T04:30:00Z'.")
+}
+
+/// Parse a socket address given as a config option
+fn parse_address(addr: &str) -> Result<SocketAddr, &'static str> {
+    addr.parse()
+        .map_err(|_| "Expected an IP address and port, like '127.0.0.1:8080'.")
+}
+
+/// Deserialize a socket address from a config file
+/// This gives the same message as the command line, naming the
+/// offending value.
+fn deserialize_address<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SocketAddr, D::Error> {
+    let addr = String::deserialize(deserializer)?;
+    parse_address(&addr).map_err(|e| D::Error::custom(format!("invalid value '{addr}': {e}")))
+}
+
+/// Deserialize an optional socket address from a config file
+fn deserialize_optional_address<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<SocketAddr>, D::Error> {
+    deserialize_address(deserializer).map(Some)
+}
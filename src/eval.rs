@@ -0,0 +1,249 @@
+//! STAR Randomness web service
+//! PPOPRF evaluation independent of the http interface
+
+use curve25519_dalek::ristretto::CompressedRistretto;
+use ppoprf::ppoprf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tracing::debug;
+
+use crate::handler::Error;
+use crate::state::{OPRFServer, OPRFState};
+use crate::telemetry;
+use crate::Config;
+
+/// Outcome of evaluating a batch of points
+#[derive(Debug)]
+pub struct BatchResult {
+    /// Randomness epoch used in the evaluation
+    pub epoch: u8,
+    /// Absolute number of the epoch, which doesn't wrap with the tag
+    pub epoch_number: u64,
+    /// Time left in the current epoch when the epoch was selected
+    pub time_remaining: Option<Duration>,
+    /// Evaluated points, in the same order as the input
+    pub points: Vec<ppoprf::Point>,
+}
+
+/// Convert raw compressed points for evaluation
+/// Every point must decompress to a Ristretto element, which also
+/// rejects non-canonical encodings. Checking this up front means a
+/// bad point fails the request before any evaluation, including
+/// before a streamed response has started.
+pub fn decode_points(points: &[Vec<u8>]) -> Result<Vec<ppoprf::Point>, Error> {
+    points
+        .iter()
+        .enumerate()
+        .map(|(index, bytes)| {
+            // Point::from doesn't check its input, so do it here.
+            CompressedRistretto::from_slice(bytes)
+                .ok()
+                .and_then(|compressed| compressed.decompress())
+                .ok_or(Error::BadPoint(index))?;
+            Ok(ppoprf::Point::from(bytes.as_slice()))
+        })
+        .collect()
+}
+
+/// Choose the epoch for an evaluation
+/// Clients may only request the current epoch, which is also
+/// the default, or the previous one during its grace period.
+pub fn select_epoch(requested: Option<u8>, state: &OPRFServer) -> Result<u8, Error> {
+    if state.exhausted() {
+        return Err(Error::Exhausted);
+    }
+    if !state.started() {
+        return Err(Error::NotStarted);
+    }
+    let epoch = requested.unwrap_or_else(|| state.current_epoch());
+    if !state.accepts(epoch) {
+        if state.was_punctured(epoch) {
+            debug!(epoch, "rejecting request for punctured epoch");
+            metrics::increment_counter!(telemetry::REJECTED_PUNCTURED);
+        }
+        return Err(Error::BadEpoch(epoch));
+    }
+    Ok(epoch)
+}
+
+/// Evaluate the PPOPRF on a single point
+pub fn evaluate_point(
+    server: &ppoprf::Server,
+    index: usize,
+    point: &ppoprf::Point,
+    epoch: u8,
+) -> Result<ppoprf::Point, Error> {
+    // Don't support returning proofs until we have a more
+    // space-efficient batch proof implemented in ppoprf.
+    match server.eval(point, epoch, false) {
+        Ok(evaluation) => {
+            metrics::increment_counter!(
+                telemetry::EVALUATIONS_BY_EPOCH,
+                "epoch" => epoch.to_string()
+            );
+            Ok(evaluation.output)
+        }
+        // Points which don't decompress are the client's fault.
+        Err(ppoprf::PPRFError::BadPointEncoding) => Err(Error::BadPoint(index)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Evaluate the PPOPRF on a batch of decoded points
+///
+/// ppoprf 0.3 only evaluates one point per call, re-deriving the
+/// tagged key each time, so this loops over the batch. If a batched
+/// evaluation becomes available upstream it can replace the loop
+/// here without changing any callers.
+pub fn evaluate_points(
+    server: &ppoprf::Server,
+    points: &[ppoprf::Point],
+    epoch: u8,
+) -> Result<Vec<ppoprf::Point>, Error> {
+    points
+        .iter()
+        .enumerate()
+        .map(|(index, point)| evaluate_point(server, index, point, epoch))
+        .collect()
+}
+
+/// Evaluate the PPOPRF on a batch of raw compressed points
+/// All points are validated before any are evaluated. The batch
+/// fails as a whole if any point can't be evaluated.
+pub fn evaluate_batch(
+    state: &OPRFServer,
+    points: &[Vec<u8>],
+    epoch: Option<u8>,
+) -> Result<BatchResult, Error> {
+    let inputs = decode_points(points)?;
+    let epoch = select_epoch(epoch, state)?;
+    let points = evaluate_points(&state.server, &inputs, epoch)?;
+    Ok(BatchResult {
+        epoch,
+        epoch_number: state.epoch_number(epoch),
+        time_remaining: state.time_remaining(),
+        points,
+    })
+}
+
+/// Points evaluated per acquisition of the state lock
+/// Large batches release the lock between chunks so epoch
+/// rotation isn't held up until the whole batch is done.
+pub const CHUNK_SIZE: usize = 64;
+
+/// Evaluate a batch of raw compressed points in chunks
+/// The read lock is taken separately for each chunk, and the epoch
+/// re-checked each time. If the epoch ends part-way through, the
+/// batch fails with `Error::EpochChanged`. The first chunk is
+/// evaluated under the lock taken to select the epoch, so batches
+/// of up to `CHUNK_SIZE` points only lock once.
+pub fn evaluate_chunked(
+    state: &OPRFState,
+    points: &[Vec<u8>],
+    epoch: Option<u8>,
+) -> Result<BatchResult, Error> {
+    evaluate_cancellable(state, points, epoch, &AtomicBool::new(false))
+}
+
+/// Evaluate a batch in chunks, stopping early if cancelled
+/// Like `evaluate_chunked`, but `cancelled` is checked before each
+/// chunk, and the batch fails with `Error::EvalTimeout` once it's
+/// set. This lets a caller which has given up on the result stop
+/// the work, rather than leave it running to completion.
+pub fn evaluate_cancellable(
+    state: &OPRFState,
+    points: &[Vec<u8>],
+    epoch: Option<u8>,
+    cancelled: &AtomicBool,
+) -> Result<BatchResult, Error> {
+    evaluate_cancellable_with(state, points, epoch, cancelled, |_| ()).map(|(result, ())| result)
+}
+
+/// Evaluate a batch in chunks, along with a snapshot of the state
+/// Like `evaluate_cancellable`, but `snapshot` is also called under
+/// the lock the epoch is selected with, so what it returns agrees
+/// with the epoch of the result.
+pub fn evaluate_cancellable_with<T>(
+    state: &OPRFState,
+    points: &[Vec<u8>],
+    epoch: Option<u8>,
+    cancelled: &AtomicBool,
+    snapshot: impl FnOnce(&OPRFServer) -> T,
+) -> Result<(BatchResult, T), Error> {
+    let inputs = decode_points(points)?;
+    let s = state.read()?;
+    let epoch = select_epoch(epoch, &s)?;
+    let epoch_number = s.epoch_number(epoch);
+    let time_remaining = s.time_remaining();
+    let snapshot = snapshot(&s);
+    let mut first = Some(s);
+    let mut outputs = Vec::with_capacity(inputs.len());
+    for (n, chunk) in inputs.chunks(CHUNK_SIZE).enumerate() {
+        if cancelled.load(Ordering::Relaxed) {
+            return Err(Error::EvalTimeout);
+        }
+        let s = match first.take() {
+            Some(s) => s,
+            None => {
+                let s = state.read()?;
+                if !s.accepts(epoch) {
+                    return Err(Error::EpochChanged(epoch));
+                }
+                s
+            }
+        };
+        // Keep the indices of errors relative to the whole batch.
+        let offset = n * CHUNK_SIZE;
+        let chunk_outputs = evaluate_points(&s.server, chunk, epoch).map_err(|e| match e {
+            Error::BadPoint(index) => Error::BadPoint(offset + index),
+            e => e,
+        })?;
+        outputs.extend(chunk_outputs);
+    }
+    let result = BatchResult {
+        epoch,
+        epoch_number,
+        time_remaining,
+        points: outputs,
+    };
+    Ok((result, snapshot))
+}
+
+/// Client input evaluated by `self_test`
+const SELF_TEST_INPUT: &[u8] = b"star-randsrv self-test";
+
+/// Reasons the self-test can fail
+#[derive(thiserror::Error, Debug)]
+pub enum SelfTestError {
+    #[error("Couldn't initialize PPOPRF state: {0}")]
+    Init(ppoprf::PPRFError),
+    #[error("Evaluation failed: {0}")]
+    Eval(#[from] Error),
+    #[error("Evaluation proof didn't verify")]
+    Proof,
+    #[error("Verifiable evaluation gave a different output")]
+    Mismatch,
+}
+
+/// Check that the PPOPRF stack works under `config`
+/// A fresh server evaluates a blinded fixed input in the current
+/// epoch, then again with a proof, which must verify against the
+/// server's public key and agree with the first output.
+pub fn self_test(config: &Config) -> Result<(), SelfTestError> {
+    let state = OPRFServer::new(config).map_err(SelfTestError::Init)?;
+    let epoch = state.current_epoch();
+    let (point, _) = ppoprf::Client::blind(SELF_TEST_INPUT);
+    let output = evaluate_point(&state.server, 0, &point, epoch)?;
+    let evaluation = state
+        .server
+        .eval(&point, epoch, true)
+        .map_err(Error::from)?;
+    let public_key = state.server.get_public_key();
+    if !ppoprf::Client::verify(&public_key, &point, &evaluation, epoch) {
+        return Err(SelfTestError::Proof);
+    }
+    if evaluation.output != output {
+        return Err(SelfTestError::Mismatch);
+    }
+    Ok(())
+}
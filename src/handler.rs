@@ -1,62 +1,578 @@
 //! STAR Randomness web service route implementation
 
-use axum::extract::{Json, State};
-use axum::http::StatusCode;
-use base64::prelude::{Engine as _, BASE64_STANDARD as BASE64};
-use serde::{Deserialize, Serialize};
-use tracing::debug;
+use axum::body::{Body, Bytes, StreamBody};
+use axum::extract::rejection::{JsonRejection, PathRejection, QueryRejection};
+use axum::extract::{FromRequest, Json, Path, Query, State};
+use axum::http::{header, HeaderMap, HeaderValue, Method, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::IntoResponse;
+use axum::Extension;
+use base64::Engine as _;
+use curve25519_dalek::ristretto::CompressedRistretto;
+use rand::seq::SliceRandom;
+use serde::de::{IgnoredAny, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{Number, Value};
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamExt;
+use tracing::{debug, info, warn};
 
-use crate::OPRFState;
+use crate::eval;
+use crate::queue::EvalQueue;
+use crate::signature::MAX_BODY;
+use crate::state::{EpochStatus, OPRFServer, Rotating};
+use crate::telemetry::{self, Stats};
+use crate::{Base64Variant, FieldCase, OPRFState};
 use ppoprf::ppoprf;
 
+/// OpenAPI description of the service
+/// This is maintained by hand alongside the request and
+/// response types below.
+const OPENAPI: &str = include_str!("openapi.json");
+
+/// Media type for streamed randomness responses
+const NDJSON: &str = "application/x-ndjson";
+
+/// Number of evaluated points to buffer while streaming
+const STREAM_BUFFER: usize = 16;
+
+/// Media type for binary randomness requests and responses
+const OCTET_STREAM: &str = "application/octet-stream";
+
+/// Media type for the PEM-armored public key from `/info`
+const PEM_FILE: &str = "application/x-pem-file";
+
+/// Length of the header preceding points in a binary response
+/// This is the epoch byte followed by a big-endian `u32` count.
+pub const BINARY_HEADER_LEN: usize = 5;
+
+/// Text encoding of compressed points in randomness requests
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PointEncoding {
+    #[default]
+    Base64,
+    Hex,
+}
+
+impl PointEncoding {
+    /// Decode a single point, using the `base64` variant if needed
+    fn decode(
+        self,
+        base64: Base64Variant,
+        index: usize,
+        point: &str,
+    ) -> Result<Vec<u8>, Error> {
+        let result = match self {
+            PointEncoding::Base64 => {
+                base64.engine().decode(point).map_err(|e| e.to_string())
+            }
+            PointEncoding::Hex => hex::decode(point).map_err(|e| e.to_string()),
+        };
+        result.map_err(|e| Error::BadPointEncoding(index, self, e))
+    }
+
+    /// Encode a single point, using the `base64` variant if needed
+    fn encode(self, base64: Base64Variant, point: &ppoprf::Point) -> String {
+        match self {
+            PointEncoding::Base64 => base64.engine().encode(point.as_bytes()),
+            PointEncoding::Hex => hex::encode(point.as_bytes()),
+        }
+    }
+}
+
+impl std::fmt::Display for PointEncoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PointEncoding::Base64 => f.write_str("base64"),
+            PointEncoding::Hex => f.write_str("hex"),
+        }
+    }
+}
+
+/// Request array which stops storing elements past `MAX_POINTS`
+///
+/// Requests with more elements are rejected anyway, and a small
+/// body can hold a great many short elements, so there's no need
+/// to allocate for all of them. The rest are parsed without being
+/// kept, and `len` still counts every element so size checks see
+/// the array as sent.
+#[derive(Debug)]
+pub struct BoundedArray<T> {
+    /// Elements up to `MAX_POINTS`
+    items: Vec<T>,
+    /// Number of elements in the request
+    len: usize,
+}
+
+impl<T> BoundedArray<T> {
+    /// Number of elements in the request, including any not kept
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Iterate over the elements which were kept
+    pub fn iter(&self) -> std::slice::Iter<'_, T> {
+        self.items.iter()
+    }
+
+    /// Take the elements which were kept
+    pub fn into_vec(self) -> Vec<T> {
+        self.items
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for BoundedArray<T> {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        struct BoundedVisitor<T>(PhantomData<T>);
+
+        impl<'de, T: Deserialize<'de>> Visitor<'de> for BoundedVisitor<T> {
+            type Value = BoundedArray<T>;
+
+            fn expecting(
+                &self,
+                f: &mut std::fmt::Formatter<'_>,
+            ) -> std::fmt::Result {
+                f.write_str("an array")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<Self::Value, A::Error> {
+                let capacity = seq.size_hint().unwrap_or(0);
+                let mut items =
+                    Vec::with_capacity(capacity.min(crate::MAX_POINTS));
+                while items.len() < crate::MAX_POINTS {
+                    match seq.next_element()? {
+                        Some(item) => items.push(item),
+                        None => break,
+                    }
+                }
+                let mut len = items.len();
+                // Only count anything beyond the limit.
+                while seq.next_element::<IgnoredAny>()?.is_some() {
+                    len += 1;
+                }
+                Ok(BoundedArray { items, len })
+            }
+        }
+
+        deserializer.deserialize_seq(BoundedVisitor(PhantomData))
+    }
+}
+
 /// Request format for the randomness endpoint
 #[derive(Deserialize, Debug)]
 pub struct RandomnessRequest {
     /// Array of points to evaluate
-    /// Should be base64-encoded, compressed Ristretto curve points.
-    points: Vec<String>,
+    /// Should be compressed Ristretto curve points, encoded as
+    /// given by `encoding`. A missing field is told apart from an
+    /// explicit `null` so each gets its own error.
+    #[serde(default, deserialize_with = "present")]
+    points: Option<Option<BoundedArray<String>>>,
     /// Optional request for evaluation within a specific epoch
-    epoch: Option<u8>,
+    /// Tags are checked against the range of epoch tags by
+    /// `epoch_tag`, so clients get a clear error for values which
+    /// don't fit. `"current"` is the same as omitting the field.
+    epoch: Option<RequestedEpoch>,
+    /// Encoding of the request and response points
+    /// All points must use the same encoding.
+    #[serde(default)]
+    encoding: PointEncoding,
+    /// Optional opaque values, one per point
+    /// These aren't interpreted, only echoed back in the response
+    /// so clients can match outputs to their inputs.
+    tags: Option<BoundedArray<Value>>,
+    /// Return the results in random order, with the permutation
+    /// needed to restore the request order
+    #[serde(default)]
+    shuffle: bool,
+    /// Time taken to parse the request body
+    #[serde(skip)]
+    parse_time: Duration,
+}
+
+/// Epoch requested for an evaluation
+#[derive(Deserialize, Debug)]
+#[serde(untagged, expecting = "an epoch tag or \"current\"")]
+enum RequestedEpoch {
+    /// A specific epoch tag
+    Tag(Number),
+    /// Whichever epoch is current when the points are evaluated
+    Current(CurrentEpoch),
+}
+
+/// The `"current"` keyword for `RequestedEpoch`
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+enum CurrentEpoch {
+    Current,
+}
+
+/// Deserialize a field which was present in the request
+/// Together with `#[serde(default)]`, this leaves `None` for a
+/// missing field and `Some(None)` for one which was `null`.
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
+}
+
+impl RandomnessRequest {
+    /// Validate the request and decode its points and epoch
+    fn inputs(
+        &self,
+        base64: Base64Variant,
+    ) -> Result<(Vec<Vec<u8>>, Option<u8>), Error> {
+        let points = match &self.points {
+            None => return Err(Error::MissingPoints),
+            Some(None) => return Err(Error::NullPoints),
+            Some(Some(points)) => points,
+        };
+        check_batch_size(points.len())?;
+        if let Some(tags) = &self.tags {
+            if tags.len() != points.len() {
+                return Err(Error::TagsMismatch(tags.len(), points.len()));
+            }
+        }
+        let epoch = match &self.epoch {
+            Some(RequestedEpoch::Tag(epoch)) => Some(epoch_tag(epoch)?),
+            // The current epoch is chosen under the same lock as the
+            // evaluation, so the response reports the one used.
+            Some(RequestedEpoch::Current(_)) | None => None,
+        };
+        let inputs = points
+            .iter()
+            .enumerate()
+            .map(|(index, point)| self.encoding.decode(base64, index, point))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok((inputs, epoch))
+    }
+}
+
+/// Query parameters accompanying a binary randomness request
+#[derive(Deserialize, Debug)]
+struct BinaryQuery {
+    /// Optional request for evaluation within a specific epoch
+    epoch: Option<u64>,
+}
+
+/// Randomness request in either of the accepted formats
+#[derive(Debug)]
+pub enum RandomnessInput {
+    /// JSON request body
+    Json(RandomnessRequest),
+    /// Concatenated raw compressed points, sent as
+    /// `application/octet-stream`, with any epoch in the query
+    Binary { points: Bytes, epoch: Option<u64> },
+}
+
+#[axum::async_trait]
+impl<S: Send + Sync> FromRequest<S, Body> for RandomnessInput {
+    type Rejection = Error;
+
+    async fn from_request(
+        request: Request<Body>,
+        state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let binary = request
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with(OCTET_STREAM));
+        if !binary {
+            // Read the body first, so only parsing is timed.
+            let (parts, body) = request.into_parts();
+            let limited = http_body::Limited::new(body, MAX_BODY);
+            let body = match hyper::body::to_bytes(limited).await {
+                Ok(body) => body,
+                Err(e) if e.is::<http_body::LengthLimitError>() => {
+                    return Err(Error::BodyTooLarge)
+                }
+                Err(_) => return Err(Error::RequestBody),
+            };
+            let start = Instant::now();
+            let request = Request::from_parts(parts, Body::from(body));
+            let Json(mut request) =
+                Json::<RandomnessRequest>::from_request(request, state).await?;
+            request.parse_time = start.elapsed();
+            return Ok(RandomnessInput::Json(request));
+        }
+        let Query(query) = Query::<BinaryQuery>::try_from_uri(request.uri())?;
+        let points = Bytes::from_request(request, state)
+            .await
+            .map_err(|_| Error::RequestBody)?;
+        Ok(RandomnessInput::Binary {
+            points,
+            epoch: query.epoch,
+        })
+    }
 }
 
 /// Response format for the randomness endpoint
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct RandomnessResponse {
     /// Resulting points from the OPRF valuation
-    /// Should be base64-encoded, compressed points in one-to-one
-    /// correspondence with the request points array.
-    points: Vec<String>,
+    /// Should be compressed points in one-to-one correspondence
+    /// with the request points array, in the request's encoding.
+    pub points: Vec<String>,
+    /// Randomness epoch used in the evaluation
+    /// A batch is always evaluated under a single epoch: requests
+    /// name at most one, and if it ends part-way through the batch
+    /// fails with `Error::EpochChanged` rather than mixing epochs.
+    /// So this applies to every point.
+    pub epoch: u8,
+    /// Absolute number of the epoch
+    /// This counts epochs since the base time, so unlike `epoch`
+    /// it doesn't wrap when the key is rotated.
+    #[serde(rename = "epochNumber")]
+    pub epoch_number: u64,
+    /// Whole seconds left in the current epoch
+    /// This counts down to the `nextEpochTime` reported by `/info`,
+    /// so clients can decide whether to wait for the next epoch
+    /// without asking for it separately. When a request names the
+    /// previous epoch, this still refers to the current one.
+    #[serde(
+        rename = "epochTimeRemainingSeconds",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub epoch_time_remaining_seconds: Option<u64>,
+    /// Tags from the request, unchanged and aligned with `points`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<Value>>,
+    /// Request index of each point, if the results were shuffled
+    /// The point at position `i` was evaluated from request point
+    /// `permutation[i]`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub permutation: Option<Vec<usize>>,
+}
+
+/// First line of a streamed randomness response
+#[derive(Serialize, Debug)]
+struct StreamHeader {
     /// Randomness epoch used in the evaluation
     epoch: u8,
+    /// Absolute number of the epoch
+    #[serde(rename = "epochNumber")]
+    epoch_number: u64,
+    /// Number of points which will follow
+    count: usize,
+}
+
+/// Evaluated point line of a streamed randomness response
+/// Lines are sent in the same order as the request points.
+#[derive(Serialize, Debug)]
+struct StreamPoint {
+    /// Compressed result point, in the request's encoding
+    point: String,
+    /// Tag given for this point in the request, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<Value>,
+}
+
+/// Response format for the batch endpoint
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BatchResponse {
+    /// Evaluation results, as returned by `/randomness`
+    pub randomness: RandomnessResponse,
+    /// Epoch and key information, as returned by `/info`
+    /// This describes the state the points were evaluated in.
+    pub info: InfoResponse,
 }
 
 /// Response format for the info endpoint
 /// Rename fields to match the earlier golang implementation.
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct InfoResponse {
     /// ServerPublicKey used to verify zero-knowledge proof
     #[serde(rename = "publicKey")]
-    public_key: String,
+    pub public_key: String,
     /// Currently active randomness epoch
     #[serde(rename = "currentEpoch")]
-    current_epoch: u8,
+    pub current_epoch: u8,
+    /// Absolute number of the current epoch
+    /// Unlike `current_epoch`, this doesn't wrap when the key is
+    /// rotated, so it identifies the epoch across rotations.
+    #[serde(rename = "currentEpochNumber")]
+    pub current_epoch_number: u64,
     /// Timestamp of the next epoch rotation
     /// This should be a string in RFC 3339 format,
-    /// e.g. 2023-03-14T16:33:05Z.
+    /// e.g. 2023-03-14T16:33:05Z. It's rounded up to a whole
+    /// second, so may be up to a second after the rotation.
     #[serde(rename = "nextEpochTime")]
-    next_epoch_time: Option<String>,
+    pub next_epoch_time: Option<String>,
+    /// Server clock at the time of the response
+    /// Clients can compare this with their own clock to detect
+    /// skew before computing epoch boundaries locally.
+    #[serde(rename = "serverTime")]
+    pub server_time: String,
+    /// Duration of each epoch
+    /// Clients can combine this with `next_epoch_time` to compute
+    /// later epoch boundaries themselves.
+    #[serde(rename = "epochSeconds")]
+    pub epoch_seconds: u32,
     /// Maximum number of points accepted in a single request
     #[serde(rename = "maxPoints")]
-    max_points: usize,
+    pub max_points: usize,
+    /// Epochs following the current one before the key is rotated
+    /// Evaluations can't be reproduced after a key rotation.
+    #[serde(rename = "epochsRemaining")]
+    pub epochs_remaining: u8,
+    /// ServerPublicKey used before the last key rotation
+    /// Only reported with `--retain-previous-key`, once the key
+    /// has rotated.
+    #[serde(
+        rename = "previousPublicKey",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub previous_public_key: Option<String>,
+    /// Details of the PPOPRF implementation
+    /// Clients can check these for compatibility.
+    pub ppoprf: PpoprfInfo,
+    /// Every epoch which can currently be evaluated, with its key
+    /// During a grace period this includes the previous epoch as
+    /// well as the current one, so clients can verify responses
+    /// from either side of the boundary.
+    #[serde(rename = "epochKeys")]
+    pub epoch_keys: Vec<EpochKey>,
+    /// Whether the epoch was being advanced when the request arrived
+    /// Requests made meanwhile wait for it, so clients seeing this
+    /// may want to back off briefly.
+    pub rotating: bool,
+}
+
+/// Evaluable epoch and the key it's evaluated under
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EpochKey {
+    /// Randomness epoch tag
+    pub epoch: u8,
+    /// ServerPublicKey used to verify evaluations in the epoch
+    #[serde(rename = "publicKey")]
+    pub public_key: String,
+}
+
+/// PPOPRF library details reported by the info endpoint
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PpoprfInfo {
+    /// Version of the ppoprf crate
+    pub version: String,
+    /// Prime-order group of the points
+    pub curve: String,
+    /// Length of compressed points in bytes
+    #[serde(rename = "pointLength")]
+    pub point_length: usize,
+}
+
+impl PpoprfInfo {
+    /// Details of the implementation this server was built with
+    fn current() -> Self {
+        PpoprfInfo {
+            version: crate::PPOPRF_VERSION.to_string(),
+            curve: "ristretto255".to_string(),
+            point_length: ppoprf::COMPRESSED_POINT_LEN,
+        }
+    }
+}
+
+/// Response format for the stats endpoint
+#[derive(Serialize, Debug)]
+pub struct StatsResponse {
+    /// Seconds since the service started
+    #[serde(rename = "uptimeSeconds")]
+    uptime_seconds: u64,
+    /// Randomness requests handled since the service started
+    #[serde(rename = "totalRequests")]
+    total_requests: u64,
+    /// Points evaluated since the service started
+    #[serde(rename = "totalPoints")]
+    total_points: u64,
+    /// Currently active randomness epoch
+    #[serde(rename = "currentEpoch")]
+    current_epoch: u8,
+    /// Epochs following the current one before the key is rotated
+    #[serde(rename = "epochsRemaining")]
+    epochs_remaining: u8,
+    /// RFC 3339 timestamp of the last epoch advance or key rotation
+    /// None until the first one, and for replicas.
+    #[serde(rename = "lastRotationTime")]
+    last_rotation_time: Option<String>,
+}
+
+/// Response format for the epoch endpoint
+#[derive(Serialize, Debug)]
+pub struct EpochResponse {
+    /// Epoch the client asked about
+    epoch: u64,
+    /// Whether evaluations are currently allowed in the epoch
+    valid: bool,
+    /// Why the epoch is or isn't valid
+    reason: EpochStatus,
+}
+
+/// Request format for the verify endpoint
+#[derive(Deserialize, Debug)]
+pub struct VerifyRequest {
+    /// Points originally submitted to the randomness endpoint
+    points: BoundedArray<String>,
+    /// Evaluated points returned by the randomness endpoint
+    /// Should be in one-to-one correspondence with `points`.
+    outputs: BoundedArray<String>,
+    /// Base64-encoded bincode proofs for each evaluation
+    proofs: BoundedArray<String>,
+    /// Randomness epoch used in the evaluation
+    epoch: Number,
+    /// ServerPublicKey reported by the info endpoint
+    #[serde(rename = "publicKey")]
+    public_key: String,
+}
+
+/// Response format for the verify endpoint
+#[derive(Serialize, Debug)]
+pub struct VerifyResponse {
+    /// Whether the proof for each evaluation verified
+    /// Elements are in one-to-one correspondence with the
+    /// request arrays.
+    valid: Vec<bool>,
 }
 
 /// Response returned to report error conditions
 #[derive(Serialize, Debug)]
 struct ErrorResponse {
+    /// Details of the error
+    error: ErrorDetail,
+}
+
+/// Description of an error condition
+#[derive(Serialize, Debug)]
+struct ErrorDetail {
+    /// Stable identifier for the kind of error
+    /// Clients should branch on this rather than `message`.
+    code: &'static str,
     /// Human-readable description of the error
     message: String,
+    /// Position of the offending element in the request, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    index: Option<usize>,
 }
 
+/// Error code attached to the extensions of error responses
+/// This lets middleware tell what went wrong without parsing
+/// the body.
+#[derive(Clone, Copy, Debug)]
+pub struct ErrorCode(pub &'static str);
+
 /// Server error conditions
 ///
 /// Used to generate an `ErrorResponse` from the `?` operator
@@ -65,16 +581,137 @@ struct ErrorResponse {
 pub enum Error {
     #[error("Couldn't lock state: RwLock poisoned")]
     LockFailure,
-    #[error("Invalid point")]
-    BadPoint,
+    #[error("Invalid point at index {0}")]
+    BadPoint(usize),
+    #[error("Invalid {1} encoding at index {0}: {2}")]
+    BadPointEncoding(usize, PointEncoding, String),
     #[error("Too many points for a single request")]
     TooManyPoints,
-    #[error("Invalid epoch {0}`")]
+    #[error("Request must contain at least one point")]
+    NoPoints,
+    #[error("points field is required")]
+    MissingPoints,
+    #[error("points must be an array, got null")]
+    NullPoints,
+    #[error("Points, outputs and proofs must have the same length")]
+    LengthMismatch,
+    #[error("Request has {0} tags for {1} points")]
+    TagsMismatch(usize, usize),
+    #[error("Shuffled results are only available as a JSON response")]
+    ShuffleUnsupported,
+    #[error("Invalid epoch {0}")]
     BadEpoch(u8),
+    #[error("Epoch {0} is not a valid epoch tag, which must be an integer from 0 to 255")]
+    BadEpochTag(Number),
+    #[error("Epoch {0} ended during evaluation")]
+    EpochChanged(u8),
     #[error("Invalid base64 encoding: {0}")]
     Base64(#[from] base64::DecodeError),
     #[error("PPOPRF error: {0}")]
     Oprf(#[from] ppoprf::PPRFError),
+    #[error("Too many requests, retry after {0} seconds")]
+    RateLimited(u64),
+    #[error("Evaluation is failing, retry after {0} seconds")]
+    CircuitOpen(u64),
+    #[error("Too many evaluations queued")]
+    QueueFull,
+    #[error("Evaluation waited too long in the queue")]
+    QueueTimeout,
+    #[error("Invalid request: {0}")]
+    BadRequest(#[from] JsonRejection),
+    #[error("Invalid request: {0}")]
+    BadPath(#[from] PathRejection),
+    #[error("Invalid request: {0}")]
+    BadQuery(#[from] QueryRejection),
+    #[error("Binary request length {0} isn't a multiple of the point length")]
+    BadBinaryLength(usize),
+    /// Carries the authentication scheme the client should use
+    #[error("Missing or incorrect credentials")]
+    Unauthorized(&'static str),
+    #[error("Request took too long to complete")]
+    Timeout,
+    #[error("Evaluation took too long to complete")]
+    EvalTimeout,
+    #[error("Couldn't read response body")]
+    ResponseBody,
+    #[error("Request body is too large")]
+    BodyTooLarge,
+    #[error("Instance is draining ahead of shutdown")]
+    Draining,
+    #[error("Epochs are exhausted, so no more evaluations are possible")]
+    Exhausted,
+    #[error("The epoch schedule hasn't started yet")]
+    NotStarted,
+    #[error("Method {0} isn't allowed for this endpoint")]
+    MethodNotAllowed(Method, HeaderValue),
+    #[error("Couldn't read request body")]
+    RequestBody,
+}
+
+impl Error {
+    /// Stable identifier for the error reported to clients
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::LockFailure | Error::ResponseBody => "internal_error",
+            Error::BadPoint(_) => "invalid_point",
+            Error::BadPointEncoding(..) | Error::Base64(_) => {
+                "invalid_encoding"
+            }
+            Error::TooManyPoints => "too_many_points",
+            Error::NoPoints => "no_points",
+            Error::LengthMismatch | Error::TagsMismatch(..) => {
+                "length_mismatch"
+            }
+            Error::BadEpoch(_) => "epoch_out_of_range",
+            Error::BadEpochTag(_) => "invalid_epoch",
+            Error::EpochChanged(_) => "epoch_changed",
+            Error::Oprf(_) => "evaluation_failed",
+            Error::RateLimited(_) => "rate_limited",
+            Error::CircuitOpen(_) => "circuit_open",
+            Error::QueueFull => "queue_full",
+            Error::QueueTimeout => "queue_timeout",
+            Error::BadRequest(JsonRejection::MissingJsonContentType(_)) => {
+                "unsupported_media_type"
+            }
+            Error::BadRequest(_)
+            | Error::BadPath(_)
+            | Error::BadQuery(_)
+            | Error::BadBinaryLength(_)
+            | Error::ShuffleUnsupported
+            | Error::MissingPoints
+            | Error::NullPoints
+            | Error::BodyTooLarge
+            | Error::RequestBody => "invalid_request",
+            Error::Unauthorized(_) => "unauthorized",
+            Error::Timeout => "request_timeout",
+            Error::EvalTimeout => "evaluation_timeout",
+            Error::Draining => "draining",
+            Error::Exhausted => "epochs_exhausted",
+            Error::NotStarted => "schedule_not_started",
+            Error::MethodNotAllowed(..) => "method_not_allowed",
+        }
+    }
+
+    /// Body describing the error to clients
+    fn to_response(&self) -> ErrorResponse {
+        ErrorResponse {
+            error: ErrorDetail {
+                code: self.code(),
+                message: self.to_string(),
+                index: self.index(),
+            },
+        }
+    }
+
+    /// Index of the request element which caused the error
+    pub fn index(&self) -> Option<usize> {
+        match self {
+            Error::BadPoint(index) | Error::BadPointEncoding(index, ..) => {
+                Some(*index)
+            }
+            _ => None,
+        }
+    }
 }
 
 /// thiserror doesn't generate a `From` impl without
@@ -89,69 +726,813 @@ impl<T> From<std::sync::PoisonError<T>> for Error {
     }
 }
 
-impl axum::response::IntoResponse for Error {
+impl IntoResponse for Error {
     /// Construct an http response from our error type
     fn into_response(self) -> axum::response::Response {
-        let code = match self {
+        let code = match &self {
             // This indicates internal failure.
-            Error::LockFailure => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::LockFailure | Error::ResponseBody => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            Error::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            Error::CircuitOpen(_)
+            | Error::QueueFull
+            | Error::QueueTimeout
+            | Error::Draining
+            | Error::Exhausted
+            | Error::NotStarted => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Error::Timeout => StatusCode::REQUEST_TIMEOUT,
+            Error::EvalTimeout => StatusCode::GATEWAY_TIMEOUT,
+            Error::BodyTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::EpochChanged(_) => StatusCode::CONFLICT,
+            Error::MethodNotAllowed(..) => StatusCode::METHOD_NOT_ALLOWED,
+            // Malformed bodies and unsupported content types have
+            // their own status codes.
+            Error::BadRequest(rejection) => rejection.status(),
+            Error::BadPath(rejection) => rejection.status(),
+            Error::BadQuery(rejection) => rejection.status(),
             // Other cases are the client's fault.
             _ => StatusCode::BAD_REQUEST,
         };
-        let body = Json(ErrorResponse {
-            message: self.to_string(),
-        });
-        (code, body).into_response()
+        let body = Json(self.to_response());
+        let mut response = (code, body).into_response();
+        response.extensions_mut().insert(ErrorCode(self.code()));
+        match self {
+            Error::RateLimited(secs) | Error::CircuitOpen(secs) => {
+                response
+                    .headers_mut()
+                    .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+            }
+            Error::Unauthorized(scheme) => {
+                response.headers_mut().insert(
+                    header::WWW_AUTHENTICATE,
+                    HeaderValue::from_static(scheme),
+                );
+            }
+            Error::MethodNotAllowed(_, allow) => {
+                response.headers_mut().insert(header::ALLOW, allow);
+            }
+            _ => {}
+        }
+        response
+    }
+}
+
+/// Check whether the client accepts the given media type
+fn accepts(headers: &HeaderMap, wanted: &str) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media_type| media_type.trim().starts_with(wanted))
+}
+
+/// Check whether the client asked for a streamed response
+fn wants_ndjson(headers: &HeaderMap) -> bool {
+    accepts(headers, NDJSON)
+}
+
+/// Check whether the client asked for a binary response
+fn wants_binary(headers: &HeaderMap) -> bool {
+    accepts(headers, OCTET_STREAM)
+}
+
+/// Encode evaluated points as a binary response
+/// The body is the epoch byte and a big-endian `u32` count of
+/// points, followed by the compressed points themselves.
+fn binary_response(
+    epoch: u8,
+    points: &[ppoprf::Point],
+) -> axum::response::Response {
+    let mut body = Vec::with_capacity(
+        BINARY_HEADER_LEN + points.len() * ppoprf::COMPRESSED_POINT_LEN,
+    );
+    body.push(epoch);
+    let count = u32::try_from(points.len()).expect("batch size fits in u32");
+    body.extend_from_slice(&count.to_be_bytes());
+    for point in points {
+        body.extend_from_slice(point.as_bytes());
+    }
+    ([(header::CONTENT_TYPE, OCTET_STREAM)], body).into_response()
+}
+
+/// Evaluate points in the background, streaming results as ndjson
+///
+/// The first line reports the epoch and number of points, followed
+/// by one line per evaluated point. Points are evaluated in chunks
+/// of `eval::CHUNK_SIZE`, as for other large batches, with the
+/// state lock taken for each chunk rather than held for the whole
+/// batch, so a slow client can't delay epoch rotation. If the epoch
+/// rotates part-way through, an `Error::EpochChanged` line is sent
+/// and the stream ends, as it does if an evaluation fails.
+fn stream_randomness(
+    state: OPRFState,
+    points: Vec<ppoprf::Point>,
+    epoch: u8,
+    epoch_number: u64,
+    encoding: PointEncoding,
+    base64: Base64Variant,
+    tags: Option<Vec<Value>>,
+) -> axum::response::Response {
+    let (tx, rx) = tokio::sync::mpsc::channel(STREAM_BUFFER);
+    tokio::task::spawn_blocking(move || {
+        let header = StreamHeader {
+            epoch,
+            epoch_number,
+            count: points.len(),
+        };
+        if tx.blocking_send(ndjson_line(&header)).is_err() {
+            return;
+        }
+        for (n, chunk) in points.chunks(eval::CHUNK_SIZE).enumerate() {
+            // Keep the indices of errors relative to the whole batch.
+            let offset = n * eval::CHUNK_SIZE;
+            let result = state
+                .read()
+                .map_err(Error::from)
+                .and_then(|s| {
+                    if !s.accepts(epoch) {
+                        return Err(Error::EpochChanged(epoch));
+                    }
+                    eval::evaluate_points(&s.server, chunk, epoch)
+                })
+                .map_err(|e| match e {
+                    Error::BadPoint(index) => Error::BadPoint(offset + index),
+                    e => e,
+                });
+            let outputs = match result {
+                Ok(outputs) => outputs,
+                Err(e) => {
+                    let _ = tx.blocking_send(ndjson_line(&e.to_response()));
+                    return;
+                }
+            };
+            for (index, output) in (offset..).zip(outputs) {
+                let point = encoding.encode(base64, &output);
+                let tag = tags.as_ref().map(|tags| tags[index].clone());
+                let line = ndjson_line(&StreamPoint { point, tag });
+                // Stop if the client has gone away.
+                if tx.blocking_send(line).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    let body = StreamBody::new(
+        ReceiverStream::new(rx).map(Ok::<_, std::convert::Infallible>),
+    );
+    ([(header::CONTENT_TYPE, NDJSON)], body).into_response()
+}
+
+/// Serialize a single line of an ndjson stream
+fn ndjson_line<T: Serialize>(value: &T) -> Bytes {
+    let mut line =
+        serde_json::to_vec(value).expect("stream lines should serialize");
+    line.push(b'\n');
+    line.into()
+}
+
+/// Convert a requested epoch to a ppoprf epoch tag
+/// Tags are `u8`, so anything else can never be a valid epoch,
+/// whatever the server's active range.
+fn epoch_tag(epoch: &Number) -> Result<u8, Error> {
+    epoch
+        .as_u64()
+        .and_then(|epoch| u8::try_from(epoch).ok())
+        .ok_or_else(|| Error::BadEpochTag(epoch.clone()))
+}
+
+/// Check the number of points in a randomness request
+fn check_batch_size(count: usize) -> Result<(), Error> {
+    if count > crate::MAX_POINTS {
+        warn!(
+            points = count,
+            max_points = crate::MAX_POINTS,
+            "rejecting oversize randomness request"
+        );
+        metrics::increment_counter!(telemetry::REJECTED_OVERSIZE);
+        return Err(Error::TooManyPoints);
+    }
+    // An empty batch is almost certainly a client bug.
+    if count == 0 {
+        return Err(Error::NoPoints);
     }
+    Ok(())
+}
+
+/// Time limit for evaluating a randomness request
+#[derive(Clone, Copy, Debug)]
+pub struct EvalTimeout(pub Duration);
+
+/// Evaluate a batch, on the queue's workers if there is one
+/// With a timeout, evaluation runs off the request's task so the
+/// request can give up on it. The work is then cancelled before
+/// its next chunk.
+async fn evaluate(
+    queue: Option<Extension<Arc<EvalQueue>>>,
+    timeout: Option<Extension<EvalTimeout>>,
+    state: OPRFState,
+    inputs: Vec<Vec<u8>>,
+    epoch: Option<u8>,
+) -> Result<eval::BatchResult, Error> {
+    offload(queue, timeout, move |cancelled| {
+        eval::evaluate_cancellable(&state, &inputs, epoch, cancelled)
+    })
+    .await
+}
+
+/// Run evaluation work as configured by the queue and timeout
+/// `work` is passed a flag which is set if the request times out,
+/// so it can stop early.
+async fn offload<T, F>(
+    queue: Option<Extension<Arc<EvalQueue>>>,
+    timeout: Option<Extension<EvalTimeout>>,
+    work: F,
+) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: FnOnce(&AtomicBool) -> Result<T, Error> + Send + 'static,
+{
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let flag = cancelled.clone();
+    let work = move || work(&flag);
+    let Some(Extension(EvalTimeout(limit))) = timeout else {
+        return match queue {
+            Some(Extension(queue)) => queue.run(work).await,
+            None => work(),
+        };
+    };
+    let result = match queue {
+        Some(Extension(queue)) => {
+            tokio::time::timeout(limit, queue.run(work)).await
+        }
+        None => {
+            let task = tokio::task::spawn_blocking(work);
+            tokio::time::timeout(limit, task)
+                .await
+                // Only if the evaluation panicked.
+                .map(|result| result.unwrap_or(Err(Error::LockFailure)))
+        }
+    };
+    result.unwrap_or_else(|_| {
+        warn!("evaluation timed out after {limit:?}");
+        cancelled.store(true, Ordering::Relaxed);
+        Err(Error::EvalTimeout)
+    })
+}
+
+/// Reorder items to match a permutation
+fn permute<T: Clone>(items: &[T], permutation: &[usize]) -> Vec<T> {
+    permutation
+        .iter()
+        .map(|&index| items[index].clone())
+        .collect()
 }
 
 /// Process PPOPRF evaluation requests
 pub async fn randomness(
     State(state): State<OPRFState>,
-    Json(request): Json<RandomnessRequest>,
-) -> Result<Json<RandomnessResponse>, Error> {
+    Extension(stats): Extension<Arc<Stats>>,
+    Extension(base64): Extension<Base64Variant>,
+    queue: Option<Extension<Arc<EvalQueue>>>,
+    timeout: Option<Extension<EvalTimeout>>,
+    headers: HeaderMap,
+    request: Result<RandomnessInput, Error>,
+) -> Result<axum::response::Response, Error> {
+    stats.record_request();
+    let request = request?;
     debug!("recv: {request:?}");
-    let state = state.read()?;
-    let epoch = request.epoch.unwrap_or(state.epoch);
-    if epoch != state.epoch {
-        return Err(Error::BadEpoch(epoch));
+    let start = Instant::now();
+    let (inputs, epoch, encoding, tags, shuffle) = match request {
+        RandomnessInput::Json(request) => {
+            let (inputs, epoch) = request.inputs(base64)?;
+            metrics::histogram!(
+                telemetry::DECODE_DURATION,
+                (request.parse_time + start.elapsed()).as_secs_f64(),
+                "format" => "json"
+            );
+            (
+                inputs,
+                epoch,
+                request.encoding,
+                request.tags.map(BoundedArray::into_vec),
+                request.shuffle,
+            )
+        }
+        RandomnessInput::Binary { points, epoch } => {
+            if points.len() % ppoprf::COMPRESSED_POINT_LEN != 0 {
+                return Err(Error::BadBinaryLength(points.len()));
+            }
+            check_batch_size(points.len() / ppoprf::COMPRESSED_POINT_LEN)?;
+            let epoch = epoch.map(Number::from);
+            let epoch = epoch.as_ref().map(epoch_tag).transpose()?;
+            let inputs = points
+                .chunks(ppoprf::COMPRESSED_POINT_LEN)
+                .map(<[u8]>::to_vec)
+                .collect();
+            metrics::histogram!(
+                telemetry::DECODE_DURATION,
+                start.elapsed().as_secs_f64(),
+                "format" => "binary"
+            );
+            (inputs, epoch, PointEncoding::default(), None, false)
+        }
+    };
+    // Other formats can't carry the permutation.
+    if shuffle && (wants_binary(&headers) || wants_ndjson(&headers)) {
+        return Err(Error::ShuffleUnsupported);
     }
-    if request.points.len() > crate::MAX_POINTS {
-        return Err(Error::TooManyPoints);
+    if wants_binary(&headers) {
+        let result = evaluate(queue, timeout, state, inputs, epoch).await?;
+        stats.record_points(result.points.len());
+        debug!("send: {} binary points", result.points.len());
+        let start = Instant::now();
+        let response = binary_response(result.epoch, &result.points);
+        metrics::histogram!(
+            telemetry::ENCODE_DURATION,
+            start.elapsed().as_secs_f64(),
+            "format" => "binary"
+        );
+        return Ok(response);
     }
-    // Don't support returning proofs until we have a more
-    // space-efficient batch proof implemented in ppoprf.
-    let mut points = Vec::with_capacity(request.points.len());
-    for base64_point in request.points {
-        let input = BASE64.decode(base64_point)?;
-        // FIXME: Point::from is fallible and needs to return a result.
-        // partial work-around: check correct length
-        if input.len() != ppoprf::COMPRESSED_POINT_LEN {
-            return Err(Error::BadPoint);
-        }
-        let point = ppoprf::Point::from(input.as_slice());
-        let evaluation = state.server.eval(&point, epoch, false)?;
-        points.push(BASE64.encode(evaluation.output.as_bytes()));
-    }
-    let response = RandomnessResponse { points, epoch };
+    if wants_ndjson(&headers) {
+        let points = eval::decode_points(&inputs)?;
+        // The stream takes the lock for each point itself.
+        let (epoch, epoch_number) = {
+            let s = state.read()?;
+            let epoch = eval::select_epoch(epoch, &s)?;
+            (epoch, s.epoch_number(epoch))
+        };
+        // Counted up front, since evaluation happens in the background.
+        stats.record_points(points.len());
+        debug!("send: streaming {} points", points.len());
+        return Ok(stream_randomness(
+            state,
+            points,
+            epoch,
+            epoch_number,
+            encoding,
+            base64,
+            tags,
+        ));
+    }
+    let result = evaluate(queue, timeout, state, inputs, epoch).await?;
+    stats.record_points(result.points.len());
+    let start = Instant::now();
+    let response =
+        randomness_response(&result, encoding, base64, tags, shuffle);
+    // Logging isn't counted, since it's usually disabled.
+    let encode_time = start.elapsed();
     debug!("send: {response:?}");
-    Ok(Json(response))
+    let start = Instant::now();
+    let response = Json(response).into_response();
+    metrics::histogram!(
+        telemetry::ENCODE_DURATION,
+        (encode_time + start.elapsed()).as_secs_f64(),
+        "format" => "json"
+    );
+    Ok(response)
+}
+
+/// Encode an evaluated batch as a JSON randomness response
+fn randomness_response(
+    result: &eval::BatchResult,
+    encoding: PointEncoding,
+    base64: Base64Variant,
+    mut tags: Option<Vec<Value>>,
+    shuffle: bool,
+) -> RandomnessResponse {
+    let mut points: Vec<String> = result
+        .points
+        .iter()
+        .map(|output| encoding.encode(base64, output))
+        .collect();
+    let mut permutation = None;
+    if shuffle {
+        let mut order: Vec<usize> = (0..points.len()).collect();
+        order.shuffle(&mut rand::thread_rng());
+        points = permute(&points, &order);
+        tags = tags.map(|tags| permute(&tags, &order));
+        permutation = Some(order);
+    }
+    RandomnessResponse {
+        points,
+        epoch: result.epoch,
+        epoch_number: result.epoch_number,
+        epoch_time_remaining_seconds: result
+            .time_remaining
+            .map(|remaining| remaining.as_secs()),
+        tags,
+        permutation,
+    }
+}
+
+/// Convert a camelCase field name to snake_case
+fn snake_case(name: &str) -> String {
+    let mut snake = String::with_capacity(name.len() + 4);
+    for c in name.chars() {
+        if c.is_ascii_uppercase() {
+            snake.push('_');
+        }
+        snake.push(c.to_ascii_lowercase());
+    }
+    snake
+}
+
+/// Rename the fields of a serialized body to the configured case
+/// Response fields are declared in camelCase, so nothing needs to
+/// change by default.
+fn apply_field_case(case: FieldCase, value: Value) -> Value {
+    match (case, value) {
+        (FieldCase::CamelCase, value) => value,
+        (FieldCase::SnakeCase, Value::Object(fields)) => Value::Object(
+            fields
+                .into_iter()
+                .map(|(name, value)| {
+                    (snake_case(&name), apply_field_case(case, value))
+                })
+                .collect(),
+        ),
+        (FieldCase::SnakeCase, Value::Array(values)) => Value::Array(
+            values
+                .into_iter()
+                .map(|value| apply_field_case(case, value))
+                .collect(),
+        ),
+        (_, value) => value,
+    }
+}
+
+/// How long before the next epoch cached `/info` responses expire
+#[derive(Clone, Copy, Debug)]
+pub struct CacheMargin(pub Duration);
+
+/// Caching headers for the current epoch and key information
+/// This stays the same until the next epoch, so caches may keep
+/// it until shortly before then. While a grace period is open the
+/// evaluable epochs change part way through, and without a
+/// schedule the next epoch isn't known, so then nothing is cached.
+fn info_cache_headers(state: &OPRFServer, margin: Duration) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    let now = OffsetDateTime::now_utc();
+    let expires = state
+        .current_window()
+        .filter(|_| state.grace_epoch.is_none())
+        .map(|(_, end)| end - margin)
+        .filter(|&expires| expires > now);
+    let Some(expires) = expires else {
+        headers.insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("no-cache"),
+        );
+        return headers;
+    };
+    let max_age = (expires - now).whole_seconds();
+    let cache_control = format!("public, max-age={max_age}");
+    let expires = httpdate::fmt_http_date(expires.into());
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_str(&cache_control)
+            .expect("cache control should be a valid header"),
+    );
+    headers.insert(
+        header::EXPIRES,
+        HeaderValue::from_str(&expires).expect("dates should be valid headers"),
+    );
+    headers
+}
+
+/// Mark a response as not to be stored by caches
+/// Evaluations are specific to the request, so never reusable.
+pub async fn no_store(
+    mut response: axum::response::Response,
+) -> axum::response::Response {
+    response
+        .headers_mut()
+        .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    response
+}
+
+/// Report the methods a route supports
+/// The router rejects methods a route doesn't handle with an empty
+/// `405 Method Not Allowed` and an `Allow` header listing those it
+/// does. Routes don't handle `OPTIONS` themselves, so it gets that
+/// list, along with `OPTIONS`, in an empty `204 No Content`. Other
+/// methods get the usual error body alongside the header. Unknown
+/// paths are still `404 Not Found`.
+pub async fn allowed_methods(
+    request: Request<Body>,
+    next: Next<Body>,
+) -> axum::response::Response {
+    let method = request.method().clone();
+    let response = next.run(request).await;
+    if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+        return response;
+    }
+    let Some(allow) = response.headers().get(header::ALLOW).cloned() else {
+        return response;
+    };
+    if method != Method::OPTIONS {
+        return Error::MethodNotAllowed(method, allow).into_response();
+    }
+    let Ok(allow) = allow.to_str() else {
+        return response;
+    };
+    let allow = format!("{allow},OPTIONS");
+    (StatusCode::NO_CONTENT, [(header::ALLOW, allow)]).into_response()
 }
 
 /// Process PPOPRF epoch and key requests
 pub async fn info(
     State(state): State<OPRFState>,
-) -> Result<Json<InfoResponse>, Error> {
+    Extension(case): Extension<FieldCase>,
+    Extension(rotating): Extension<Rotating>,
+    Extension(CacheMargin(margin)): Extension<CacheMargin>,
+    headers: HeaderMap,
+) -> Result<axum::response::Response, Error> {
     debug!("recv: info request");
+    // Checked before locking, since the lock waits for rotation.
+    let rotating = rotating.get();
     let state = state.read()?;
-    let public_key = state.server.get_public_key().serialize_to_bincode()?;
-    let public_key = BASE64.encode(public_key);
-    let response = InfoResponse {
-        current_epoch: state.epoch,
-        next_epoch_time: state.next_epoch_time.clone(),
+    if !state.started() {
+        return Err(Error::NotStarted);
+    }
+    if accepts(&headers, PEM_FILE) {
+        let key = state.public_key_bincode.clone();
+        let headers = info_cache_headers(&state, margin);
+        drop(state);
+        debug!("send: PEM public key");
+        let content_type = [(header::CONTENT_TYPE, PEM_FILE)];
+        return Ok(
+            (headers, content_type, crate::pem::encode(&key)).into_response()
+        );
+    }
+    let response = info_response(&state, rotating);
+    let headers = info_cache_headers(&state, margin);
+    drop(state);
+    debug!("send: {response:?}");
+    let response =
+        serde_json::to_value(response).expect("info response should serialize");
+    Ok((headers, Json(apply_field_case(case, response))).into_response())
+}
+
+/// Describe the current epoch and key
+fn info_response(state: &OPRFServer, rotating: bool) -> InfoResponse {
+    let current_epoch = state.current_epoch();
+    InfoResponse {
+        current_epoch,
+        current_epoch_number: state.epoch_number(current_epoch),
+        next_epoch_time: state.next_epoch_time(),
+        server_time: OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .expect("well-known timestamp format should always succeed"),
+        epoch_seconds: state.epoch_seconds,
         max_points: crate::MAX_POINTS,
-        public_key,
+        epochs_remaining: state.epochs_remaining(),
+        public_key: state.public_key.clone(),
+        previous_public_key: state.previous_public_key.clone(),
+        ppoprf: PpoprfInfo::current(),
+        // Grace periods never span a key rotation, so every
+        // evaluable epoch is under the current key.
+        epoch_keys: state
+            .evaluable_epochs()
+            .into_iter()
+            .map(|epoch| EpochKey {
+                epoch,
+                public_key: state.public_key.clone(),
+            })
+            .collect(),
+        rotating,
+    }
+}
+
+/// Evaluate points along with the info describing their epoch
+/// The evaluation and the info are taken under a single read lock,
+/// so unlike separate `/randomness` and `/info` requests they can't
+/// straddle an epoch rotation.
+pub async fn batch(
+    State(state): State<OPRFState>,
+    Extension(stats): Extension<Arc<Stats>>,
+    Extension(base64): Extension<Base64Variant>,
+    Extension(case): Extension<FieldCase>,
+    queue: Option<Extension<Arc<EvalQueue>>>,
+    timeout: Option<Extension<EvalTimeout>>,
+    request: Result<Json<RandomnessRequest>, JsonRejection>,
+) -> Result<Json<Value>, Error> {
+    stats.record_request();
+    let Json(request) = request?;
+    debug!("recv: batch {request:?}");
+    let (inputs, epoch) = request.inputs(base64)?;
+    // Large batches are chunked like `/randomness`, so they don't
+    // hold the lock long enough to starve a rotation.
+    let (result, info) = offload(queue, timeout, move |cancelled| {
+        eval::evaluate_cancellable_with(
+            &state,
+            &inputs,
+            epoch,
+            cancelled,
+            // Unlike `/info`, this reports whether a rotation is
+            // under way once the evaluation has the lock.
+            |s| info_response(s, s.rotating.get()),
+        )
+    })
+    .await?;
+    stats.record_points(result.points.len());
+    let response = BatchResponse {
+        randomness: randomness_response(
+            &result,
+            request.encoding,
+            base64,
+            request.tags.map(BoundedArray::into_vec),
+            request.shuffle,
+        ),
+        info,
+    };
+    debug!("send: {response:?}");
+    let mut response = serde_json::to_value(response)
+        .expect("batch response should serialize");
+    // Only the info is subject to `--json-field-case`, as in `/info`.
+    response["info"] = apply_field_case(case, response["info"].take());
+    Ok(Json(response))
+}
+
+/// Decode a base64-encoded compressed Ristretto point
+/// Returns `None` unless the encoding is valid and decompresses
+/// to a curve point.
+fn decode_point(
+    base64: Base64Variant,
+    base64_point: &str,
+) -> Option<ppoprf::Point> {
+    let bytes = base64.engine().decode(base64_point).ok()?;
+    let compressed = CompressedRistretto::from_slice(&bytes).ok()?;
+    compressed.decompress()?;
+    Some(ppoprf::Point::from(bytes.as_slice()))
+}
+
+/// Check the proof for a single evaluation
+fn verify_one(
+    base64: Base64Variant,
+    public_key: &ppoprf::ServerPublicKey,
+    point: &str,
+    output: &str,
+    proof: &str,
+    epoch: u8,
+) -> bool {
+    // ppoprf panics on points which don't decompress, so
+    // treat anything malformed as a failed verification.
+    let (Some(input), Some(output)) =
+        (decode_point(base64, point), decode_point(base64, output))
+    else {
+        return false;
+    };
+    let Some(proof) =
+        base64.engine().decode(proof).ok().and_then(|bytes| {
+            ppoprf::ProofDLEQ::load_from_bincode(&bytes).ok()
+        })
+    else {
+        return false;
+    };
+    let evaluation = ppoprf::Evaluation {
+        output,
+        proof: Some(proof),
+    };
+    ppoprf::Client::verify(public_key, &input, &evaluation, epoch)
+}
+
+/// Verify PPOPRF evaluation proofs on behalf of a client
+pub async fn verify(
+    Extension(base64): Extension<Base64Variant>,
+    request: Result<Json<VerifyRequest>, JsonRejection>,
+) -> Result<Json<VerifyResponse>, Error> {
+    let Json(request) = request?;
+    debug!("recv: {request:?}");
+    if request.points.len() > crate::MAX_POINTS {
+        return Err(Error::TooManyPoints);
+    }
+    if request.outputs.len() != request.points.len()
+        || request.proofs.len() != request.points.len()
+    {
+        return Err(Error::LengthMismatch);
+    }
+    let epoch = epoch_tag(&request.epoch)?;
+    let public_key = base64.engine().decode(request.public_key)?;
+    let public_key = ppoprf::ServerPublicKey::load_from_bincode(&public_key)?;
+    let valid = request
+        .points
+        .iter()
+        .zip(request.outputs.iter())
+        .zip(request.proofs.iter())
+        .map(|((point, output), proof)| {
+            verify_one(base64, &public_key, point, output, proof, epoch)
+        })
+        .collect();
+    let response = VerifyResponse { valid };
+    debug!("send: {response:?}");
+    Ok(Json(response))
+}
+
+/// Report running totals and epoch state for dashboards
+pub async fn stats(
+    State(state): State<OPRFState>,
+    Extension(stats): Extension<Arc<Stats>>,
+    Extension(case): Extension<FieldCase>,
+) -> Result<Json<Value>, Error> {
+    debug!("recv: stats request");
+    let state = state.read()?;
+    let format = |time: OffsetDateTime| {
+        time.format(&Rfc3339)
+            .expect("well-known timestamp format should always succeed")
+    };
+    let response = StatsResponse {
+        uptime_seconds: stats.uptime().as_secs(),
+        total_requests: stats.requests(),
+        total_points: stats.points(),
+        current_epoch: state.current_epoch(),
+        epochs_remaining: state.epochs_remaining(),
+        last_rotation_time: state.last_rotation.map(format),
+    };
+    debug!("send: {response:?}");
+    let value = serde_json::to_value(response)
+        .expect("stats response should serialize");
+    Ok(Json(apply_field_case(case, value)))
+}
+
+/// Report whether an epoch can currently be evaluated
+/// Clients can use this to check a cached epoch instead of
+/// submitting points to find out.
+pub async fn epoch(
+    State(state): State<OPRFState>,
+    epoch: Result<Path<u64>, PathRejection>,
+) -> Result<Json<EpochResponse>, Error> {
+    let Path(epoch) = epoch?;
+    debug!("recv: epoch {epoch} request");
+    // Anything which isn't an epoch tag is out of range too.
+    let state = state.read()?;
+    if !state.started() {
+        return Err(Error::NotStarted);
+    }
+    let reason = match u8::try_from(epoch) {
+        Ok(tag) => state.epoch_status(tag),
+        Err(_) => EpochStatus::OutOfRange,
+    };
+    drop(state);
+    let response = EpochResponse {
+        epoch,
+        valid: reason.is_valid(),
+        reason,
     };
     debug!("send: {response:?}");
     Ok(Json(response))
 }
+
+/// Whether the instance is being drained ahead of shutdown
+/// This is shared by `/ready` and the admin endpoints which set it.
+#[derive(Default)]
+pub struct Drain(AtomicBool);
+
+impl Drain {
+    /// Whether load balancers should stop sending traffic
+    pub fn is_draining(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Report whether the instance should receive new traffic
+/// This fails while draining, though requests are still served.
+pub async fn ready(
+    State(state): State<OPRFState>,
+    Extension(drain): Extension<Arc<Drain>>,
+) -> Result<&'static str, Error> {
+    if drain.is_draining() {
+        return Err(Error::Draining);
+    }
+    let state = state.read()?;
+    if state.exhausted() {
+        return Err(Error::Exhausted);
+    }
+    if !state.started() {
+        return Err(Error::NotStarted);
+    }
+    Ok("ready\n")
+}
+
+/// Stop reporting readiness, so load balancers drain the instance
+pub async fn drain(Extension(drain): Extension<Arc<Drain>>) -> StatusCode {
+    drain.0.store(true, Ordering::Relaxed);
+    info!("draining: /ready now fails");
+    StatusCode::NO_CONTENT
+}
+
+/// Resume reporting readiness after `drain`
+pub async fn undrain(Extension(drain): Extension<Arc<Drain>>) -> StatusCode {
+    drain.0.store(false, Ordering::Relaxed);
+    info!("no longer draining: /ready now succeeds");
+    StatusCode::NO_CONTENT
+}
+
+/// Return a machine-readable description of the API
+pub async fn openapi() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "application/json")], OPENAPI)
+}
@@ -0,0 +1,161 @@
+//! STAR Randomness web service
+//! Replay of responses to retried requests
+
+use axum::body::{Body, Bytes, Full};
+use axum::extract::State;
+use axum::http::{header, HeaderMap, HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+use crate::handler::Error;
+use crate::signature::MAX_BODY;
+
+/// Header carrying the client's idempotency key
+pub const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+/// Header marking a response as a replay of an earlier one
+pub const REPLAYED: &str = "idempotent-replayed";
+
+/// Idempotency key and a digest of the request body
+/// Including the body means a key reused for a different request,
+/// or chosen by another client, can't return someone else's
+/// response.
+type CacheKey = (String, [u8; 32]);
+
+/// Successful response saved for replay
+#[derive(Clone)]
+struct Saved {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    /// When the response was generated
+    created: Instant,
+}
+
+/// Recent responses to requests carrying an idempotency key
+pub struct ResponseCache {
+    /// How long responses are kept
+    ttl: Duration,
+    /// Maximum number of responses kept at once
+    capacity: usize,
+    entries: Mutex<HashMap<CacheKey, Saved>>,
+}
+
+impl ResponseCache {
+    /// Create a cache holding up to `capacity` responses for `ttl`
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        ResponseCache {
+            ttl,
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Look up an unexpired response
+    fn get(&self, key: &CacheKey, now: Instant) -> Option<Saved> {
+        let entries = self.entries.lock().expect("response cache lock poisoned");
+        entries
+            .get(key)
+            .filter(|saved| now.saturating_duration_since(saved.created) < self.ttl)
+            .cloned()
+    }
+
+    /// Save a response, making room if the cache is full
+    fn insert(&self, key: CacheKey, saved: Saved, now: Instant) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().expect("response cache lock poisoned");
+        if !entries.contains_key(&key) && entries.len() >= self.capacity {
+            self.evict(&mut entries, now);
+        }
+        entries.insert(key, saved);
+    }
+
+    /// Make room in a full cache, freeing at least a batch of entries
+    /// Finding entries to evict means scanning the whole cache, so
+    /// this is done once per batch of inserts rather than for each
+    /// one.
+    fn evict(&self, entries: &mut HashMap<CacheKey, Saved>, now: Instant) {
+        // Expired entries go first, then the oldest.
+        entries.retain(|_, saved| now.saturating_duration_since(saved.created) < self.ttl);
+        let batch = (self.capacity / 16).max(1);
+        let excess = entries.len().saturating_sub(self.capacity - batch);
+        if excess > 0 {
+            let mut created: Vec<(Instant, &CacheKey)> = entries
+                .iter()
+                .map(|(key, saved)| (saved.created, key))
+                .collect();
+            created.select_nth_unstable(excess - 1);
+            let oldest: Vec<CacheKey> = created[..excess]
+                .iter()
+                .map(|(_, key)| (*key).clone())
+                .collect();
+            for key in &oldest {
+                entries.remove(key);
+            }
+        }
+    }
+}
+
+/// Middleware replaying the response to a repeated request
+/// Requests with the same `Idempotency-Key` header and body as an
+/// earlier successful json response get the same bytes back,
+/// without evaluating again. Errors and streamed responses aren't
+/// saved, so retrying them evaluates as usual.
+pub async fn replay(
+    State(cache): State<Arc<ResponseCache>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Result<Response, Error> {
+    let Some(idempotency_key) = request
+        .headers()
+        .get(IDEMPOTENCY_KEY)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned)
+    else {
+        return Ok(next.run(request).await);
+    };
+    let (parts, body) = request.into_parts();
+    let body = match hyper::body::to_bytes(http_body::Limited::new(body, MAX_BODY)).await {
+        Ok(body) => body,
+        Err(e) if e.is::<http_body::LengthLimitError>() => return Err(Error::BodyTooLarge),
+        Err(_) => return Err(Error::RequestBody),
+    };
+    let key = (idempotency_key, Sha256::digest(&body).into());
+    if let Some(saved) = cache.get(&key, Instant::now()) {
+        debug!("replaying response for idempotency key {:?}", key.0);
+        let mut response = (saved.status, saved.headers, Full::from(saved.body)).into_response();
+        response
+            .headers_mut()
+            .insert(REPLAYED, HeaderValue::from_static("true"));
+        return Ok(response);
+    }
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+    if !response.status().is_success() || !is_json {
+        return Ok(response);
+    }
+    let (parts, body) = response.into_parts();
+    let body = hyper::body::to_bytes(body)
+        .await
+        .map_err(|_| Error::ResponseBody)?;
+    let saved = Saved {
+        status: parts.status,
+        headers: parts.headers.clone(),
+        body: body.clone(),
+        created: Instant::now(),
+    };
+    cache.insert(key, saved, Instant::now());
+    Ok(Response::from_parts(parts, Full::from(body)).into_response())
+}
@@ -0,0 +1,312 @@
+//! STAR Randomness web service
+//! Routes and state, shared by the server binary and fuzz targets
+
+use axum::{routing::get, routing::post, Extension, Router};
+use hyper::server::conn::AddrIncoming;
+use metrics_exporter_prometheus::PrometheusHandle;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+mod access;
+mod auth;
+mod breaker;
+#[cfg(feature = "client")]
+pub mod client;
+mod config;
+pub mod eval;
+mod handler;
+mod idempotency;
+pub mod logging;
+pub mod mac;
+pub mod pem;
+mod queue;
+mod ratelimit;
+mod signature;
+pub mod state;
+pub mod statsd;
+pub mod telemetry;
+mod throttle;
+mod timeout;
+
+pub use config::{
+    Base64Variant, Config, EpochExhaustionPolicy, FieldCase, RotationFailurePolicy, Route, Secret,
+};
+pub use handler::{BatchResponse, EpochKey, InfoResponse, PpoprfInfo, RandomnessResponse};
+pub use state::OPRFState;
+pub use throttle::ThrottledIncoming;
+
+#[cfg(test)]
+mod tests;
+
+/// Maximum number of points acceptable in a single request
+pub const MAX_POINTS: usize = 1024;
+
+/// Version of the ppoprf library, reported by `/info`
+/// Cargo doesn't expose dependency versions to the build, so this
+/// must be kept in step with `Cargo.lock`. A test checks it.
+pub const PPOPRF_VERSION: &str = "0.3.1";
+
+/// Initialize an axum::Router for our web service
+/// Having this as a separate function makes testing easier.
+pub fn app(oprf_state: OPRFState, config: &Config) -> Router {
+    // Endpoints which evaluate points share the same protections.
+    // Innermost, so only the handler itself is timed.
+    let mut evaluation = [post(handler::randomness), post(handler::batch)]
+        .map(|route| route.route_layer(axum::middleware::from_fn(telemetry::record_duration)));
+    if let Some(size) = config.eval_queue_size {
+        let workers = match config.eval_queue_workers {
+            Some(workers) => workers as usize,
+            None => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        };
+        info!("queueing up to {size} evaluations for {workers} worker threads");
+        let queue = queue::EvalQueue::new(
+            size as usize,
+            workers,
+            Duration::from_millis(config.eval_queue_deadline_ms),
+        );
+        let queue = Arc::new(queue);
+        evaluation = evaluation.map(|route| route.layer(Extension(queue.clone())));
+    }
+    if let Some(ms) = config.eval_timeout_ms {
+        let limit = handler::EvalTimeout(Duration::from_millis(ms));
+        evaluation = evaluation.map(|route| route.layer(Extension(limit)));
+    }
+    if let Some(threshold) = config.circuit_breaker_threshold {
+        let breaker = breaker::CircuitBreaker::new(
+            threshold,
+            Duration::from_secs(config.circuit_breaker_cooldown_seconds),
+        );
+        let breaker = Arc::new(breaker);
+        evaluation = evaluation.map(|route| {
+            route.route_layer(axum::middleware::from_fn_with_state(
+                breaker.clone(),
+                breaker::guard,
+            ))
+        });
+    }
+    if let Some(ttl) = config.idempotency_ttl_seconds {
+        // Separate caches, so a key reused across endpoints can't
+        // replay the other endpoint's response.
+        evaluation = evaluation.map(|route| {
+            let cache = idempotency::ResponseCache::new(
+                Duration::from_secs(ttl),
+                config.idempotency_cache_size,
+            );
+            route.route_layer(axum::middleware::from_fn_with_state(
+                Arc::new(cache),
+                idempotency::replay,
+            ))
+        });
+    }
+    if config.require_signed_requests {
+        let path = config
+            .client_keys
+            .as_deref()
+            .expect("validated config has client keys");
+        let keys = signature::ClientKeys::load(path).expect("Could not load client keys");
+        if keys.is_empty() {
+            warn!("No client keys loaded; all randomness requests will be rejected");
+        }
+        info!("accepting requests signed by {} client keys", keys.len());
+        let keys = Arc::new(keys);
+        evaluation = evaluation.map(|route| {
+            route.route_layer(axum::middleware::from_fn_with_state(
+                keys.clone(),
+                signature::require_signature,
+            ))
+        });
+    }
+    if let Some(per_second) = config.rate_limit_per_second {
+        let limiter = Arc::new(ratelimit::RateLimiter::new(per_second, config.trust_proxy));
+        evaluation = evaluation.map(|route| {
+            route.route_layer(axum::middleware::from_fn_with_state(
+                limiter.clone(),
+                ratelimit::limit,
+            ))
+        });
+    }
+    // Sign outermost so rate limit errors are covered too.
+    if let Some(key) = &config.response_hmac_key {
+        let key = Arc::<[u8]>::from(key.expose().as_bytes());
+        evaluation = evaluation.map(|route| {
+            route.route_layer(axum::middleware::from_fn_with_state(key.clone(), mac::sign))
+        });
+    }
+    // Outermost, so errors aren't cached either.
+    let [randomness, batch] =
+        evaluation.map(|route| route.layer(axum::middleware::map_response(handler::no_store)));
+    // The flag is shared outside the lock, so it can be read
+    // while a rotation holds it.
+    let rotating = oprf_state
+        .read()
+        .expect("Failed to lock OPRFState")
+        .rotating
+        .clone();
+    let info = get(handler::info).layer(Extension(config.json_field_case));
+    let mut stats = get(handler::stats).layer(Extension(config.json_field_case));
+    if let Some(token) = &config.admin_token {
+        stats = stats.route_layer(axum::middleware::from_fn_with_state(
+            Arc::<str>::from(token.expose()),
+            auth::require_token,
+        ));
+    }
+    let root = match &config.root_health_check {
+        // Stable response for load balancer health checks
+        Some(body) => {
+            let body = body.clone();
+            get(|| async move { body })
+        }
+        // Friendly default route to identify the site
+        None => get(|| async { "STAR randomness server\n" }),
+    };
+    let mut routes = vec![
+        (Route::Welcome, "/", root),
+        // Main endpoints
+        (Route::Randomness, "/randomness", randomness),
+        (Route::Info, "/info", info),
+        (
+            Route::Batch,
+            "/batch",
+            batch.layer(Extension(config.json_field_case)),
+        ),
+        (Route::Epoch, "/epoch/:epoch", get(handler::epoch)),
+        (Route::Stats, "/stats", stats),
+        (Route::Verify, "/verify", post(handler::verify)),
+        (Route::Openapi, "/openapi.json", get(handler::openapi)),
+        (Route::Ready, "/ready", get(handler::ready)),
+    ];
+    // Admin endpoints change the instance's state, so are only
+    // served with a token to protect them.
+    if let Some(token) = &config.admin_token {
+        let token = Arc::<str>::from(token.expose());
+        for (path, handler) in [
+            ("/admin/drain", post(handler::drain)),
+            ("/admin/undrain", post(handler::undrain)),
+        ] {
+            let handler = handler.route_layer(axum::middleware::from_fn_with_state(
+                token.clone(),
+                auth::require_token,
+            ));
+            routes.push((Route::Admin, path, handler));
+        }
+    }
+    // Only mount the routes operators asked for.
+    let app = routes
+        .into_iter()
+        .filter(|(route, ..)| config.route_enabled(*route))
+        .fold(Router::new(), |app, (_, path, handler)| {
+            app.route(path, handler)
+        })
+        // Attach shared state
+        .layer(Extension(Arc::new(telemetry::Stats::new())))
+        .layer(Extension(config.base64_variant))
+        .layer(Extension(Arc::new(handler::Drain::default())))
+        .layer(Extension(rotating))
+        .layer(Extension(handler::CacheMargin(Duration::from_secs(
+            config.info_cache_margin_seconds.into(),
+        ))))
+        .with_state(oprf_state);
+    let app = match config.path_prefix() {
+        Some(prefix) => Router::new().nest(prefix, app),
+        None => app,
+    };
+    // The router adds `Allow` to its 405 responses outside any route
+    // layers, so this has to wrap the router as a whole to see it.
+    let app = Router::new()
+        .fallback_service(app)
+        .layer(axum::middleware::from_fn(handler::allowed_methods));
+    let app = match config.request_timeout_seconds {
+        Some(seconds) => app.layer(axum::middleware::from_fn_with_state(
+            Duration::from_secs(seconds),
+            timeout::limit,
+        )),
+        None => app,
+    };
+    // Logging must come after active routes
+    let app = app.layer(tower_http::trace::TraceLayer::new_for_http());
+    if config.access_log {
+        app.layer(axum::middleware::from_fn_with_state(
+            config.trust_proxy,
+            access::log,
+        ))
+    } else {
+        app
+    }
+}
+
+/// Open the listening socket for the service
+/// Binding the socket ourselves lets `--tcp-backlog` be applied,
+/// which hyper's own `bind` doesn't support. Keepalive and nodelay
+/// are set on each accepted connection.
+pub fn bind(config: &Config) -> std::io::Result<AddrIncoming> {
+    let socket = match config.listen {
+        SocketAddr::V4(_) => tokio::net::TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => tokio::net::TcpSocket::new_v6()?,
+    };
+    // Match std's listener, so a restarted server can rebind
+    // while old connections linger.
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    socket.bind(config.listen)?;
+    let listener = socket.listen(config.tcp_backlog)?;
+    let mut incoming = AddrIncoming::from_listener(listener).map_err(std::io::Error::other)?;
+    incoming.set_keepalive(config.http_keepalive_seconds.map(Duration::from_secs));
+    incoming.set_nodelay(config.tcp_nodelay);
+    Ok(incoming)
+}
+
+/// Configure the HTTP server on a bound listener
+/// Connections are HTTP/1.1 only unless `--http2` is given, in
+/// which case hyper detects clients starting HTTP/2 with prior
+/// knowledge and serves them h2c instead. New connections are
+/// accepted no faster than `--max-new-connections-per-second`.
+pub fn server(
+    incoming: AddrIncoming,
+    config: &Config,
+) -> hyper::server::Builder<ThrottledIncoming> {
+    if let Some(per_second) = config.max_new_connections_per_second {
+        info!("accepting at most {per_second} new connections per second");
+    }
+    let incoming = ThrottledIncoming::new(incoming, config.max_new_connections_per_second);
+    axum::Server::builder(incoming).http1_only(!config.http2)
+}
+
+/// Initialize an axum::Router for the metrics endpoint
+/// If `admin_token` is given, requests must present it.
+pub fn metrics_app(metrics_handle: PrometheusHandle, admin_token: Option<&str>) -> Router {
+    let mut metrics = get(|| async move { metrics_handle.render() });
+    if let Some(token) = admin_token {
+        metrics = metrics.route_layer(axum::middleware::from_fn_with_state(
+            Arc::<str>::from(token),
+            auth::require_token,
+        ));
+    }
+    Router::new().route("/metrics", metrics)
+}
+
+/// Serve the metrics endpoint in the background
+/// Metrics are auxiliary, so if the address can't be bound the
+/// error is only logged, unless `--require-metrics` is set.
+pub fn serve_metrics(
+    metrics_app: Router,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<(), hyper::Error> {
+    let server = match axum::Server::try_bind(&addr) {
+        Ok(server) => server,
+        Err(e) if config.require_metrics => return Err(e),
+        Err(e) => {
+            error!("Couldn't serve metrics on {addr}, continuing without them: {e}");
+            return Ok(());
+        }
+    };
+    info!("Metrics server listening on {addr}");
+    tokio::spawn(async move {
+        if let Err(e) = server.serve(metrics_app.into_make_service()).await {
+            error!("Metrics server failed: {e}");
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,47 @@
+//! STAR Randomness web service
+//! Switching log verbosity at runtime
+
+use tracing_subscriber::{reload, EnvFilter};
+
+use crate::Config;
+
+/// Filter applied while verbose logging is switched on
+/// Debug output from our own code and request tracing, without
+/// the much noisier connection handling in hyper.
+pub const VERBOSE_FILTER: &str = "info,star_randsrv=debug,tower_http=debug";
+
+/// Toggles between the configured log filter and `VERBOSE_FILTER`
+///
+/// This lets operators capture detailed logs from a running server
+/// during an incident, then quiet them again, without a restart.
+pub struct LogToggle<S> {
+    /// Handle to the reloadable filter of the installed subscriber
+    handle: reload::Handle<EnvFilter, S>,
+    /// Whether `VERBOSE_FILTER` is in effect
+    verbose: bool,
+}
+
+impl<S> LogToggle<S> {
+    /// Control the filter behind `handle`
+    /// This assumes it currently has the configured filter.
+    pub fn new(handle: reload::Handle<EnvFilter, S>) -> Self {
+        LogToggle {
+            handle,
+            verbose: false,
+        }
+    }
+
+    /// Switch to the other filter
+    /// The configured filter is rebuilt from `config`. Returns
+    /// whether verbose logging is now on.
+    pub fn toggle(&mut self, config: &Config) -> Result<bool, reload::Error> {
+        let filter = if self.verbose {
+            config.log_filter()
+        } else {
+            EnvFilter::new(VERBOSE_FILTER)
+        };
+        self.handle.reload(filter)?;
+        self.verbose = !self.verbose;
+        Ok(self.verbose)
+    }
+}
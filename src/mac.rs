@@ -0,0 +1,53 @@
+//! STAR Randomness web service
+//! Integrity codes over response bodies
+
+use axum::body::{Body, Full};
+use axum::extract::State;
+use axum::http::{header, HeaderValue, Request};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use base64::prelude::{Engine as _, BASE64_STANDARD as BASE64};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::sync::Arc;
+
+use crate::handler::Error;
+
+/// Header carrying the response integrity code
+pub const RESPONSE_MAC: &str = "x-response-mac";
+
+/// Compute the base64-encoded HMAC-SHA256 of a response body
+pub fn response_mac(key: &[u8], body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(body);
+    BASE64.encode(mac.finalize().into_bytes())
+}
+
+/// Middleware adding an `X-Response-MAC` header to json responses
+/// Streamed responses are passed through unsigned, since signing
+/// them would mean buffering the whole stream.
+pub async fn sign(
+    State(key): State<Arc<[u8]>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Result<Response, Error> {
+    let response = next.run(request).await;
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+    if !is_json {
+        return Ok(response);
+    }
+    let (mut parts, body) = response.into_parts();
+    let body = hyper::body::to_bytes(body)
+        .await
+        .map_err(|_| Error::ResponseBody)?;
+    let mac = response_mac(&key, &body);
+    parts.headers.insert(
+        RESPONSE_MAC,
+        HeaderValue::from_str(&mac).expect("base64 is a valid header value"),
+    );
+    Ok(Response::from_parts(parts, Full::from(body)).into_response())
+}
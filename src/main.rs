@@ -1,159 +1,232 @@
 //! STAR Randomness web service
 
-use axum::{routing::get, routing::post, Router};
-use axum_prometheus::PrometheusMetricLayer;
-use clap::Parser;
-use metrics_exporter_prometheus::PrometheusHandle;
+use axum_prometheus::PrometheusMetricLayerBuilder;
+#[cfg(unix)]
 use rlimit::Resource;
+use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
 use tikv_jemallocator::Jemalloc;
-use time::format_description::well_known::Rfc3339;
-use time::OffsetDateTime;
-use tracing::{debug, info, metadata::LevelFilter};
-use tracing_subscriber::EnvFilter;
+use tracing::{error, info, warn};
+
+use star_randsrv::logging::LogToggle;
+use star_randsrv::statsd::StatsdRecorder;
+use star_randsrv::{eval, state, telemetry, Config, Secret};
 
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
-mod handler;
-mod state;
-
-pub use state::OPRFState;
-
-#[cfg(test)]
-mod tests;
-
-/// Maximum number of points acceptable in a single request
-const MAX_POINTS: usize = 1024;
-
-/// Command line switches
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-pub struct Config {
-    /// Host and port to listen for http connections
-    #[arg(long, default_value = "127.0.0.1:8080")]
-    listen: String,
-    /// Duration of each randomness epoch
-    #[arg(long, default_value_t = 5)]
-    epoch_seconds: u32,
-    /// First epoch tag to make available
-    #[arg(long, default_value_t = 0)]
-    first_epoch: u8,
-    /// Last epoch tag to make available
-    #[arg(long, default_value_t = 255)]
-    last_epoch: u8,
-    /// Optional absolute time at which to anchor the first epoch
-    /// This can be used to align the epoch sequence across different
-    /// invocations.
-    #[arg(long, value_name = "RFC 3339 timestamp", value_parser = parse_timestamp)]
-    epoch_base_time: Option<OffsetDateTime>,
-    /// Increases OS nofile limit to 65535, so the server can handle
-    /// more concurrent connections.
-    #[arg(long, default_value_t = false)]
-    increase_nofile_limit: bool,
-    /// Enable prometheus metric reporting and listen on specified address.
-    #[arg(long)]
-    prometheus_listen: Option<String>,
-}
+/// Open file limit requested by `--increase-nofile-limit`
+#[cfg(unix)]
+const NOFILE_LIMIT: u64 = 65535;
 
-/// Parse a timestamp given as a config option
-fn parse_timestamp(stamp: &str) -> Result<OffsetDateTime, &'static str> {
-    OffsetDateTime::parse(stamp, &Rfc3339).map_err(|_| "Try something like '2023-05-15T04:30:00Z'.")
+/// Wait for a request to terminate the process
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("should be able to listen for ctrl-c");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("should be able to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
 }
 
-/// Initialize an axum::Router for our web service
-/// Having this as a separate function makes testing easier.
-fn app(oprf_state: OPRFState) -> Router {
-    Router::new()
-        // Friendly default route to identify the site
-        .route("/", get(|| async { "STAR randomness server\n" }))
-        // Main endpoints
-        .route("/randomness", post(handler::randomness))
-        .route("/info", get(handler::info))
-        // Attach shared state
-        .with_state(oprf_state)
-        // Logging must come after active routes
-        .layer(tower_http::trace::TraceLayer::new_for_http())
+/// Toggle verbose logging each time SIGUSR2 is received
+#[cfg(unix)]
+async fn toggle_logging_on_signal<S>(mut toggle: LogToggle<S>, config: Config) {
+    let mut signal = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined2())
+        .expect("should be able to listen for SIGUSR2");
+    while signal.recv().await.is_some() {
+        match toggle.toggle(&config) {
+            Ok(true) => info!("SIGUSR2 received, logging verbosely"),
+            Ok(false) => info!("SIGUSR2 received, restored configured log filter"),
+            Err(e) => error!("Couldn't change log filter: {e}"),
+        }
+    }
 }
 
-fn start_prometheus_server(metrics_handle: PrometheusHandle, listen: String) {
-    tokio::spawn(async move {
-        let addr = listen.parse().unwrap();
-        let metrics_app =
-            Router::new().route("/metrics", get(|| async move { metrics_handle.render() }));
-        info!("Metrics server listening on {}", &listen);
-        axum::Server::bind(&addr)
-            .serve(metrics_app.into_make_service())
-            .await
-            .unwrap();
-    });
+/// Raise the open file limit so the server can handle more
+/// concurrent connections
+/// Some container platforms don't allow this, so failure isn't
+/// fatal; the server continues with whatever limit it has.
+fn increase_nofile_limit() {
+    #[cfg(unix)]
+    if let Err(e) = rlimit::setrlimit(Resource::NOFILE, NOFILE_LIMIT, NOFILE_LIMIT) {
+        // Raising the hard limit needs privileges, but we may
+        // still be able to raise the soft limit up to it.
+        warn!("Couldn't set nofile limit to {NOFILE_LIMIT}: {e}");
+        if let Err(e) = rlimit::increase_nofile_limit(NOFILE_LIMIT) {
+            warn!("Couldn't raise nofile limit, keeping existing limit: {e}");
+        }
+    }
+    #[cfg(not(unix))]
+    warn!("Raising the nofile limit isn't supported on this platform");
 }
 
-fn increase_nofile_limit() {
-    let curr_limits =
-        rlimit::getrlimit(Resource::NOFILE).expect("should be able to get current nofile limit");
-    info!("Current nofile limits = {:?}", curr_limits);
-
-    rlimit::setrlimit(Resource::NOFILE, 65535, 65535).expect("should be able to set nofile limit");
-    let curr_limits = rlimit::getrlimit(Resource::NOFILE)
-        .expect("should be able to get current nofile limit after updating it");
-    info!(
-        "Attempted nofile limit change! Current nofile limits = {:?}",
-        curr_limits
-    );
+/// Report the open file limit in effect
+fn log_nofile_limit() {
+    #[cfg(unix)]
+    match rlimit::getrlimit(Resource::NOFILE) {
+        Ok((soft, hard)) => info!("nofile limits: soft = {soft}, hard = {hard}"),
+        Err(e) => warn!("Couldn't read nofile limit: {e}"),
+    }
 }
 
-#[tokio::main]
-async fn main() {
+fn main() {
+    // Command line switches
+    // These are loaded first since they can configure logging.
+    let config = Config::load();
+
     // Start logging
-    // The default subscriber respects filter directives like `RUST_LOG=info`
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            EnvFilter::builder()
-                .with_default_directive(LevelFilter::INFO.into())
-                .from_env()
-                .unwrap(),
-        )
-        .init();
+    // Filter directives like `RUST_LOG=info` can be given in the
+    // environment or with `--log-filter`. The filter is reloadable
+    // so SIGUSR2 can switch to verbose logging.
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(config.log_filter())
+        .with_filter_reloading();
+    let log_toggle = LogToggle::new(subscriber.reload_handle());
+    subscriber.init();
+    state::install_panic_hook();
     info!("STARing up!");
+    // Secrets are redacted by the Debug impl.
+    info!(?config, "effective config");
+
+    if config.self_test {
+        match eval::self_test(&config) {
+            Ok(()) => info!("self-test passed"),
+            Err(e) => {
+                error!("self-test failed: {e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
 
-    // Command line switches
-    let config = Config::parse();
-    debug!(?config, "config parsed");
-    let addr = config.listen.parse().unwrap();
+    // The runtime is built by hand so its size can be configured.
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(threads) = config.worker_threads {
+        info!("using {threads} worker threads");
+        runtime.worker_threads(threads as usize);
+    }
+    runtime
+        .build()
+        .expect("Could not start tokio runtime")
+        .block_on(async {
+            #[cfg(unix)]
+            tokio::spawn(toggle_logging_on_signal(log_toggle, config.clone()));
+            #[cfg(not(unix))]
+            drop(log_toggle);
+            serve(config).await
+        });
+}
+
+/// Run the service until shutdown is requested
+async fn serve(config: Config) {
+    let addr = config.listen;
 
     if config.increase_nofile_limit {
         increase_nofile_limit();
     }
+    log_nofile_limit();
 
     // Oblivious function state
     info!("initializing OPRF state...");
     let server = state::OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
-    info!("epoch now {}", server.epoch);
+    info!("epoch now {}", server.current_epoch());
     let oprf_state = Arc::new(RwLock::new(server));
 
-    let metric_layer = config.prometheus_listen.as_ref().map(|listen| {
-        let (layer, handle) = PrometheusMetricLayer::pair();
-        start_prometheus_server(handle, listen.clone());
+    let statsd = config.statsd_addr.map(|addr| {
+        let recorder = StatsdRecorder::new(addr).unwrap_or_else(|e| {
+            error!("Couldn't send metrics to statsd at {addr}: {e}");
+            std::process::exit(1);
+        });
+        info!("Sending metrics to statsd at {addr}");
+        tokio::spawn(recorder.clone().flush_periodically());
+        recorder
+    });
+    let prometheus = config.prometheus_listen.is_some();
+    let handle = telemetry::install_recorder(prometheus, statsd);
+    let metric_layer = config.prometheus_listen.map(|listen| {
+        let handle = handle.expect("prometheus recorder is installed");
+        let (layer, handle) = PrometheusMetricLayerBuilder::new()
+            .with_metrics_from_fn(|| handle)
+            .build_pair();
+        let metrics_app =
+            star_randsrv::metrics_app(handle, config.admin_token.as_ref().map(Secret::expose));
+        if let Err(e) = star_randsrv::serve_metrics(metrics_app, listen, &config) {
+            error!("Couldn't serve metrics on {listen}: {e}");
+            std::process::exit(1);
+        }
         layer
     });
 
-    // Spawn a background process to advance the epoch
-    info!("Spawning background epoch rotation task...");
-    let background_state = oprf_state.clone();
-    tokio::spawn(async move { state::epoch_loop(background_state, &config).await });
-
     // Set up routes and middleware
     info!("initializing routes...");
-    let mut app = app(oprf_state);
+    let mut app = star_randsrv::app(oprf_state.clone(), &config);
     if let Some(metric_layer) = metric_layer {
         app = app.layer(metric_layer);
     }
 
+    // Signal background tasks when it's time to exit
+    let (shutdown_tx, mut server_shutdown) = tokio::sync::watch::channel(false);
+    let signal_tx = shutdown_tx.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!("Shutdown requested");
+        let _ = signal_tx.send(true);
+    });
+
+    // Open the socket before the config is handed off.
+    let incoming = star_randsrv::bind(&config).unwrap_or_else(|e| {
+        error!("Couldn't listen on {addr}: {e}");
+        std::process::exit(1);
+    });
+    if config.http2 {
+        info!("accepting HTTP/2 with prior knowledge");
+    }
+    let server = star_randsrv::server(incoming, &config);
+
+    // Spawn a background process to advance the epoch
+    // Replicas derive the epoch from the clock instead.
+    let rotation = if config.no_rotate {
+        info!("Not rotating epochs; deriving them from the epoch base time");
+        None
+    } else {
+        info!("Spawning background epoch rotation task...");
+        Some(tokio::spawn(state::supervise_epoch_loop(
+            oprf_state,
+            config,
+            shutdown_tx,
+        )))
+    };
+
     // Start the server
     info!("Listening on {}", &addr);
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+    server
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(async move {
+            let _ = server_shutdown.changed().await;
+        })
         .await
         .unwrap();
+
+    // Let the epoch loop finish saving its state.
+    if let Some(rotation) = rotation {
+        if let Err(e) = rotation.await.expect("epoch rotation supervisor failed") {
+            error!("Exiting: {e}");
+            std::process::exit(1);
+        }
+    }
+    info!("Goodbye");
 }
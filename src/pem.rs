@@ -0,0 +1,58 @@
+//! STAR Randomness web service
+//! PEM armor for the server public key
+//!
+//! The key is the same bincode `ServerPublicKey` reported by
+//! `/info`, wrapped as described by RFC 7468 for tools which only
+//! ingest PEM. The base64 inside is always standard and padded,
+//! whatever `--base64-variant` says.
+
+use base64::prelude::{Engine as _, BASE64_STANDARD as BASE64};
+
+/// Label of a PEM-armored `ServerPublicKey`
+pub const LABEL: &str = "PPOPRF PUBLIC KEY";
+
+/// Characters of base64 per line
+const LINE_LEN: usize = 64;
+
+/// Failure to read a PEM-armored public key
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Missing `-----BEGIN {LABEL}-----` line")]
+    MissingBegin,
+    #[error("Missing `-----END {LABEL}-----` line")]
+    MissingEnd,
+    #[error("Invalid base64 encoding: {0}")]
+    Base64(#[from] base64::DecodeError),
+}
+
+/// Wrap bincode `ServerPublicKey` bytes in PEM armor
+pub fn encode(key: &[u8]) -> String {
+    let encoded = BASE64.encode(key);
+    let mut pem = format!("-----BEGIN {LABEL}-----\n");
+    for line in encoded.as_bytes().chunks(LINE_LEN) {
+        pem.push_str(std::str::from_utf8(line).expect("base64 should be ascii"));
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {LABEL}-----\n"));
+    pem
+}
+
+/// Read the bincode `ServerPublicKey` bytes out of PEM armor
+/// Anything before the first line or after the last is ignored.
+pub fn decode(pem: &str) -> Result<Vec<u8>, Error> {
+    let begin = format!("-----BEGIN {LABEL}-----");
+    let end = format!("-----END {LABEL}-----");
+    let mut lines = pem.lines().map(str::trim);
+    lines
+        .by_ref()
+        .find(|line| *line == begin)
+        .ok_or(Error::MissingBegin)?;
+    let mut encoded = String::new();
+    for line in lines {
+        if line == end {
+            return Ok(BASE64.decode(encoded)?);
+        }
+        encoded.push_str(line);
+    }
+    Err(Error::MissingEnd)
+}
@@ -0,0 +1,112 @@
+//! STAR Randomness web service
+//! Bounded queue of evaluations for a pool of worker threads
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+use crate::handler::Error;
+use crate::telemetry;
+
+/// Work submitted to the queue
+/// Jobs report their own results, so the queue only has to
+/// know when they were submitted.
+struct Job {
+    /// When the job must have started by
+    deadline: Instant,
+    /// Runs the work, or reports that it was too late
+    run: Box<dyn FnOnce(bool) + Send>,
+}
+
+/// Queue of evaluations serviced by dedicated threads
+///
+/// Requests wait for a free worker instead of all evaluating at
+/// once, which smooths out bursts at the cost of latency. Work is
+/// rejected when the queue is full, or if it waits longer than the
+/// deadline before a worker picks it up. Dropping the queue stops
+/// the workers once they finish the work already queued.
+pub struct EvalQueue {
+    jobs: SyncSender<Job>,
+    /// How long work may wait in the queue
+    deadline: Duration,
+    /// Jobs queued but not yet started
+    depth: Arc<AtomicUsize>,
+}
+
+impl EvalQueue {
+    /// Start `workers` threads servicing a queue of `size` jobs
+    pub fn new(size: usize, workers: usize, deadline: Duration) -> Self {
+        let (jobs, receiver) = mpsc::sync_channel(size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let depth = Arc::new(AtomicUsize::new(0));
+        for n in 0..workers {
+            let receiver = receiver.clone();
+            let depth = depth.clone();
+            thread::Builder::new()
+                .name(format!("eval-{n}"))
+                .spawn(move || work(&receiver, &depth))
+                .expect("Could not start evaluation thread");
+        }
+        metrics::gauge!(telemetry::EVAL_QUEUE_DEPTH, 0.0);
+        EvalQueue {
+            jobs,
+            deadline,
+            depth,
+        }
+    }
+
+    /// Run `f` on a worker thread and wait for the result
+    pub async fn run<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T, Error> + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let job = Job {
+            deadline: Instant::now() + self.deadline,
+            run: Box::new(move |expired| {
+                let result = if expired {
+                    Err(Error::QueueTimeout)
+                } else {
+                    f()
+                };
+                // The request may have been abandoned.
+                let _ = tx.send(result);
+            }),
+        };
+        // Count the job before a worker can take it.
+        let depth = self.depth.fetch_add(1, Ordering::Relaxed) + 1;
+        match self.jobs.try_send(job) {
+            Ok(()) => metrics::gauge!(telemetry::EVAL_QUEUE_DEPTH, depth as f64),
+            Err(e) => {
+                self.depth.fetch_sub(1, Ordering::Relaxed);
+                return Err(match e {
+                    TrySendError::Full(_) => Error::QueueFull,
+                    // Only if every worker has panicked.
+                    TrySendError::Disconnected(_) => Error::LockFailure,
+                });
+            }
+        }
+        rx.await.map_err(|_| Error::LockFailure)?
+    }
+}
+
+/// Service jobs until the queue is dropped
+fn work(receiver: &Mutex<Receiver<Job>>, depth: &AtomicUsize) {
+    loop {
+        // Only hold the lock while waiting, not while working.
+        let job = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => return,
+        };
+        let Ok(job) = job else {
+            return;
+        };
+        let depth = depth.fetch_sub(1, Ordering::Relaxed) - 1;
+        metrics::gauge!(telemetry::EVAL_QUEUE_DEPTH, depth as f64);
+        (job.run)(Instant::now() > job.deadline);
+    }
+}
@@ -0,0 +1,157 @@
+//! STAR Randomness web service
+//! Per-client request rate limiting
+
+use axum::body::Body;
+use axum::extract::{ConnectInfo, State};
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+use crate::handler::Error;
+
+/// Maximum number of clients to track at once
+/// When the table is full, idle entries are evicted first,
+/// then the least-recently-seen clients.
+const MAX_TRACKED_CLIENTS: usize = 65536;
+
+/// Number of entries freed whenever the table fills
+/// Finding entries to evict means scanning the whole table, so
+/// this is done once per batch of new clients rather than for
+/// each one.
+const EVICT_BATCH: usize = MAX_TRACKED_CLIENTS / 16;
+
+/// Token bucket for a single client
+struct Bucket {
+    /// Requests which may be made without waiting
+    tokens: f64,
+    /// Last time `tokens` was refilled
+    updated: Instant,
+}
+
+/// Token-bucket rate limiter keyed by client address
+pub struct RateLimiter {
+    /// Tokens added to each bucket per second
+    rate: f64,
+    /// Maximum tokens a bucket can hold
+    burst: f64,
+    /// Whether to key on `X-Forwarded-For` instead of the peer address
+    trust_proxy: bool,
+    /// Per-client state
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    /// Create a limiter allowing `per_second` requests from each client
+    /// Bursts of up to one second's worth of requests are permitted.
+    pub fn new(per_second: u32, trust_proxy: bool) -> Self {
+        let rate = f64::from(per_second);
+        RateLimiter {
+            rate,
+            burst: rate,
+            trust_proxy,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Account for a request from `client` at time `now`
+    /// Returns the time to wait before retrying if the client is over
+    /// its limit.
+    pub fn check(&self, client: IpAddr, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().expect("rate limiter lock poisoned");
+        if !buckets.contains_key(&client) && buckets.len() >= MAX_TRACKED_CLIENTS {
+            self.evict(&mut buckets, now);
+        }
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    /// Make room in a full table, freeing at least `EVICT_BATCH`
+    fn evict(&self, buckets: &mut HashMap<IpAddr, Bucket>, now: Instant) {
+        // Buckets which would have refilled completely carry no
+        // information, so they can go first.
+        buckets.retain(|_, bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated);
+            bucket.tokens + elapsed.as_secs_f64() * self.rate < self.burst
+        });
+        let excess = buckets
+            .len()
+            .saturating_sub(MAX_TRACKED_CLIENTS - EVICT_BATCH);
+        if excess > 0 {
+            let mut seen: Vec<(Instant, IpAddr)> = buckets
+                .iter()
+                .map(|(client, bucket)| (bucket.updated, *client))
+                .collect();
+            seen.select_nth_unstable(excess - 1);
+            for (_, client) in &seen[..excess] {
+                buckets.remove(client);
+            }
+        }
+    }
+
+    /// Determine which client a request should be charged to
+    fn client(&self, request: &Request<Body>) -> Option<IpAddr> {
+        client_addr(request, self.trust_proxy)
+    }
+}
+
+/// Address of the client which sent a request
+/// With `trust_proxy`, this comes from the `X-Forwarded-For` header
+/// if present, rather than the connection.
+pub(crate) fn client_addr(request: &Request<Body>, trust_proxy: bool) -> Option<IpAddr> {
+    if trust_proxy {
+        // Use the rightmost address, which was appended by our
+        // proxy. Anything to the left was supplied by the client
+        // and can't be trusted.
+        let forwarded = request
+            .headers()
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .last()
+            .and_then(|addr| addr.trim().parse().ok());
+        if forwarded.is_some() {
+            return forwarded;
+        }
+    }
+    request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip())
+}
+
+/// Middleware enforcing the rate limit on a route
+pub async fn limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Result<Response, Error> {
+    match limiter.client(&request) {
+        Some(client) => {
+            if let Err(wait) = limiter.check(client, Instant::now()) {
+                debug!("rate limiting {client}");
+                // Round up so clients don't retry too early.
+                let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+                return Err(Error::RateLimited(secs.max(1)));
+            }
+        }
+        None => debug!("no client address, skipping rate limit"),
+    }
+    Ok(next.run(request).await)
+}
@@ -0,0 +1,118 @@
+//! STAR Randomness web service
+//! Ed25519 request signatures for client authorization
+
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use base64::prelude::{Engine as _, BASE64_STANDARD as BASE64};
+use ed25519_dalek::{Signature, VerifyingKey};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::debug;
+
+use crate::config;
+use crate::handler::Error;
+
+/// Header carrying the signer's base64-encoded public key
+pub const CLIENT_KEY: &str = "x-client-key";
+
+/// Header carrying the base64-encoded signature over the body
+pub const SIGNATURE: &str = "x-signature";
+
+/// Largest request body which will be buffered by middleware
+/// This matches axum's default limit for json bodies.
+pub(crate) const MAX_BODY: usize = 2 * 1024 * 1024;
+
+/// Public keys of the clients allowed to make requests
+#[derive(Debug, Default)]
+pub struct ClientKeys {
+    keys: HashMap<[u8; 32], VerifyingKey>,
+}
+
+impl ClientKeys {
+    /// Read an allowlist of base64-encoded Ed25519 public keys
+    /// The file has one key per line. Blank lines and lines
+    /// starting with `#` are ignored.
+    pub fn load(path: &Path) -> Result<Self, config::Error> {
+        let contents =
+            std::fs::read_to_string(path).map_err(|e| config::Error::Io(path.to_owned(), e))?;
+        let mut keys = HashMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let key = decode_key(line)
+                .ok_or_else(|| config::Error::ClientKey(path.to_owned(), number + 1))?;
+            keys.insert(key.to_bytes(), key);
+        }
+        Ok(ClientKeys { keys })
+    }
+
+    /// Number of allowed clients
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Whether no clients are allowed
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Check a signature over `body` from the given client
+    /// Both the key and signature are base64-encoded. Unknown
+    /// keys fail verification.
+    pub fn verify(&self, client_key: &str, signature: &str, body: &[u8]) -> bool {
+        let Some(key) = decode_key(client_key).and_then(|key| self.keys.get(key.as_bytes())) else {
+            return false;
+        };
+        let Some(signature) = BASE64
+            .decode(signature)
+            .ok()
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+        else {
+            return false;
+        };
+        key.verify_strict(body, &signature).is_ok()
+    }
+}
+
+/// Parse a base64-encoded Ed25519 public key
+fn decode_key(encoded: &str) -> Option<VerifyingKey> {
+    let bytes: [u8; 32] = BASE64.decode(encoded).ok()?.try_into().ok()?;
+    VerifyingKey::from_bytes(&bytes).ok()
+}
+
+/// Middleware rejecting requests not signed by an allowed client
+/// The body is buffered so the signature can be checked before
+/// the request is passed on.
+pub async fn require_signature(
+    State(keys): State<Arc<ClientKeys>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Result<Response, Error> {
+    let (parts, body) = request.into_parts();
+    let body = match hyper::body::to_bytes(http_body::Limited::new(body, MAX_BODY)).await {
+        Ok(body) => body,
+        Err(e) if e.is::<http_body::LengthLimitError>() => return Err(Error::BodyTooLarge),
+        Err(_) => return Err(Error::Unauthorized("Signature")),
+    };
+    let header = |name| {
+        parts
+            .headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    let verified = match (header(CLIENT_KEY), header(SIGNATURE)) {
+        (Some(client_key), Some(signature)) => keys.verify(client_key, signature, &body),
+        _ => false,
+    };
+    if !verified {
+        debug!("rejecting request without a valid client signature");
+        return Err(Error::Unauthorized("Signature"));
+    }
+    Ok(next.run(Request::from_parts(parts, Body::from(body))).await)
+}
@@ -1,11 +1,22 @@
 //! STAR Randomness web service
 //! Epoch and key state and its management
 
-use std::sync::{Arc, RwLock};
+use base64::Engine as _;
+use curve25519_dalek::constants::RISTRETTO_BASEPOINT_COMPRESSED;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock, TryLockError};
+use std::time::Duration;
 use time::format_description::well_known::Rfc3339;
-use tracing::{info, instrument};
+use time::OffsetDateTime;
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tracing::{debug, error, info, instrument, warn};
 
-use crate::Config;
+use crate::telemetry;
+use crate::{Config, EpochExhaustionPolicy, RotationFailurePolicy};
 use ppoprf::ppoprf;
 
 /// Internal state of the OPRF service
@@ -13,9 +24,155 @@ pub struct OPRFServer {
     /// oprf implementation
     pub server: ppoprf::Server,
     /// currently-valid randomness epoch
+    ///
+    /// This is a ppoprf metadata tag, which the library fixes as a
+    /// `u8`. It can't be widened here without a matching change
+    /// upstream.
     pub epoch: u8,
-    /// RFC 3339 timestamp of the next epoch rotation
-    pub next_epoch_time: Option<String>,
+    /// Absolute number of the current epoch
+    /// This counts epochs since the base time, so unlike `epoch`
+    /// it keeps increasing across key rotations.
+    pub epoch_number: u64,
+    /// Start and end of the current epoch, as set by `epoch_loop`
+    pub epoch_window: Option<(OffsetDateTime, OffsetDateTime)>,
+    /// Epochs available under each key, in the order they're used
+    /// The key must be rotated after the last one.
+    pub epochs: Vec<u8>,
+    /// Duration of each epoch
+    pub epoch_seconds: u32,
+    /// Previous epoch, still accepted until its grace period ends
+    pub grace_epoch: Option<u8>,
+    /// Furthest behind the current epoch a request may be
+    pub max_epoch_lag: Option<u8>,
+    /// Clock-derived schedule for replicas which don't rotate
+    ///
+    /// If set, the current epoch is computed from the clock on each
+    /// request rather than advanced by `epoch_loop`.
+    pub schedule: Option<Schedule>,
+    /// Base64-encoded bincode ServerPublicKey
+    /// This only changes with the key, so it's encoded once here
+    /// rather than on every `/info` request.
+    pub public_key: String,
+    /// Bincode ServerPublicKey which `public_key` encodes
+    /// Kept for the PEM form of `/info`, so both forms come from the
+    /// same serialization.
+    pub public_key_bincode: Vec<u8>,
+    /// Encoded public key in use before the last key rotation
+    /// Only kept if `--retain-previous-key` is set.
+    pub previous_public_key: Option<String>,
+    /// Epochs punctured under the current key
+    /// ppoprf doesn't expose this, so it's tracked alongside.
+    pub punctured: BTreeSet<u8>,
+    /// Most recently punctured epoch, kept across key rotations
+    /// This lets requests lagging behind a rotation be told apart
+    /// from ones for epochs which were never valid, even once the
+    /// key has changed.
+    pub last_punctured: Option<u8>,
+    /// When `epoch_loop` last advanced the epoch or rotated the key
+    pub last_rotation: Option<OffsetDateTime>,
+    /// How long the warmup evaluation under this key took
+    /// `None` if it failed, which only costs the first request
+    /// its head start.
+    pub warmup_time: Option<Duration>,
+    /// Set while `epoch_loop` advances the epoch or rotates the key
+    /// This is shared outside the lock, and kept across key
+    /// rotations, so it can be read without waiting for them.
+    pub rotating: Rotating,
+    /// Key to swap in at the next rotation, if one is ready
+    pub standby: Option<OPRFKey>,
+    /// Set once the epochs have run out under
+    /// `--on-epoch-exhaustion exit` or `halt`
+    /// No epoch is accepted after this, so nothing more can be
+    /// evaluated.
+    pub halted: bool,
+}
+
+/// PPOPRF key with its encoded public key
+/// Generating a key takes a while, so `epoch_loop` prepares one
+/// as a standby before a rotation is due. Rotating then only has
+/// to swap it in while holding the lock.
+pub struct OPRFKey {
+    /// Epochs the key was generated for
+    epochs: Vec<u8>,
+    /// oprf implementation holding the private key
+    server: ppoprf::Server,
+    /// Base64-encoded bincode ServerPublicKey
+    pub public_key: String,
+    /// Bincode ServerPublicKey which `public_key` encodes
+    public_key_bincode: Vec<u8>,
+}
+
+impl OPRFKey {
+    /// Generate a key for the epochs of `config`
+    pub fn generate(config: &Config) -> Result<Self, ppoprf::PPRFError> {
+        let epochs = config.epoch_list();
+        let server = ppoprf::Server::new(epochs.clone())?;
+        let public_key_bincode =
+            server.get_public_key().serialize_to_bincode()?;
+        let public_key =
+            config.base64_variant.engine().encode(&public_key_bincode);
+        Ok(OPRFKey {
+            epochs,
+            server,
+            public_key,
+            public_key_bincode,
+        })
+    }
+}
+
+/// Flag set while the epoch is being advanced
+/// Clones share the same flag.
+#[derive(Clone, Debug, Default)]
+pub struct Rotating(Arc<AtomicBool>);
+
+impl Rotating {
+    /// Whether a rotation is in progress
+    pub fn get(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+
+    /// Set the flag until the returned guard is dropped
+    /// Clearing it on drop means a panic can't leave it set.
+    pub fn begin(&self) -> RotatingGuard<'_> {
+        self.0.store(true, Ordering::Release);
+        RotatingGuard(self)
+    }
+}
+
+/// Clears the `Rotating` flag when dropped
+pub struct RotatingGuard<'a>(&'a Rotating);
+
+impl Drop for RotatingGuard<'_> {
+    fn drop(&mut self) {
+        self.0 .0.store(false, Ordering::Release);
+    }
+}
+
+/// Whether an epoch can be evaluated, and why
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EpochStatus {
+    /// The currently-valid epoch
+    Current,
+    /// The previous epoch, still accepted during its grace period
+    Grace,
+    /// A later epoch which isn't valid yet
+    Future,
+    /// An earlier epoch which can never be evaluated again
+    Punctured,
+    /// An earlier epoch which isn't accepted, but wasn't punctured
+    /// This is the case for replicas, which never puncture, or a
+    /// grace epoch beyond `--max-epoch-lag`.
+    Expired,
+    /// Not one of the configured epochs
+    OutOfRange,
+}
+
+impl EpochStatus {
+    /// Whether evaluations are allowed in an epoch with this status
+    pub fn is_valid(self) -> bool {
+        matches!(self, EpochStatus::Current | EpochStatus::Grace)
+    }
 }
 
 /// Shareable wrapper around the server state
@@ -24,34 +181,662 @@ pub type OPRFState = Arc<RwLock<OPRFServer>>;
 impl OPRFServer {
     /// Initialize a new OPRFServer state with the given configuration
     pub fn new(config: &Config) -> Result<Self, ppoprf::PPRFError> {
-        // ppoprf wants a vector, so generate one from our range.
-        let epochs: Vec<u8> =
-            (config.first_epoch..=config.last_epoch).collect();
+        Ok(Self::with_key(config, OPRFKey::generate(config)?))
+    }
+
+    /// Initialize a new OPRFServer state using an existing key
+    fn with_key(config: &Config, key: OPRFKey) -> Self {
+        let OPRFKey {
+            epochs,
+            server,
+            public_key,
+            public_key_bincode,
+        } = key;
         let epoch = epochs[0];
-        let server = ppoprf::Server::new(epochs)?;
-        Ok(OPRFServer {
+        let warmup_time = warm_up(&server, epoch);
+        let schedule = match (config.no_rotate, config.epoch_base_time) {
+            (true, Some(base_time)) => Some(
+                Schedule::new(config, base_time)
+                    .one_pass(OffsetDateTime::now_utc()),
+            ),
+            _ => None,
+        };
+        OPRFServer {
             server,
             epoch,
-            next_epoch_time: None,
+            epoch_number: 0,
+            epoch_window: None,
+            epochs,
+            epoch_seconds: config.epoch_seconds,
+            grace_epoch: None,
+            max_epoch_lag: config.max_epoch_lag,
+            schedule,
+            public_key,
+            public_key_bincode,
+            previous_public_key: None,
+            punctured: BTreeSet::new(),
+            last_punctured: None,
+            last_rotation: None,
+            warmup_time,
+            rotating: Rotating::default(),
+            standby: None,
+            halted: false,
+        }
+    }
+
+    /// Currently-valid randomness epoch
+    /// Before a replica's schedule has started this is the first
+    /// epoch, though `accepts` refuses it until then.
+    pub fn current_epoch(&self) -> u8 {
+        match &self.schedule {
+            Some(schedule) => schedule
+                .at(OffsetDateTime::now_utc())
+                .map_or(self.epoch, |(epoch, _)| epoch),
+            None => self.epoch,
+        }
+    }
+
+    /// Whether no more evaluations are possible under this key
+    /// This is the case once `halt` is called, or once a replica has
+    /// made its one pass through the epochs.
+    pub fn exhausted(&self) -> bool {
+        self.halted
+            || self.schedule.as_ref().is_some_and(|schedule| {
+                schedule.ended(OffsetDateTime::now_utc())
+            })
+    }
+
+    /// Whether the epoch schedule has started
+    /// This is only ever false for replicas, which follow the clock
+    /// and so can't place a time before the base time, for example
+    /// after the wall clock steps backwards.
+    pub fn started(&self) -> bool {
+        self.schedule.as_ref().is_none_or(|schedule| {
+            schedule.at(OffsetDateTime::now_utc()).is_some()
+        })
+    }
+
+    /// Position of an epoch in the configured sequence
+    pub fn position(&self, epoch: u8) -> Option<usize> {
+        self.epochs.iter().position(|&e| e == epoch)
+    }
+
+    /// How many epochs `epoch` comes before `current` in the sequence
+    /// Tags needn't be contiguous, so this counts positions rather
+    /// than comparing tags. Returns `None` for later or unknown
+    /// epochs.
+    fn lag(&self, current: u8, epoch: u8) -> Option<usize> {
+        self.position(current)?.checked_sub(self.position(epoch)?)
+    }
+
+    /// Absolute number of an accepted epoch
+    /// Only the current epoch and the grace epoch before it are
+    /// accepted, so the epoch's distance behind the current one maps
+    /// it to a number.
+    pub fn epoch_number(&self, epoch: u8) -> u64 {
+        let (current, number) = match &self.schedule {
+            Some(schedule) => {
+                let now = OffsetDateTime::now_utc();
+                match (schedule.at(now), schedule.elapsed_epochs(now)) {
+                    (Some((epoch, _)), Some(number)) => (epoch, number),
+                    _ => (self.epoch, 0),
+                }
+            }
+            None => (self.epoch, self.epoch_number),
+        };
+        let lag = self.lag(current, epoch).unwrap_or_default();
+        number.saturating_sub(lag as u64)
+    }
+
+    /// Start and end of the current epoch
+    /// Anything publishing the epoch schedule should derive it from
+    /// this, so endpoints can't disagree about the boundaries. It's
+    /// `None` until `epoch_loop` first sets it, or while a replica's
+    /// schedule hasn't started.
+    pub fn current_window(&self) -> Option<(OffsetDateTime, OffsetDateTime)> {
+        match &self.schedule {
+            Some(schedule) => schedule.window(OffsetDateTime::now_utc()),
+            None => self.epoch_window,
+        }
+    }
+
+    /// RFC 3339 timestamp of the next epoch rotation
+    pub fn next_epoch_time(&self) -> Option<String> {
+        self.current_window().map(|(_, end)| format_rotation(end))
+    }
+
+    /// Time left in the current epoch
+    /// This is measured to the end of `current_window`, the same
+    /// boundary published as the next epoch time, and is zero once
+    /// that has passed but `epoch_loop` hasn't yet advanced.
+    pub fn time_remaining(&self) -> Option<Duration> {
+        self.current_window().map(|(_, end)| {
+            (end - OffsetDateTime::now_utc())
+                .try_into()
+                .unwrap_or_default()
         })
     }
+
+    /// Number of epochs which follow the current one under this key
+    pub fn epochs_remaining(&self) -> u8 {
+        let now = OffsetDateTime::now_utc();
+        if let Some(remaining) =
+            self.schedule.as_ref().and_then(|s| s.remaining(now))
+        {
+            return remaining;
+        }
+        let position = self.position(self.current_epoch()).unwrap_or_default();
+        // There are at most 256 epochs, so this fits.
+        (self.epochs.len() - 1 - position) as u8
+    }
+
+    /// Whether evaluations are allowed in the given epoch
+    /// This is the current epoch, or the previous one during
+    /// its grace period if that's within `--max-epoch-lag`.
+    pub fn accepts(&self, epoch: u8) -> bool {
+        if self.exhausted() || !self.started() {
+            return false;
+        }
+        let current = self.current_epoch();
+        if epoch == current {
+            return true;
+        }
+        // Earlier epochs are punctured, so only the grace epoch
+        // can lag behind.
+        let within_lag = self.max_epoch_lag.is_none_or(|lag| {
+            self.lag(current, epoch).is_some_and(|n| n <= lag.into())
+        });
+        self.grace_epoch == Some(epoch) && within_lag
+    }
+
+    /// Epochs in which evaluations are currently allowed
+    /// This is the current epoch, followed by the grace epoch if
+    /// it's still accepted.
+    pub fn evaluable_epochs(&self) -> Vec<u8> {
+        let mut epochs = vec![self.current_epoch()];
+        epochs.extend(self.grace_epoch.filter(|&epoch| self.accepts(epoch)));
+        epochs
+    }
+
+    /// Whether the epoch was punctured, under this key or just before
+    pub fn was_punctured(&self, epoch: u8) -> bool {
+        self.punctured.contains(&epoch) || self.last_punctured == Some(epoch)
+    }
+
+    /// Describe whether the given epoch can be evaluated
+    pub fn epoch_status(&self, epoch: u8) -> EpochStatus {
+        let Some(position) = self.position(epoch) else {
+            return EpochStatus::OutOfRange;
+        };
+        let current = self.current_epoch();
+        if epoch == current {
+            EpochStatus::Current
+        } else if Some(position) > self.position(current) {
+            EpochStatus::Future
+        } else if self.punctured.contains(&epoch) {
+            EpochStatus::Punctured
+        } else if self.accepts(epoch) {
+            EpochStatus::Grace
+        } else {
+            EpochStatus::Expired
+        }
+    }
+
+    /// Move on to the next epoch
+    /// The current epoch is punctured so it can no longer be
+    /// used, unless `--epoch-grace-seconds` is set, in which case
+    /// it remains usable until `end_grace` is called. If no epochs
+    /// remain, or puncturing fails, the key is rotated instead.
+    pub fn advance(&mut self, config: &Config) {
+        // Only one epoch can be in its grace period at a time.
+        // If that fails, the fresh key starts over anyway.
+        if !self.end_grace(config) {
+            info!("epoch now {}", self.epoch);
+            return;
+        }
+
+        // Advance to the next epoch in the sequence, if any.
+        let old_epoch = self.epoch;
+        let new_epoch = self
+            .position(old_epoch)
+            .and_then(|position| self.epochs.get(position + 1))
+            .copied();
+        if let Some(new_epoch) = new_epoch {
+            if config.epoch_grace_seconds > 0 {
+                self.grace_epoch = Some(old_epoch);
+                // Server is already initialized for this one.
+                self.epoch = new_epoch;
+            } else if self.puncture(old_epoch, config) {
+                self.epoch = new_epoch;
+            }
+        } else {
+            info!("Epochs exhausted! Rotating OPRF key");
+            // The old key is discarded, so there's no grace period.
+            if self.puncture(old_epoch, config) {
+                self.rotate_key(config);
+            }
+        }
+        info!("epoch now {}", self.epoch);
+    }
+
+    /// Stop evaluating once the epochs have run out
+    /// This replaces the key rotation `advance` would otherwise do.
+    /// The grace and current epochs are punctured, so the key can't
+    /// evaluate anything more.
+    pub fn halt(&mut self, config: &Config) {
+        info!("Epochs exhausted! No longer evaluating");
+        self.end_grace(config);
+        let epoch = self.epoch;
+        self.puncture(epoch, config);
+        // Set last, since a failed puncture replaces the state.
+        self.halted = true;
+    }
+
+    /// Replace the OPRF key, starting over at the first epoch
+    /// The old public key is kept if `--retain-previous-key` is set,
+    /// so clients can tell which key earlier evaluations used.
+    pub fn rotate_key(&mut self, config: &Config) {
+        let previous = std::mem::take(&mut self.public_key);
+        let epoch_number = self.epoch_number;
+        let last_punctured = self.last_punctured;
+        let rotating = self.rotating.clone();
+        // A standby generated for different epochs can't be used,
+        // so it's dropped along with the old key.
+        let standby = self.standby.take().filter(|key| {
+            let matches = key.epochs == config.epoch_list();
+            if !matches {
+                warn!("Discarding standby key generated for other epochs");
+            }
+            matches
+        });
+        *self = match standby {
+            Some(key) => {
+                info!("Swapping in standby OPRF key");
+                OPRFServer::with_key(config, key)
+            }
+            // Panics if this fails. Puncture should mean we can't
+            // violate privacy through further evaluations, but we
+            // still want to drop the inner state with its private key.
+            None => OPRFServer::new(config)
+                .expect("Could not initialize new PPOPRF state"),
+        };
+        // Epoch numbers carry on under the new key.
+        self.epoch_number = epoch_number;
+        self.last_punctured = last_punctured;
+        self.rotating = rotating;
+        if config.retain_previous_key {
+            self.previous_public_key = Some(previous);
+        }
+    }
+
+    /// Puncture the previous epoch if it's in its grace period
+    /// Returns false if puncturing failed and the key was rotated.
+    pub fn end_grace(&mut self, config: &Config) -> bool {
+        let Some(epoch) = self.grace_epoch.take() else {
+            return true;
+        };
+        let punctured = self.puncture(epoch, config);
+        info!("grace period for epoch {epoch} ended");
+        punctured
+    }
+
+    /// Puncture an epoch so it can no longer be used
+    /// If that fails, the key is rotated instead. Discarding the
+    /// private key also means nothing more can be evaluated under
+    /// it, so this is the safe fallback. Returns false if so.
+    fn puncture(&mut self, epoch: u8, config: &Config) -> bool {
+        match self.server.puncture(epoch) {
+            Ok(()) => {
+                self.punctured.insert(epoch);
+                self.last_punctured = Some(epoch);
+                true
+            }
+            Err(e) => {
+                error!("Failed to puncture epoch {epoch}, rotating OPRF key instead: {e}");
+                metrics::increment_counter!(telemetry::PUNCTURE_FAILURES);
+                self.rotate_key(config);
+                false
+            }
+        }
+    }
+}
+
+/// Evaluate a throwaway point under a fresh key
+/// This runs whenever the key is created, at startup and on each
+/// rotation, so the first real request doesn't pay for any lazy
+/// initialization. The output is discarded and reveals nothing.
+fn warm_up(server: &ppoprf::Server, epoch: u8) -> Option<Duration> {
+    let point = ppoprf::Point::from(
+        RISTRETTO_BASEPOINT_COMPRESSED.as_bytes().as_slice(),
+    );
+    let start = std::time::Instant::now();
+    match server.eval(&point, epoch, false) {
+        Ok(_) => {
+            let elapsed = start.elapsed();
+            debug!("warmup evaluation took {elapsed:?}");
+            Some(elapsed)
+        }
+        Err(e) => {
+            warn!("warmup evaluation failed: {e}");
+            None
+        }
+    }
+}
+
+/// Sequence of epochs anchored at a base time
+#[derive(Clone, Debug)]
+pub struct Schedule {
+    /// Time at which the first epoch began
+    base_time: OffsetDateTime,
+    /// Duration of each epoch
+    interval: Duration,
+    /// Epoch tags in the sequence, in order
+    epochs: Vec<u8>,
+    /// Elapsed epoch count at which the schedule ends, if it does
+    end: Option<u64>,
+}
+
+impl Schedule {
+    /// Describe the epoch sequence of `config` starting at `base_time`
+    pub fn new(config: &Config, base_time: OffsetDateTime) -> Self {
+        Schedule {
+            base_time,
+            interval: Duration::from_secs(config.epoch_seconds.into()),
+            epochs: config.epoch_list(),
+            end: None,
+        }
+    }
+
+    /// End the schedule after one pass through the epochs from `now`
+    /// Tags repeat once the list wraps, so a key which is never
+    /// punctured mustn't follow the schedule any further. If `now`
+    /// is before the base time, the pass starts there instead.
+    pub fn one_pass(mut self, now: OffsetDateTime) -> Self {
+        let start = self.elapsed_epochs(now).unwrap_or_default();
+        self.end = Some(start + self.epochs.len() as u64);
+        self
+    }
+
+    /// Whether the schedule has ended by `now`
+    pub fn ended(&self, now: OffsetDateTime) -> bool {
+        let elapsed = self.elapsed_epochs(now);
+        self.end
+            .zip(elapsed)
+            .is_some_and(|(end, elapsed)| elapsed >= end)
+    }
+
+    /// Number of epochs which follow the one active at `now`
+    /// Returns `None` if the schedule doesn't end, or hasn't started.
+    pub fn remaining(&self, now: OffsetDateTime) -> Option<u8> {
+        let remaining = self
+            .end?
+            .saturating_sub(self.elapsed_epochs(now)? + 1)
+            .min(u8::MAX.into());
+        Some(remaining as u8)
+    }
+
+    /// Number of whole epochs between the base time and `now`
+    /// Returns `None` if `now` is before the base time, which a
+    /// wall clock stepping backwards can cause even after startup.
+    pub fn elapsed_epochs(&self, now: OffsetDateTime) -> Option<u64> {
+        if now < self.base_time {
+            return None;
+        }
+        // The ratio of two Durations is an f64 (in seconds) which
+        // covers the representable range of `OffsetDateTime`.
+        Some(((now - self.base_time) / self.interval).floor() as u64)
+    }
+
+    /// Start and end of the epoch active at `now`
+    pub fn window(
+        &self,
+        now: OffsetDateTime,
+    ) -> Option<(OffsetDateTime, OffsetDateTime)> {
+        let (_, end) = self.at(now)?;
+        Some((end - self.interval, end))
+    }
+
+    /// Epoch active at `now`, and the time it ends
+    /// Returns `None` if the schedule hasn't started by `now`.
+    pub fn at(&self, now: OffsetDateTime) -> Option<(u8, OffsetDateTime)> {
+        let elapsed_epochs = self.elapsed_epochs(now)?;
+
+        // The list is never empty once the config is validated.
+        let position = elapsed_epochs % self.epochs.len() as u64;
+        let epoch = self.epochs[position as usize];
+
+        // `Duration` doesn't implement `Mul<u64>` so we must truncate
+        // the elapsed epoch count. `Config::validate` rejects base
+        // times far enough back to overflow, but check in case
+        // base_time is very large while inverval is small.
+        let count = u32::try_from(elapsed_epochs + 1).ok()?;
+        Some((epoch, self.base_time + self.interval * count))
+    }
+}
+
+/// Format a rotation time for publication
+/// The timestamp is rounded up to a whole second, so it's never
+/// before the actual rotation. A client waiting until the published
+/// time will always find the new epoch in effect.
+pub fn format_rotation(next_rotation: OffsetDateTime) -> String {
+    let truncated = next_rotation
+        .replace_nanosecond(0)
+        .expect("should be able to truncate to a fixed ns");
+    let rounded = if truncated < next_rotation {
+        truncated + time::Duration::SECOND
+    } else {
+        truncated
+    };
+    rounded
+        .format(&Rfc3339)
+        .expect("well-known timestamp format should always succeed")
+}
+
+/// Epoch schedule position saved across restarts
+///
+/// The ppoprf library doesn't support serializing the server's
+/// private key, so a restart always begins with a fresh key. Saving
+/// the base time lets the epoch sequence continue where it left off
+/// when `--epoch-base-time` isn't given explicitly.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SavedState {
+    /// Time at which the first epoch began
+    #[serde(with = "time::serde::rfc3339")]
+    pub base_time: OffsetDateTime,
+    /// Epoch active at shutdown
+    pub epoch: u8,
+    /// RFC 3339 timestamp of the rotation which was pending
+    pub next_epoch_time: Option<String>,
+}
+
+impl SavedState {
+    /// Read a previously-saved state file
+    /// Returns `Ok(None)` if the file doesn't exist.
+    pub fn load(path: &Path) -> std::io::Result<Option<Self>> {
+        let contents = match std::fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(Some(serde_json::from_slice(&contents)?))
+    }
+
+    /// Write the state file
+    /// The file is replaced atomically so a crash while writing
+    /// can't leave a truncated file behind.
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let contents = serde_json::to_vec_pretty(self)?;
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, contents)?;
+        std::fs::rename(&tmp, path)
+    }
+}
+
+/// Persist the schedule position if a state file is configured
+fn save_state(state: &OPRFState, config: &Config, base_time: OffsetDateTime) {
+    let Some(path) = &config.state_file else {
+        return;
+    };
+    let saved = match state.read() {
+        Ok(s) => SavedState {
+            base_time,
+            epoch: s.epoch,
+            next_epoch_time: s.next_epoch_time(),
+        },
+        Err(_) => {
+            error!("Couldn't lock OPRFState to save state");
+            return;
+        }
+    };
+    match saved.save(path) {
+        Ok(()) => info!("saved epoch state to {}", path.display()),
+        Err(e) => {
+            error!("Couldn't save epoch state to {}: {e}", path.display())
+        }
+    }
+}
+
+/// Sanity-check the time of the next scheduled rotation
+///
+/// The next rotation should never be more than one interval in the
+/// future. If it is, the system clock has stepped backward and
+/// sleeping until `next_rotation` would keep the current epoch
+/// active far too long. Re-anchor the schedule from `now` instead.
+pub fn check_schedule(
+    next_rotation: OffsetDateTime,
+    now: OffsetDateTime,
+    interval: Duration,
+) -> OffsetDateTime {
+    let remaining = next_rotation - now;
+    if remaining > interval {
+        warn!(
+            "Clock stepped backward: next rotation is {} seconds away \
+             with a {} second interval. Re-anchoring epoch schedule.",
+            remaining.whole_seconds(),
+            interval.as_secs()
+        );
+        return now + interval;
+    }
+    next_rotation
+}
+
+/// Source of the current time for epoch scheduling
+///
+/// This lets tests substitute a clock which follows tokio's
+/// paused time, so rotations can be fast-forwarded.
+pub trait Clock {
+    /// The current wall-clock time
+    fn now(&self) -> OffsetDateTime;
+}
+
+/// The system's wall clock
+#[derive(Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> OffsetDateTime {
+        OffsetDateTime::now_utc()
+    }
+}
+
+/// Time at which the epoch schedule began
+/// This comes from `--epoch-base-time` if given, otherwise the
+/// saved state, otherwise `start_time`.
+fn base_time(config: &Config, start_time: OffsetDateTime) -> OffsetDateTime {
+    if let Some(base_time) = config.epoch_base_time {
+        return base_time;
+    }
+    // A saved base time lets us resume the previous schedule.
+    let saved = config.state_file.as_deref().and_then(|path| {
+        SavedState::load(path)
+            .unwrap_or_else(|e| {
+                warn!("Ignoring unreadable state file {}: {e}", path.display());
+                None
+            })
+            .filter(|saved| saved.base_time <= start_time)
+    });
+    match saved {
+        Some(saved) => {
+            info!(
+                "resuming schedule saved at epoch {} with rotation due {:?}",
+                saved.epoch, saved.next_epoch_time
+            );
+            saved.base_time
+        }
+        None => start_time,
+    }
+}
+
+/// Anchor the schedule at the scheduled key rotation if it's passed
+/// The epoch sequence starts over when the key is rotated at
+/// `--rotate-key-at`, so a loop started after that time must count
+/// epochs from the rotation rather than the original base time.
+fn rotated_base_time(
+    config: &Config,
+    base_time: OffsetDateTime,
+    now: OffsetDateTime,
+) -> OffsetDateTime {
+    match config.rotate_key_at {
+        Some(rotate_at) if base_time <= rotate_at && rotate_at <= now => {
+            rotate_at
+        }
+        _ => base_time,
+    }
+}
+
+/// Absolute number of the epoch active at `now`
+/// Epochs are counted from the original base time. A scheduled key
+/// rotation which has passed cuts short the epoch it interrupts, and
+/// the count carries on from the rotation rather than starting over.
+fn epoch_number(
+    config: &Config,
+    base_time: OffsetDateTime,
+    rotated_base_time: OffsetDateTime,
+    now: OffsetDateTime,
+) -> u64 {
+    let interval = Duration::from_secs(config.epoch_seconds.into());
+    // Count the interrupted epoch, if any, along with the whole ones.
+    let before = ((rotated_base_time - base_time) / interval).ceil() as u64;
+    before
+        + Schedule::new(config, rotated_base_time)
+            .elapsed_epochs(now)
+            .expect("epoch-base-time should be in the past")
 }
 
 /// Advance to the next epoch on a timer
 /// This can be invoked as a background task to handle epoch
 /// advance and key rotation according to the given Config.
-#[instrument(skip_all)]
-pub async fn epoch_loop(state: OPRFState, config: &Config) {
-    let epochs = config.first_epoch..=config.last_epoch;
+/// The loop exits when `shutdown` changes or its sender is
+/// dropped, saving the schedule position if so configured. It also
+/// exits once the epochs run out under `--on-epoch-exhaustion exit`.
+pub async fn epoch_loop(
+    state: OPRFState,
+    config: &Config,
+    shutdown: watch::Receiver<bool>,
+) -> Stopped {
+    epoch_loop_with_clock(state, config, shutdown, &SystemClock).await
+}
 
-    let interval =
-        std::time::Duration::from_secs(config.epoch_seconds.into());
+/// Advance to the next epoch on a timer, reading time from `clock`
+/// Sleeps use tokio's timer, so `clock` should advance along with
+/// `tokio::time::Instant`.
+#[instrument(skip_all)]
+pub async fn epoch_loop_with_clock(
+    state: OPRFState,
+    config: &Config,
+    mut shutdown: watch::Receiver<bool>,
+    clock: &impl Clock,
+) -> Stopped {
+    let interval = Duration::from_secs(config.epoch_seconds.into());
     info!("rotating epoch every {} seconds", interval.as_secs());
+    let grace = Duration::from_secs(config.epoch_grace_seconds.into());
 
-    let start_time = time::OffsetDateTime::now_utc();
-    // Epoch base_time comes from a config argument if given,
-    // otherwise use start_time.
-    let base_time = config.epoch_base_time.unwrap_or(start_time);
+    let start_time = clock.now();
+    let original_base_time = base_time(config, start_time);
+    let mut base_time =
+        rotated_base_time(config, original_base_time, start_time);
     info!(
         "epoch base time = {}",
         base_time
@@ -61,97 +846,427 @@ pub async fn epoch_loop(state: OPRFState, config: &Config) {
 
     // Calculate where we are in the epoch schedule relative to the
     // base time. We may need to start in the middle of the range.
-    assert!(
-        start_time >= base_time,
-        "epoch-base-time should be in the past"
-    );
-    // The time difference will be positive after the assert.
-    // The ratio of two Durations is an f64 (in seconds) which covers
-    // the representable range of `OffsetDateTime`.
-    let elapsed_epochs = ((start_time - base_time) / interval).floor() as u64;
-
-    // The `epochs` range is `u8`, so the length can be no more
-    // than `u8::MAX + 1`, making it safe to truncate the modulo.
-    let offset = elapsed_epochs % epochs.len() as u64;
-    let current_epoch = epochs.start() + offset as u8;
+    // The first rotation happens after whatever time remains for
+    // the current epoch.
+    let (current_epoch, mut next_rotation) = Schedule::new(config, base_time)
+        .at(start_time)
+        .expect("epoch-base-time should be in the past");
+    let rotating = state
+        .read()
+        .expect("Failed to lock OPRFState")
+        .rotating
+        .clone();
 
     // Advance to the current epoch if base time indicates we started
     // in the middle of a sequence.
-    if current_epoch != config.first_epoch {
-        info!(
-            "Puncturing obsolete epochs {}..{} to match base time",
-            config.first_epoch, current_epoch
-        );
+    let epochs = config.epoch_list();
+    let position = epochs.iter().position(|&e| e == current_epoch);
+    if let Some(obsolete) =
+        position.map(|p| &epochs[..p]).filter(|o| !o.is_empty())
+    {
+        info!("Puncturing obsolete epochs {obsolete:?} to match base time");
         let mut s = state.write().expect("Failed to lock OPRFState");
-        for epoch in config.first_epoch..current_epoch {
-            s.server
-                .puncture(epoch)
-                .expect("Failed to puncture obsolete epoch");
+        // A failure rotates the key, which then starts over at the
+        // first epoch.
+        if obsolete.iter().all(|&epoch| s.puncture(epoch, config)) {
+            s.epoch = current_epoch;
         }
-        s.epoch = current_epoch;
         info!("epoch now {}", s.epoch);
     }
+    state
+        .write()
+        .expect("Failed to lock OPRFState")
+        .epoch_number =
+        epoch_number(config, original_base_time, base_time, start_time);
 
-    // First rotation happens after whatever time remains for the current epoch.
-    // `Duration` doesn't implement `Mul<u64>` so we must truncate the elapsed
-    // epoch count. Assert that this is valid in case base_time is very large
-    // while inverval is small.
-    assert!(elapsed_epochs < u32::MAX as u64, "cast mustn't overflow");
-    let mut next_rotation =
-        base_time + interval * (elapsed_epochs + 1) as u32;
+    // A key rotation scheduled for the future interrupts the epoch
+    // sequence once it's due.
+    let mut rotate_at = config.rotate_key_at.filter(|t| *t > start_time);
+    if let Some(rotate_at) = rotate_at {
+        info!(
+            "key rotation scheduled at {}",
+            rotate_at
+                .format(&Rfc3339)
+                .expect("well-known timestamp format should always succeed")
+        );
+    }
 
     loop {
-        // Pre-calculate the next_epoch_time for the InfoResponse hander.
-        // Truncate to the nearest second.
-        let timestamp = next_rotation
-            .replace_millisecond(0)
-            .expect("should be able to truncate to a fixed ms")
-            .format(&Rfc3339)
-            .expect("well-known timestamp format should always succeed");
-        {
+        // Guard against the system clock stepping backward.
+        next_rotation = check_schedule(next_rotation, clock.now(), interval);
+
+        // The current epoch ends early if the key is due for rotation.
+        // It started a whole interval before it would otherwise end,
+        // which is also the previous rotation.
+        let scheduled = rotate_at.filter(|t| *t <= next_rotation);
+        let epoch_end = scheduled.unwrap_or(next_rotation);
+        let epoch_start = next_rotation - interval;
+
+        // Publish the window for the InfoResponse handler.
+        let epochs_remaining = {
             // Acquire a temporary write lock which should be dropped
             // before sleeping. The locking should not fail, but if it
             // does we can't set the field back to None, so panic rather
             // than report stale information.
             let mut s = state
                 .write()
-                .expect("should be able to update next_epoch_time");
-            s.next_epoch_time = Some(timestamp);
+                .expect("should be able to update epoch_window");
+            s.epoch_window = Some((epoch_start, epoch_end));
+            publish_position(&s);
+            metrics::gauge!(
+                telemetry::EPOCHS_REMAINING,
+                s.epochs_remaining() as f64
+            );
+            s.epochs_remaining()
+        };
+
+        // Get the next key ready if this epoch ends in a rotation.
+        let exhausted = epochs_remaining == 0 && scheduled.is_none();
+        let rotates_on_exhaustion =
+            config.on_epoch_exhaustion == EpochExhaustionPolicy::Rotate;
+        if scheduled.is_some() || exhausted && rotates_on_exhaustion {
+            prepare_standby(&state, config).await;
         }
 
-        // Wait until the current epoch ends.
-        let sleep_duration = next_rotation - time::OffsetDateTime::now_utc();
+        // Wait until the current epoch ends. Sleeping until an absolute
+        // deadline rather than for a relative duration means time
+        // spent rotating or a late wakeup doesn't accumulate.
+        let remaining = epoch_end - clock.now();
         // Negative durations mean we're behind.
-        if sleep_duration.is_positive() {
-            tokio::time::sleep(sleep_duration.unsigned_abs()).await;
+        if remaining.is_positive() {
+            let deadline = Instant::now() + remaining.unsigned_abs();
+            tokio::select! {
+                _ = tokio::time::sleep_until(deadline) => {}
+                _ = shutdown.changed() => {
+                    info!("shutting down epoch rotation");
+                    save_state(&state, config, base_time);
+                    return Stopped::Shutdown;
+                }
+            }
+        }
+
+        if let Some(rotated) = scheduled {
+            info!("Scheduled key rotation due! Rotating OPRF key");
+            {
+                // Flag the rotation before waiting for the lock.
+                let _rotating = rotating.begin();
+                let mut s = state.write().expect("Failed to lock OPRFState");
+                let epoch = s.epoch;
+                if s.puncture(epoch, config) {
+                    s.rotate_key(config);
+                }
+                s.epoch_number += 1;
+                s.last_rotation = Some(clock.now());
+                info!("epoch now {}", s.epoch);
+            }
+            // Start the epoch sequence over from the rotation.
+            rotate_at = None;
+            base_time = rotated;
+            next_rotation = rotated + interval;
+            continue;
+        }
+        if exhausted && !rotates_on_exhaustion {
+            {
+                let _rotating = rotating.begin();
+                let mut s = state.write().expect("Failed to lock OPRFState");
+                s.halt(config);
+                s.last_rotation = Some(clock.now());
+            }
+            if config.on_epoch_exhaustion == EpochExhaustionPolicy::Exit {
+                save_state(&state, config, base_time);
+                return Stopped::Exhausted;
+            }
+            // Keep reporting the halt until the server shuts down.
+            let _ = shutdown.changed().await;
+            info!("shutting down epoch rotation");
+            save_state(&state, config, base_time);
+            return Stopped::Shutdown;
         }
         next_rotation += interval;
 
         // Acquire exclusive access to the oprf state.
         // Panics if this fails, since processing requests with an
         // expired epoch weakens user privacy.
-        let mut s = state.write().expect("Failed to lock OPRFState");
+        {
+            let _rotating = rotating.begin();
+            let mut s = state.write().expect("Failed to lock OPRFState");
+            s.advance(config);
+            s.epoch_number += 1;
+            s.last_rotation = Some(clock.now());
+        }
+        metrics::increment_counter!(telemetry::EPOCHS_ADVANCED);
 
-        // Puncture the current epoch so it can no longer be used.
-        let old_epoch = s.epoch;
-        s.server
-            .puncture(old_epoch)
-            .expect("Failed to puncture current epoch");
-
-        // Advance to the next epoch, checking for overflow
-        // and out-of-range.
-        let new_epoch = old_epoch.checked_add(1);
-        if new_epoch.filter(|e| epochs.contains(e)).is_some() {
-            // Server is already initialized for this one.
-            s.epoch = new_epoch.unwrap();
-        } else {
-            info!("Epochs exhausted! Rotating OPRF key");
-            // Panics if this fails. Puncture should mean we can't
-            // violate privacy through further evaluations, but we
-            // still want to drop the inner state with its private key.
-            *s = OPRFServer::new(config)
-                .expect("Could not initialize new PPOPRF state");
+        // Let requests for the previous epoch finish, then puncture it.
+        if grace.is_zero() {
+            continue;
+        }
+        // A scheduled key rotation cuts the grace period short.
+        let grace = match rotate_at {
+            Some(t) => {
+                grace.min((t - clock.now()).try_into().unwrap_or_default())
+            }
+            None => grace,
+        };
+        tokio::select! {
+            _ = tokio::time::sleep(grace) => {}
+            _ = shutdown.changed() => {
+                info!("shutting down epoch rotation");
+                save_state(&state, config, base_time);
+                return Stopped::Shutdown;
+            }
+        }
+        let _rotating = rotating.begin();
+        state
+            .write()
+            .expect("Failed to lock OPRFState")
+            .end_grace(config);
+    }
+}
+
+/// Generate a standby key in the background, unless one is ready
+/// If this fails, the rotation generates a key itself as usual.
+async fn prepare_standby(state: &OPRFState, config: &Config) {
+    if state
+        .read()
+        .expect("Failed to lock OPRFState")
+        .standby
+        .is_some()
+    {
+        return;
+    }
+    let key_config = config.clone();
+    let generated =
+        tokio::task::spawn_blocking(move || OPRFKey::generate(&key_config))
+            .await;
+    match generated {
+        Ok(Ok(key)) => {
+            info!("standby OPRF key ready");
+            state.write().expect("Failed to lock OPRFState").standby =
+                Some(key);
+        }
+        Ok(Err(e)) => warn!("Couldn't generate standby OPRF key: {e}"),
+        Err(e) => warn!("Standby OPRF key generation failed: {e}"),
+    }
+}
+
+/// Reason the epoch rotation task stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stopped {
+    /// `shutdown` changed or its sender was dropped
+    Shutdown,
+    /// Epochs ran out under `--on-epoch-exhaustion exit`
+    Exhausted,
+}
+
+/// The epoch rotation task stopped before shutdown
+#[derive(thiserror::Error, Debug)]
+pub enum RotationFailed {
+    #[error("epoch rotation panicked: {0}")]
+    Panicked(String),
+    #[error("epoch rotation was cancelled")]
+    Cancelled,
+    #[error("epochs exhausted under the current key")]
+    Exhausted,
+}
+
+impl From<tokio::task::JoinError> for RotationFailed {
+    fn from(e: tokio::task::JoinError) -> Self {
+        match e.try_into_panic() {
+            Ok(payload) => RotationFailed::Panicked(panic_message(&*payload)),
+            Err(_) => RotationFailed::Cancelled,
         }
-        info!("epoch now {}", s.epoch);
     }
 }
+
+/// Message carried by a panic payload
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    // panic!() payloads are either a literal or formatted.
+    match payload.downcast_ref::<String>() {
+        Some(message) => message.clone(),
+        None => payload
+            .downcast_ref::<&str>()
+            .map_or("unknown panic payload", |message| message)
+            .to_string(),
+    }
+}
+
+/// Schedule position of the epoch rotation task, for failure reports
+#[derive(Clone, Debug, Default)]
+struct Position {
+    epoch: u8,
+    epoch_number: u64,
+    epochs_remaining: u8,
+    next_epoch_time: Option<String>,
+}
+
+impl Position {
+    fn of(s: &OPRFServer) -> Self {
+        Position {
+            epoch: s.epoch,
+            epoch_number: s.epoch_number,
+            epochs_remaining: s.epochs_remaining(),
+            next_epoch_time: s.next_epoch_time(),
+        }
+    }
+}
+
+/// Epoch rotation task running in the current tokio task
+struct EpochLoop {
+    state: OPRFState,
+    /// Position as of the last published epoch window
+    published: Mutex<Position>,
+}
+
+tokio::task_local! {
+    static EPOCH_LOOP: EpochLoop;
+}
+
+/// Note the position of the epoch rotation task, if this is it
+/// The panic hook falls back to this when it can't read the state.
+fn publish_position(s: &OPRFServer) {
+    let _ = EPOCH_LOOP.try_with(|task| {
+        *task
+            .published
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Position::of(s);
+    });
+}
+
+/// Report panics in the epoch rotation task as they happen
+/// Hooks run before a panic unwinds or aborts, so the report is made
+/// whatever the build's panic strategy. Other panics, and these
+/// too after the report, go to the previously installed hook.
+pub fn install_panic_hook() {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let _ = EPOCH_LOOP.try_with(|task| {
+            let position = match task.state.try_read() {
+                Ok(s) => Position::of(&s),
+                Err(TryLockError::Poisoned(e)) => Position::of(&e.into_inner()),
+                // The panicking thread may hold the lock itself, so
+                // waiting for it could deadlock.
+                Err(TryLockError::WouldBlock) => task
+                    .published
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .clone(),
+            };
+            let failure =
+                RotationFailed::Panicked(panic_message(info.payload()));
+            report_failure(&position, &failure);
+        });
+        previous(info);
+    }));
+}
+
+/// Log a failure of the epoch rotation task
+/// The epoch and schedule position are included so a post-mortem
+/// can tell where in the schedule rotation stopped.
+fn report_failure(position: &Position, failure: &RotationFailed) {
+    let panic = match failure {
+        RotationFailed::Panicked(message) => Some(message.as_str()),
+        RotationFailed::Cancelled | RotationFailed::Exhausted => None,
+    };
+    error!(
+        panic,
+        epoch = position.epoch,
+        epoch_number = position.epoch_number,
+        epochs_remaining = position.epochs_remaining,
+        next_epoch_time = position.next_epoch_time,
+        "{failure}"
+    );
+}
+
+/// Delay before restarting a failed epoch rotation task
+/// This keeps a loop which fails immediately from spinning.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Run `epoch_loop` as a supervised background task
+/// If the task fails, the failure is logged and counted, then
+/// handled according to `--on-rotation-failure`. Exiting signals
+/// `shutdown` and returns the error, as does running out of epochs
+/// under `--on-epoch-exhaustion exit`.
+///
+/// A panic in the task is seen here as its `JoinError`, which relies
+/// on panics unwinding. The release profile is set up accordingly.
+/// The panic itself is logged by the hook `install_panic_hook` sets.
+pub async fn supervise_epoch_loop(
+    state: OPRFState,
+    config: Config,
+    shutdown: watch::Sender<bool>,
+) -> Result<(), RotationFailed> {
+    supervise_epoch_loop_with_clock(state, config, shutdown, SystemClock).await
+}
+
+/// Run `epoch_loop_with_clock` as a supervised background task
+#[instrument(skip_all)]
+pub async fn supervise_epoch_loop_with_clock<C>(
+    state: OPRFState,
+    mut config: Config,
+    shutdown: watch::Sender<bool>,
+    clock: C,
+) -> Result<(), RotationFailed>
+where
+    C: Clock + Clone + Send + Sync + 'static,
+{
+    // Pin the schedule so a restarted loop resumes it.
+    config.epoch_base_time = Some(base_time(&config, clock.now()));
+    loop {
+        let loop_state = state.clone();
+        let loop_config = config.clone();
+        let loop_shutdown = shutdown.subscribe();
+        let loop_clock = clock.clone();
+        let task_local = EpochLoop {
+            state: state.clone(),
+            published: Default::default(),
+        };
+        let task = tokio::spawn(EPOCH_LOOP.scope(task_local, async move {
+            epoch_loop_with_clock(
+                loop_state,
+                &loop_config,
+                loop_shutdown,
+                &loop_clock,
+            )
+            .await
+        }));
+        let e = match task.await {
+            Ok(Stopped::Shutdown) => return Ok(()),
+            Ok(Stopped::Exhausted) => {
+                error!("shutting down after epochs were exhausted");
+                let _ = shutdown.send(true);
+                return Err(RotationFailed::Exhausted);
+            }
+            Err(e) => RotationFailed::from(e),
+        };
+        // Panics were already reported by the hook as they happened.
+        if let RotationFailed::Cancelled = e {
+            error!("{e}");
+        }
+        metrics::increment_counter!(telemetry::EPOCH_LOOP_FAILURES);
+        match config.on_rotation_failure {
+            RotationFailurePolicy::Exit => {
+                error!("shutting down after epoch rotation failure");
+                let _ = shutdown.send(true);
+                return Err(e);
+            }
+            RotationFailurePolicy::Restart => {
+                tokio::time::sleep(RESTART_DELAY).await;
+                restart(&state, &config);
+            }
+        }
+    }
+}
+
+/// Reset the OPRF state after the epoch loop failed
+/// We can't tell which epochs were punctured before the failure,
+/// so start again under a fresh key. The restarted loop punctures
+/// the epochs before the current one to match the schedule.
+fn restart(state: &OPRFState, config: &Config) {
+    warn!("restarting epoch rotation with a fresh key");
+    // A panic while the lock was held leaves it poisoned, but
+    // we're replacing the state entirely.
+    let mut s = state.write().unwrap_or_else(PoisonError::into_inner);
+    s.rotate_key(config);
+    drop(s);
+    state.clear_poison();
+}
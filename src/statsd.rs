@@ -0,0 +1,252 @@
+//! STAR Randomness web service
+//! Metrics export in the dogstatsd format
+//!
+//! Metrics are aggregated in memory and pushed over UDP on each
+//! `flush`, so a busy server doesn't send a packet per event.
+//! Labels become dogstatsd tags, e.g. `name:1|c|#epoch:12`.
+
+use metrics::{
+    Counter, CounterFn, Gauge, GaugeFn, Histogram, HistogramFn, Key, KeyName, Recorder,
+    SharedString, Unit,
+};
+use std::collections::HashMap;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::debug;
+
+/// How often aggregated metrics are sent
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Largest datagram to send
+/// This fits in a typical ethernet MTU, so packets aren't
+/// fragmented. A single longer line is still sent on its own.
+const MAX_PACKET: usize = 1432;
+
+/// Counter increments since the last flush
+#[derive(Default)]
+struct StatsdCounter(AtomicU64);
+
+impl CounterFn for StatsdCounter {
+    fn increment(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    // statsd counters only carry increments, so there's no way to
+    // report an absolute value.
+    fn absolute(&self, _value: u64) {}
+}
+
+/// Latest gauge value
+#[derive(Default)]
+struct StatsdGauge {
+    /// Bits of the `f64` value
+    value: AtomicU64,
+    /// Whether the value changed since the last flush
+    changed: AtomicBool,
+}
+
+impl StatsdGauge {
+    /// Apply `f` to the current value
+    fn update(&self, f: impl Fn(f64) -> f64) {
+        let _ = self
+            .value
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some(f(f64::from_bits(bits)).to_bits())
+            });
+        self.changed.store(true, Ordering::Relaxed);
+    }
+}
+
+impl GaugeFn for StatsdGauge {
+    fn increment(&self, value: f64) {
+        self.update(|current| current + value);
+    }
+
+    fn decrement(&self, value: f64) {
+        self.update(|current| current - value);
+    }
+
+    fn set(&self, value: f64) {
+        self.update(|_| value);
+    }
+}
+
+/// Histogram samples since the last flush
+#[derive(Default)]
+struct StatsdHistogram(Mutex<Vec<f64>>);
+
+impl HistogramFn for StatsdHistogram {
+    fn record(&self, value: f64) {
+        self.0
+            .lock()
+            .expect("statsd histogram lock poisoned")
+            .push(value);
+    }
+}
+
+/// Aggregated state of a single registered metric
+enum Metric {
+    Counter(Arc<StatsdCounter>),
+    Gauge(Arc<StatsdGauge>),
+    Histogram(Arc<StatsdHistogram>),
+}
+
+/// Shared state of a `StatsdRecorder`
+struct Inner {
+    /// Socket connected to the statsd server
+    socket: UdpSocket,
+    /// Registered metrics, keyed by name and labels
+    metrics: Mutex<HashMap<Key, Metric>>,
+}
+
+/// Recorder sending metrics to a dogstatsd server
+/// Clones share the same metrics, so one can be installed as the
+/// global recorder while another is flushed.
+#[derive(Clone)]
+pub struct StatsdRecorder {
+    inner: Arc<Inner>,
+}
+
+impl StatsdRecorder {
+    /// Create a recorder sending to `addr`
+    pub fn new(addr: SocketAddr) -> std::io::Result<Self> {
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        // Metrics are best effort, and mustn't hold up the runtime.
+        socket.set_nonblocking(true)?;
+        Ok(StatsdRecorder {
+            inner: Arc::new(Inner {
+                socket,
+                metrics: Mutex::new(HashMap::new()),
+            }),
+        })
+    }
+
+    /// Send everything recorded since the last flush
+    pub fn flush(&self) {
+        let mut lines = Vec::new();
+        let metrics = self
+            .inner
+            .metrics
+            .lock()
+            .expect("statsd metrics lock poisoned");
+        for (key, metric) in metrics.iter() {
+            let name = key.name();
+            let tags = tags(key);
+            match metric {
+                Metric::Counter(counter) => {
+                    let value = counter.0.swap(0, Ordering::Relaxed);
+                    if value > 0 {
+                        lines.push(format!("{name}:{value}|c{tags}"));
+                    }
+                }
+                Metric::Gauge(gauge) => {
+                    if gauge.changed.swap(false, Ordering::Relaxed) {
+                        let value = f64::from_bits(gauge.value.load(Ordering::Relaxed));
+                        lines.push(format!("{name}:{value}|g{tags}"));
+                    }
+                }
+                Metric::Histogram(histogram) => {
+                    let samples = std::mem::take(
+                        &mut *histogram.0.lock().expect("statsd histogram lock poisoned"),
+                    );
+                    for value in samples {
+                        lines.push(format!("{name}:{value}|h{tags}"));
+                    }
+                }
+            }
+        }
+        drop(metrics);
+        for packet in packets(&lines) {
+            if let Err(e) = self.inner.socket.send(packet.as_bytes()) {
+                debug!("Couldn't send statsd metrics: {e}");
+            }
+        }
+    }
+
+    /// Flush at `FLUSH_INTERVAL` for as long as the runtime runs
+    pub async fn flush_periodically(self) {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.flush();
+        }
+    }
+
+    /// Look up the metric for `key`, registering it if necessary
+    fn register(&self, key: &Key, new: impl FnOnce() -> Metric) -> Metric {
+        let mut metrics = self
+            .inner
+            .metrics
+            .lock()
+            .expect("statsd metrics lock poisoned");
+        let metric = metrics.entry(key.clone()).or_insert_with(new);
+        match metric {
+            Metric::Counter(counter) => Metric::Counter(counter.clone()),
+            Metric::Gauge(gauge) => Metric::Gauge(gauge.clone()),
+            Metric::Histogram(histogram) => Metric::Histogram(histogram.clone()),
+        }
+    }
+}
+
+impl Recorder for StatsdRecorder {
+    // statsd has no metadata, so descriptions aren't sent.
+    fn describe_counter(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+    fn describe_gauge(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+    fn describe_histogram(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn register_counter(&self, key: &Key) -> Counter {
+        match self.register(key, || Metric::Counter(Arc::default())) {
+            Metric::Counter(counter) => Counter::from_arc(counter),
+            _ => Counter::noop(),
+        }
+    }
+
+    fn register_gauge(&self, key: &Key) -> Gauge {
+        match self.register(key, || Metric::Gauge(Arc::default())) {
+            Metric::Gauge(gauge) => Gauge::from_arc(gauge),
+            _ => Gauge::noop(),
+        }
+    }
+
+    fn register_histogram(&self, key: &Key) -> Histogram {
+        match self.register(key, || Metric::Histogram(Arc::default())) {
+            Metric::Histogram(histogram) => Histogram::from_arc(histogram),
+            _ => Histogram::noop(),
+        }
+    }
+}
+
+/// Format the labels of `key` as dogstatsd tags
+fn tags(key: &Key) -> String {
+    let tags: Vec<String> = key
+        .labels()
+        .map(|label| format!("{}:{}", label.key(), label.value()))
+        .collect();
+    if tags.is_empty() {
+        String::new()
+    } else {
+        format!("|#{}", tags.join(","))
+    }
+}
+
+/// Pack lines into as few datagrams as possible
+fn packets(lines: &[String]) -> Vec<String> {
+    let mut packets: Vec<String> = Vec::new();
+    for line in lines {
+        match packets.last_mut() {
+            Some(packet) if packet.len() + 1 + line.len() <= MAX_PACKET => {
+                packet.push('\n');
+                packet.push_str(line);
+            }
+            _ => packets.push(line.clone()),
+        }
+    }
+    packets
+}
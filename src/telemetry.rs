@@ -0,0 +1,273 @@
+//! STAR Randomness web service
+//! Application metrics
+//!
+//! These are recorded through the `metrics` facade, and exported
+//! by the prometheus recorder when `--prometheus-listen` is given.
+
+use axum::body::Body;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use axum_prometheus::utils::{requests_duration_name, SECONDS_DURATION_BUCKETS};
+use metrics::{describe_counter, describe_gauge, describe_histogram, register_counter, Unit};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use metrics_util::layers::FanoutBuilder;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::handler::ErrorCode;
+use crate::statsd::StatsdRecorder;
+
+/// Randomness requests rejected for containing too many points
+pub const REJECTED_OVERSIZE: &str = "randomness_rejected_oversize_total";
+
+/// Randomness requests rejected because their epoch was punctured
+/// These come from clients lagging behind rotation, as opposed to
+/// asking for an epoch which was never valid.
+pub const REJECTED_PUNCTURED: &str = "randomness_rejected_punctured_total";
+
+/// Points evaluated, labeled by the epoch used
+/// Epochs are `u8`, so the label has at most 256 values.
+pub const EVALUATIONS_BY_EPOCH: &str = "oprf_evaluations_by_epoch_total";
+
+/// Epochs remaining before the OPRF key is rotated
+pub const EPOCHS_REMAINING: &str = "oprf_epochs_remaining";
+
+/// Epochs advanced by the rotation task, including key rotations
+pub const EPOCHS_ADVANCED: &str = "oprf_epochs_advanced_total";
+
+/// Epochs which couldn't be punctured, forcing a key rotation
+pub const PUNCTURE_FAILURES: &str = "oprf_puncture_failures_total";
+
+/// Times the circuit breaker opened after repeated evaluation failures
+pub const CIRCUIT_BREAKER_TRIPS: &str = "randomness_circuit_breaker_trips_total";
+
+/// Randomness evaluations waiting for a worker thread
+pub const EVAL_QUEUE_DEPTH: &str = "randomness_eval_queue_depth";
+
+/// Unexpected failures of the epoch rotation task
+pub const EPOCH_LOOP_FAILURES: &str = "oprf_epoch_loop_failures_total";
+
+/// Time to handle randomness requests, labeled by outcome
+pub const REQUEST_DURATION: &str = "randomness_request_duration_seconds";
+
+/// Time to parse randomness requests and decode their points,
+/// labeled by request format
+pub const DECODE_DURATION: &str = "randomness_decode_duration_seconds";
+
+/// Time to encode evaluated points and serialize the response,
+/// labeled by response format
+pub const ENCODE_DURATION: &str = "randomness_encode_duration_seconds";
+
+/// Histogram buckets for `REQUEST_DURATION`, in seconds
+/// A single point evaluates in well under a millisecond, while a
+/// full batch on a loaded server can take seconds.
+const REQUEST_DURATION_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Histogram buckets for `DECODE_DURATION` and `ENCODE_DURATION`
+/// Encoding a single point takes around a microsecond, so these
+/// start finer than `REQUEST_DURATION_BUCKETS`.
+const CODEC_DURATION_BUCKETS: &[f64] = &[
+    0.00001, 0.000025, 0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05,
+    0.1,
+];
+
+/// Prometheus exporter configuration for our metrics
+/// Histograms are exported with buckets rather than as summaries.
+pub fn builder() -> PrometheusBuilder {
+    PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full(REQUEST_DURATION.to_string()),
+            REQUEST_DURATION_BUCKETS,
+        )
+        .expect("buckets should not be empty")
+        .set_buckets_for_metric(
+            Matcher::Full(DECODE_DURATION.to_string()),
+            CODEC_DURATION_BUCKETS,
+        )
+        .expect("buckets should not be empty")
+        .set_buckets_for_metric(
+            Matcher::Full(ENCODE_DURATION.to_string()),
+            CODEC_DURATION_BUCKETS,
+        )
+        .expect("buckets should not be empty")
+        .set_buckets_for_metric(
+            Matcher::Full(requests_duration_name().to_string()),
+            SECONDS_DURATION_BUCKETS,
+        )
+        .expect("buckets should not be empty")
+}
+
+/// Install the global metrics recorder
+/// Metrics are recorded for prometheus if `prometheus` is set, and
+/// sent to statsd if a recorder is given, or both. The handle
+/// renders the prometheus metrics.
+pub fn install_recorder(
+    prometheus: bool,
+    statsd: Option<StatsdRecorder>,
+) -> Option<PrometheusHandle> {
+    let prometheus = prometheus.then(|| builder().build_recorder());
+    let handle = prometheus.as_ref().map(|recorder| recorder.handle());
+    let recorder: Box<dyn metrics::Recorder> = match (prometheus, statsd) {
+        (Some(prometheus), Some(statsd)) => Box::new(
+            FanoutBuilder::default()
+                .add_recorder(prometheus)
+                .add_recorder(statsd)
+                .build(),
+        ),
+        (Some(prometheus), None) => Box::new(prometheus),
+        (None, Some(statsd)) => Box::new(statsd),
+        (None, None) => return None,
+    };
+    metrics::set_boxed_recorder(recorder).expect("Could not install metrics recorder");
+    register();
+    handle
+}
+
+/// Middleware recording how long a request took to handle
+/// This covers parsing the body, evaluation and serializing the
+/// response. Streamed responses are only timed until the first
+/// line is ready. Failures are labeled with their error code.
+pub async fn record_duration(request: Request<Body>, next: Next<Body>) -> Response {
+    let start = Instant::now();
+    let response = next.run(request).await;
+    let outcome = response
+        .extensions()
+        .get::<ErrorCode>()
+        .map_or("ok", |code| code.0);
+    metrics::histogram!(
+        REQUEST_DURATION,
+        start.elapsed().as_secs_f64(),
+        "outcome" => outcome
+    );
+    response
+}
+
+/// Running totals reported by the `/stats` endpoint
+/// These duplicate some of the metrics above, but are kept
+/// whether or not a metrics recorder is installed.
+pub struct Stats {
+    /// When the service started
+    started: Instant,
+    /// Randomness requests handled
+    requests: AtomicU64,
+    /// Points evaluated by the randomness endpoint
+    points: AtomicU64,
+}
+
+impl Stats {
+    /// Start counting from zero
+    pub fn new() -> Self {
+        Stats {
+            started: Instant::now(),
+            requests: AtomicU64::new(0),
+            points: AtomicU64::new(0),
+        }
+    }
+
+    /// Count a randomness request
+    pub fn record_request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count evaluated points
+    pub fn record_points(&self, count: usize) {
+        self.points.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// Time since the service started
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Randomness requests handled so far
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    /// Points evaluated so far
+    pub fn points(&self) -> u64 {
+        self.points.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats::new()
+    }
+}
+
+/// Register our metrics with the installed recorder
+/// Counters are registered up front so they're exported
+/// with a zero value before the first event.
+pub fn register() {
+    describe_counter!(
+        REJECTED_OVERSIZE,
+        Unit::Count,
+        "Randomness requests rejected for exceeding the maximum number of points"
+    );
+    register_counter!(REJECTED_OVERSIZE);
+    describe_counter!(
+        REJECTED_PUNCTURED,
+        Unit::Count,
+        "Randomness requests rejected for an epoch which was already punctured"
+    );
+    register_counter!(REJECTED_PUNCTURED);
+    describe_counter!(
+        EVALUATIONS_BY_EPOCH,
+        Unit::Count,
+        "Points evaluated by the randomness endpoint, by epoch"
+    );
+    describe_gauge!(
+        EPOCHS_REMAINING,
+        Unit::Count,
+        "Epochs remaining before the OPRF key is rotated"
+    );
+    describe_counter!(
+        EPOCHS_ADVANCED,
+        Unit::Count,
+        "Epochs advanced by the rotation task since the process started"
+    );
+    register_counter!(EPOCHS_ADVANCED);
+    describe_counter!(
+        PUNCTURE_FAILURES,
+        Unit::Count,
+        "Epochs which couldn't be punctured, rotating the OPRF key instead"
+    );
+    register_counter!(PUNCTURE_FAILURES);
+    describe_counter!(
+        CIRCUIT_BREAKER_TRIPS,
+        Unit::Count,
+        "Times the circuit breaker opened after repeated evaluation failures"
+    );
+    register_counter!(CIRCUIT_BREAKER_TRIPS);
+    describe_gauge!(
+        EVAL_QUEUE_DEPTH,
+        Unit::Count,
+        "Randomness evaluations waiting for a worker thread, with --eval-queue-size"
+    );
+    describe_counter!(
+        EPOCH_LOOP_FAILURES,
+        Unit::Count,
+        "Unexpected failures of the epoch rotation task"
+    );
+    register_counter!(EPOCH_LOOP_FAILURES);
+    describe_histogram!(
+        REQUEST_DURATION,
+        Unit::Seconds,
+        "Time to handle randomness requests, by outcome"
+    );
+    describe_histogram!(
+        DECODE_DURATION,
+        Unit::Seconds,
+        "Time to parse randomness requests and decode their points, by format"
+    );
+    describe_histogram!(
+        ENCODE_DURATION,
+        Unit::Seconds,
+        "Time to encode evaluated points and serialize responses, by format"
+    );
+}
@@ -2,39 +2,127 @@
 
 use crate::state::OPRFServer;
 use axum::body::Body;
+use axum::extract::ConnectInfo;
 use axum::http::Request;
 use axum::http::StatusCode;
+use axum::response::Response;
 use base64::prelude::{Engine as _, BASE64_STANDARD as BASE64};
 use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+use ed25519_dalek::{Signer, SigningKey};
+use metrics_exporter_prometheus::PrometheusHandle;
 use rand::rngs::OsRng;
 use serde_json::{json, Value};
-use std::sync::{Arc, RwLock};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
 use std::time::Duration;
 use time::OffsetDateTime;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tower::ServiceExt;
 
 const EPOCH: u8 = 12;
 const NEXT_EPOCH_TIME: &str = "2023-03-22T21:46:35Z";
 
-/// Create an app instance for testing
-fn test_app() -> crate::Router {
-    // arbitrary config
-    let config = crate::Config {
-        listen: "127.0.0.1:8081".to_string(),
+/// Arbitrary config for testing
+fn test_config() -> crate::Config {
+    crate::Config {
+        config: None,
+        self_test: false,
+        listen: "127.0.0.1:8081".parse().unwrap(),
         epoch_seconds: 1,
+        epoch_grace_seconds: 0,
+        max_epoch_lag: None,
         first_epoch: EPOCH,
         last_epoch: EPOCH * 2,
+        epochs: None,
+        retain_previous_key: false,
         epoch_base_time: None,
+        rotate_key_at: None,
         increase_nofile_limit: false,
+        http_keepalive_seconds: None,
+        tcp_backlog: 1024,
+        max_new_connections_per_second: None,
+        tcp_nodelay: false,
+        http2: false,
+        request_timeout_seconds: None,
+        eval_timeout_ms: None,
+        worker_threads: None,
+        base64_variant: crate::Base64Variant::Standard,
+        json_field_case: crate::FieldCase::CamelCase,
+        info_cache_margin_seconds: 5,
         prometheus_listen: None,
-    };
+        statsd_addr: None,
+        require_metrics: false,
+        admin_token: None,
+        response_hmac_key: None,
+        require_signed_requests: false,
+        client_keys: None,
+        idempotency_ttl_seconds: None,
+        idempotency_cache_size: 1024,
+        circuit_breaker_threshold: None,
+        circuit_breaker_cooldown_seconds: 30,
+        eval_queue_size: None,
+        eval_queue_workers: None,
+        eval_queue_deadline_ms: 1000,
+        rate_limit_per_second: None,
+        trust_proxy: false,
+        access_log: false,
+        log_filter: None,
+        root_health_check: None,
+        disable_welcome: false,
+        enable_routes: crate::Route::ALL.to_vec(),
+        path_prefix: None,
+        state_file: None,
+        no_rotate: false,
+        on_rotation_failure: crate::RotationFailurePolicy::Restart,
+        on_epoch_exhaustion: crate::EpochExhaustionPolicy::Rotate,
+    }
+}
+
+/// Create an app instance for testing
+fn test_app() -> crate::Router {
+    test_app_with_config(&test_config())
+}
+
+/// Create an app instance for testing with a specific config
+fn test_app_with_config(config: &crate::Config) -> crate::Router {
     // server state
-    let mut server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
-    server.next_epoch_time = Some(NEXT_EPOCH_TIME.to_owned());
+    let mut server = OPRFServer::new(config).expect("Could not initialize PPOPRF state");
+    let end = OffsetDateTime::parse(
+        NEXT_EPOCH_TIME,
+        &time::format_description::well_known::Rfc3339,
+    )
+    .unwrap();
+    server.epoch_window = Some((end - Duration::from_secs(config.epoch_seconds.into()), end));
     let oprf_state = Arc::new(RwLock::new(server));
 
     // attach axum routes and middleware
-    crate::app(oprf_state)
+    crate::app(oprf_state, config)
+}
+
+/// Metrics recorder shared by all tests
+/// The `metrics` facade only allows one recorder per process, and
+/// tests run concurrently, so check for increases rather than
+/// exact values.
+fn test_recorder() -> &'static PrometheusHandle {
+    static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+    HANDLE.get_or_init(|| {
+        let recorder = crate::telemetry::builder().build_recorder();
+        let handle = recorder.handle();
+        metrics::set_boxed_recorder(Box::new(recorder)).expect("Could not install recorder");
+        crate::telemetry::register();
+        handle
+    })
+}
+
+/// Look up the current value of a metric, e.g. `name{label="value"}`
+fn metric_value(series: &str) -> f64 {
+    let prefix = format!("{series} ");
+    test_recorder()
+        .render()
+        .lines()
+        .find_map(|line| line.strip_prefix(&prefix))
+        .map_or(0.0, |value| value.parse().unwrap())
 }
 
 /// Create a request for testing
@@ -63,13 +151,218 @@ async fn welcome() {
     let request = test_request("/", None);
     let response = app.oneshot(request).await.unwrap();
 
-    // Root should return some identifying text for friendliness.
+    // Root should return some identifying text for friendliness,
+    // unless disabled.
     assert_eq!(response.status(), StatusCode::OK);
     let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
     let message = std::str::from_utf8(body.as_ref()).unwrap();
     assert!(!message.is_empty());
 }
 
+/// Root should return a fixed body when configured for load
+/// balancer health checks.
+#[tokio::test]
+async fn root_health_check() {
+    for (body, expected) in [(None, "OK"), (Some("healthy"), "healthy")] {
+        let mut args = vec!["star-randsrv", "--root-health-check"];
+        args.extend(body);
+        let config = crate::Config::try_load_from(args).expect("config should load");
+        assert_eq!(config.root_health_check.as_deref(), Some(expected));
+        let app = test_app_with_config(&crate::Config {
+            root_health_check: config.root_health_check,
+            ..test_config()
+        });
+
+        let response = app.oneshot(test_request("/", None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body.as_ref(), expected.as_bytes());
+    }
+}
+
+/// `--disable-welcome` should remove the root route only.
+#[tokio::test]
+async fn disable_welcome() {
+    let config = crate::Config {
+        disable_welcome: true,
+        ..test_config()
+    };
+    config.validate().expect("config should be valid");
+    let app = test_app_with_config(&config);
+
+    let response = app.clone().oneshot(test_request("/", None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = app
+        .clone()
+        .oneshot(test_request("/info", None))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let payload = json!({ "points": make_points(1) }).to_string();
+    let request = test_request("/randomness", Some(payload));
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // There's no root route for a health check to answer on.
+    let invalid = crate::Config {
+        root_health_check: Some("OK".to_string()),
+        ..config
+    };
+    assert!(invalid.validate().is_err());
+    let args = ["star-randsrv", "--disable-welcome", "--root-health-check"];
+    assert!(crate::Config::try_load_from(args).is_err());
+}
+
+/// `--log-filter` should set log levels per module.
+#[test]
+fn log_filter() {
+    use tracing::Level;
+
+    let args = [
+        "star-randsrv",
+        "--log-filter",
+        "warn,star_randsrv::state=debug",
+    ];
+    let config = crate::Config::try_load_from(args).expect("config should load");
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(config.log_filter())
+        .with_writer(std::io::sink)
+        .finish();
+    tracing::subscriber::with_default(subscriber, || {
+        assert!(tracing::enabled!(target: "star_randsrv::state", Level::DEBUG));
+        assert!(!tracing::enabled!(target: "star_randsrv::state", Level::TRACE));
+        assert!(!tracing::enabled!(target: "star_randsrv::handler", Level::INFO));
+        assert!(tracing::enabled!(target: "star_randsrv::handler", Level::WARN));
+        assert!(!tracing::enabled!(target: "access", Level::INFO));
+    });
+
+    // Invalid directives are rejected up front.
+    let args = ["star-randsrv", "--log-filter", "star_randsrv=loud"];
+    assert!(crate::Config::try_load_from(args).is_err());
+}
+
+/// Toggling verbose logging should reload the subscriber's filter.
+#[test]
+fn log_toggle() {
+    use tracing::Level;
+
+    let args = ["star-randsrv", "--log-filter", "warn"];
+    let config = crate::Config::try_load_from(args).expect("config should load");
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(config.log_filter())
+        .with_writer(std::io::sink)
+        .with_filter_reloading();
+    let mut toggle = crate::logging::LogToggle::new(builder.reload_handle());
+    tracing::subscriber::with_default(builder.finish(), || {
+        assert!(!tracing::enabled!(target: "star_randsrv::state", Level::DEBUG));
+        assert!(toggle.toggle(&config).unwrap());
+        assert!(tracing::enabled!(target: "star_randsrv::state", Level::DEBUG));
+        assert!(tracing::enabled!(target: "hyper", Level::INFO));
+        assert!(!tracing::enabled!(target: "hyper", Level::DEBUG));
+
+        // Toggling again restores the configured filter.
+        assert!(!toggle.toggle(&config).unwrap());
+        assert!(!tracing::enabled!(target: "star_randsrv::state", Level::DEBUG));
+        assert!(!tracing::enabled!(target: "hyper", Level::INFO));
+    });
+}
+
+/// Every route should be served under `--path-prefix`, and none
+/// outside it.
+#[tokio::test]
+async fn path_prefix() {
+    let args = ["star-randsrv", "--path-prefix", "/randsrv/"];
+    let config = crate::Config::try_load_from(args).expect("config should load");
+    assert_eq!(config.path_prefix(), Some("/randsrv"));
+    let app = test_app_with_config(&crate::Config {
+        path_prefix: config.path_prefix,
+        ..test_config()
+    });
+
+    for path in ["/randsrv", "/randsrv/info", "/randsrv/epoch/12"] {
+        let response = app.clone().oneshot(test_request(path, None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "{path}");
+    }
+    for path in ["/randsrv/randomness", "/randsrv/batch"] {
+        let payload = json!({ "points": make_points(1) }).to_string();
+        let request = test_request(path, Some(payload));
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "{path}");
+    }
+    let request = Request::builder()
+        .method("OPTIONS")
+        .uri("/randsrv/info")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.headers()["Allow"], "GET,HEAD,OPTIONS");
+    for path in ["/", "/info", "/other/info"] {
+        let response = app.clone().oneshot(test_request(path, None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "{path}");
+    }
+
+    // A bare `/` is no prefix.
+    let config = crate::Config {
+        path_prefix: Some("/".to_string()),
+        ..test_config()
+    };
+    assert_eq!(config.path_prefix(), None);
+    let app = test_app_with_config(&config);
+    let response = app.oneshot(test_request("/info", None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    for prefix in ["randsrv", "/:name", "/*rest"] {
+        let invalid = crate::Config {
+            path_prefix: Some(prefix.to_string()),
+            ..test_config()
+        };
+        assert!(invalid.validate().is_err(), "{prefix}");
+    }
+}
+
+/// Only the routes given to `--enable-routes` should be served.
+#[tokio::test]
+async fn enable_routes() {
+    let args = ["star-randsrv", "--enable-routes", "info,randomness"];
+    let config = crate::Config::try_load_from(args).expect("config should load");
+    assert_eq!(
+        config.enable_routes,
+        [crate::Route::Info, crate::Route::Randomness]
+    );
+    let app = test_app_with_config(&crate::Config {
+        enable_routes: config.enable_routes,
+        ..test_config()
+    });
+
+    let response = app
+        .clone()
+        .oneshot(test_request("/info", None))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let payload = json!({ "points": make_points(1) }).to_string();
+    let request = test_request("/randomness", Some(payload));
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    for path in ["/", "/epoch/12", "/stats", "/openapi.json"] {
+        let response = app.clone().oneshot(test_request(path, None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "{path}");
+    }
+
+    // Something must be served, and a health check needs the root.
+    let invalid = crate::Config {
+        enable_routes: vec![],
+        ..test_config()
+    };
+    assert!(invalid.validate().is_err());
+    let invalid = crate::Config {
+        enable_routes: vec![crate::Route::Info],
+        root_health_check: Some("OK".to_string()),
+        ..test_config()
+    };
+    assert!(invalid.validate().is_err());
+}
+
 #[tokio::test]
 async fn info() {
     let app = test_app();
@@ -89,6 +382,18 @@ async fn info() {
     assert!(json["nextEpochTime"].is_string());
     let next_epoch_time = json["nextEpochTime"].as_str().unwrap();
     assert_eq!(next_epoch_time, NEXT_EPOCH_TIME);
+    assert_eq!(json["epochSeconds"], json!(test_config().epoch_seconds));
+    let server_time = json["serverTime"]
+        .as_str()
+        .expect("serverTime should be a string");
+    let server_time =
+        OffsetDateTime::parse(server_time, &time::format_description::well_known::Rfc3339)
+            .expect("serverTime should be a valid timestamp");
+    let skew = (OffsetDateTime::now_utc() - server_time).abs();
+    assert!(
+        skew < Duration::from_secs(5),
+        "serverTime is {skew} from now"
+    );
     assert!(json["maxPoints"].is_number());
     let max_points = json["maxPoints"].as_u64().unwrap();
     assert_eq!(max_points, crate::MAX_POINTS as u64);
@@ -97,6 +402,261 @@ async fn info() {
     let binkey = BASE64.decode(b64key).unwrap();
     let _ = ppoprf::ppoprf::ServerPublicKey::load_from_bincode(&binkey)
         .expect("Could not parse server public key");
+    assert_eq!(json["ppoprf"]["version"], json!(crate::PPOPRF_VERSION));
+    assert_eq!(json["ppoprf"]["curve"], json!("ristretto255"));
+    assert_eq!(
+        json["ppoprf"]["pointLength"],
+        json!(ppoprf::ppoprf::COMPRESSED_POINT_LEN)
+    );
+}
+
+#[test]
+/// The reported ppoprf version should match the one we build against
+fn ppoprf_version_matches_lockfile() {
+    let lock = include_str!("../Cargo.lock");
+    let entry = format!("name = \"ppoprf\"\nversion = \"{}\"", crate::PPOPRF_VERSION);
+    assert!(lock.contains(&entry), "Cargo.lock doesn't have {entry}");
+}
+
+/// Fetch /info as json
+async fn info_json(app: &crate::Router) -> Value {
+    let request = test_request("/info", None);
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    serde_json::from_slice(body.as_ref()).unwrap()
+}
+
+#[tokio::test]
+async fn info_field_case() {
+    let camel_case = [
+        "publicKey",
+        "currentEpoch",
+        "currentEpochNumber",
+        "nextEpochTime",
+        "serverTime",
+        "epochSeconds",
+        "maxPoints",
+        "epochsRemaining",
+        "ppoprf",
+        "epochKeys",
+        "rotating",
+    ];
+    let snake_case = [
+        "public_key",
+        "current_epoch",
+        "current_epoch_number",
+        "next_epoch_time",
+        "server_time",
+        "epoch_seconds",
+        "max_points",
+        "epochs_remaining",
+        "ppoprf",
+        "epoch_keys",
+        "rotating",
+    ];
+
+    // camelCase is the default.
+    let json = info_json(&test_app()).await;
+    let fields = json.as_object().unwrap();
+    assert_eq!(fields.len(), camel_case.len());
+    for name in camel_case {
+        assert!(fields.contains_key(name), "missing {name}");
+    }
+
+    let config = crate::Config {
+        json_field_case: crate::FieldCase::SnakeCase,
+        ..test_config()
+    };
+    let json = info_json(&test_app_with_config(&config)).await;
+    let fields = json.as_object().unwrap();
+    assert_eq!(fields.len(), snake_case.len());
+    for name in snake_case {
+        assert!(fields.contains_key(name), "missing {name}");
+    }
+    assert_eq!(json["current_epoch"], json!(EPOCH));
+
+    let config = crate::Config::try_load_from(["star-randsrv", "--json-field-case", "snake-case"])
+        .expect("config should load");
+    assert_eq!(config.json_field_case, crate::FieldCase::SnakeCase);
+}
+
+#[tokio::test]
+async fn info_epoch_seconds() {
+    let config = crate::Config {
+        epoch_seconds: 3600,
+        ..test_config()
+    };
+    let app = test_app_with_config(&config);
+
+    let request = test_request("/info", None);
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let json: Value = serde_json::from_slice(body.as_ref()).unwrap();
+    assert_eq!(json["epochSeconds"], json!(3600));
+}
+
+/// Fetch the number of epochs remaining from /info
+async fn epochs_remaining(app: &crate::Router) -> u64 {
+    let request = test_request("/info", None);
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let json: Value = serde_json::from_slice(body.as_ref()).unwrap();
+    json["epochsRemaining"].as_u64().unwrap()
+}
+
+/// Fetch the current and previous public keys from /info
+async fn info_public_keys(app: &crate::Router) -> (Value, Value) {
+    let request = test_request("/info", None);
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let json: Value = serde_json::from_slice(body.as_ref()).unwrap();
+    (json["publicKey"].clone(), json["previousPublicKey"].clone())
+}
+
+#[tokio::test]
+async fn info_previous_public_key() {
+    let config = crate::Config {
+        first_epoch: 0,
+        last_epoch: 1,
+        retain_previous_key: true,
+        ..test_config()
+    };
+    let server = OPRFServer::new(&config).unwrap();
+    let oprf_state = Arc::new(RwLock::new(server));
+    let app = crate::app(oprf_state.clone(), &config);
+
+    // Nothing to report before the first key rotation.
+    let (original, previous) = info_public_keys(&app).await;
+    assert!(previous.is_null());
+    oprf_state.write().unwrap().advance(&config);
+    assert_eq!(
+        info_public_keys(&app).await,
+        (original.clone(), Value::Null)
+    );
+
+    // Rotating the key retains the old one.
+    oprf_state.write().unwrap().advance(&config);
+    let (current, previous) = info_public_keys(&app).await;
+    assert_ne!(current, original);
+    assert_eq!(previous, original);
+    let binkey = BASE64.decode(previous.as_str().unwrap()).unwrap();
+    ppoprf::ppoprf::ServerPublicKey::load_from_bincode(&binkey)
+        .expect("Could not parse previous public key");
+
+    // The encoded keys are cached with the state.
+    {
+        let s = oprf_state.read().unwrap();
+        let encoded = BASE64.encode(s.server.get_public_key().serialize_to_bincode().unwrap());
+        assert_eq!(s.public_key, encoded);
+        assert_eq!(current, json!(encoded));
+    }
+
+    // Without the option the old key is discarded.
+    let config = crate::Config {
+        retain_previous_key: false,
+        ..config
+    };
+    oprf_state.write().unwrap().rotate_key(&config);
+    let (_, previous) = info_public_keys(&app).await;
+    assert!(previous.is_null());
+}
+
+#[tokio::test]
+async fn info_epochs_remaining() {
+    let config = test_config();
+    let server = OPRFServer::new(&config).unwrap();
+    let oprf_state = Arc::new(RwLock::new(server));
+    let app = crate::app(oprf_state.clone(), &config);
+
+    // Count down through the range of configured epochs.
+    let total = (config.last_epoch - config.first_epoch) as u64;
+    assert_eq!(epochs_remaining(&app).await, total);
+    oprf_state.write().unwrap().advance(&config);
+    assert_eq!(epochs_remaining(&app).await, total - 1);
+    for _ in 1..total {
+        oprf_state.write().unwrap().advance(&config);
+    }
+    assert_eq!(epochs_remaining(&app).await, 0);
+
+    // Advancing past the last epoch rotates the key and starts over.
+    oprf_state.write().unwrap().advance(&config);
+    assert_eq!(epochs_remaining(&app).await, total);
+    assert_eq!(oprf_state.read().unwrap().epoch, config.first_epoch);
+}
+
+/// A fresh key should be warmed up, at startup and on rotation.
+#[test]
+fn warmup() {
+    let config = crate::Config {
+        last_epoch: EPOCH,
+        ..test_config()
+    };
+    let mut server = OPRFServer::new(&config).unwrap();
+    assert!(server.warmup_time.is_some());
+
+    // Running out of epochs rotates the key, which warms up again.
+    let key = server.public_key.clone();
+    server.warmup_time = None;
+    server.advance(&config);
+    assert_ne!(server.public_key, key);
+    assert!(server.warmup_time.is_some());
+}
+
+#[tokio::test]
+async fn openapi() {
+    let app = test_app();
+
+    let request = test_request("/openapi.json", None);
+    let response = app.oneshot(request).await.unwrap();
+
+    // The API description should be well-formed json.
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["Content-Type"], "application/json");
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let json: Value =
+        serde_json::from_slice(body.as_ref()).expect("Could not parse response body as json");
+    assert_eq!(json["info"]["version"], env!("CARGO_PKG_VERSION"));
+    // Every route should be described.
+    let paths = json["paths"].as_object().unwrap();
+    for path in [
+        "/",
+        "/info",
+        "/epoch/{epoch}",
+        "/stats",
+        "/randomness",
+        "/batch",
+        "/verify",
+        "/openapi.json",
+        "/ready",
+        "/admin/drain",
+        "/admin/undrain",
+    ] {
+        assert!(paths.contains_key(path), "{path} missing from openapi.json");
+    }
+    // Response fields should match what the server sends.
+    let info = &json["components"]["schemas"]["InfoResponse"]["properties"];
+    for field in [
+        "publicKey",
+        "currentEpoch",
+        "currentEpochNumber",
+        "nextEpochTime",
+        "serverTime",
+        "epochSeconds",
+        "maxPoints",
+        "epochsRemaining",
+        "ppoprf",
+        "epochKeys",
+        "rotating",
+    ] {
+        assert!(
+            info.get(field).is_some(),
+            "{field} missing from InfoResponse"
+        );
+    }
 }
 
 #[tokio::test]
@@ -148,7 +708,7 @@ async fn epoch() {
     .to_string();
     let request = test_request("/randomness", Some(payload));
     let response = test_app().oneshot(request).await.unwrap();
-    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    verify_error(response, StatusCode::BAD_REQUEST, "epoch_out_of_range").await;
 
     // Verify later epochs are rejected.
     let payload = json!({
@@ -158,51 +718,67 @@ async fn epoch() {
     .to_string();
     let request = test_request("/randomness", Some(payload));
     let response = test_app().oneshot(request).await.unwrap();
-    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    verify_error(response, StatusCode::BAD_REQUEST, "epoch_out_of_range").await;
+
+    // Values which can't be epoch tags at all get their own error.
+    for epoch in [json!(300), json!(-1), json!(1.5)] {
+        let payload = json!({
+            "points": points,
+            "epoch": epoch
+        })
+        .to_string();
+        let request = test_request("/randomness", Some(payload));
+        let response = test_app().oneshot(request).await.unwrap();
+        let json = verify_error(response, StatusCode::BAD_REQUEST, "invalid_epoch").await;
+        assert_eq!(
+            json["error"]["message"],
+            json!(format!(
+                "Epoch {epoch} is not a valid epoch tag, which must be an integer from 0 to 255"
+            ))
+        );
+    }
 }
 
 /// If --epoch-base-time is set, confirm the server starts
 /// with the correct epoch.
-#[tokio::test]
+#[tokio::test(start_paused = true)]
 async fn epoch_base_time() {
-    let now = OffsetDateTime::now_utc();
+    let now = OffsetDateTime::from_unix_timestamp(1684125000).unwrap();
+    let clock = TokioClock {
+        base: now,
+        start: tokio::time::Instant::now(),
+    };
     let delay = Duration::from_secs(5);
 
     // Config with explicit base time
     let config = crate::Config {
-        listen: "127.0.0.1:8081".to_string(),
-        epoch_seconds: 1,
-        first_epoch: EPOCH,
-        last_epoch: EPOCH * 2,
         epoch_base_time: Some(now - delay),
-        increase_nofile_limit: false,
-        prometheus_listen: None,
+        ..test_config()
     };
     // Verify test parameters are compatible with the
     // expected_epoch calculation.
     assert!(EPOCH as u64 + delay.as_secs() < EPOCH as u64 * 2);
     let expected_epoch = EPOCH + delay.as_secs() as u8;
     let advance = Duration::from_secs(config.epoch_seconds.into());
-    let expected_time = (now + advance)
-        // Published timestamp is truncated to the second.
-        .replace_millisecond(0)
-        .expect("should be able to truncate to a fixed ms")
-        .format(&time::format_description::well_known::Rfc3339)
-        .expect("well-known timestamp format should always succeed");
+    // Published timestamp is rounded up to the second.
+    let expected_time = crate::state::format_rotation(now + advance);
 
     // server state
     let server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
     let oprf_state = Arc::new(RwLock::new(server));
     // background task to manage epoch rotation
     let background_state = oprf_state.clone();
-    tokio::spawn(async move { crate::state::epoch_loop(background_state, &config).await });
+    let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    tokio::spawn(async move {
+        crate::state::epoch_loop_with_clock(background_state, &config, shutdown_rx, &clock).await
+    });
 
-    // Wait for `epoch_loop` to update `next_epoch_time` as a proxy
+    // Wait for `epoch_loop` to update `epoch_window` as a proxy
     // for completing epoch schedule initialization. Use a timeout
     // to avoid hanging test runs.
     let pause = Duration::from_millis(10);
     let mut tries = 0;
-    while oprf_state.read().unwrap().next_epoch_time.is_none() {
+    while oprf_state.read().unwrap().epoch_window.is_none() {
         println!("waiting for {pause:?} for initialization {tries}");
         assert!(tries < 10, "timeout waiting for epoch_loop initialization");
         tokio::time::sleep(pause).await;
@@ -210,7 +786,7 @@ async fn epoch_base_time() {
     }
 
     // attach axum routes and middleware
-    let app = crate::app(oprf_state);
+    let app = crate::app(oprf_state, &test_config());
 
     let request = test_request("/info", None);
     let response = app.oneshot(request).await.unwrap();
@@ -229,74 +805,4015 @@ async fn epoch_base_time() {
     assert_eq!(next_epoch_time, expected_time);
 }
 
-/// Check a randomness response body for validity
-fn verify_randomness_body(body: axum::body::Bytes, expected_points: usize) {
-    // Randomness should return a list of points and an epoch.
-    assert!(!body.is_empty());
-    let json: Value =
-        serde_json::from_slice(body.as_ref()).expect("Response body should parse as json");
-    // Top-level value should be an object.
-    assert!(json.is_object());
-    // Epoch should match test_app.
-    let epoch = json["epoch"].as_u64().unwrap();
-    assert_eq!(epoch, EPOCH as u64);
-    // Points array should have the expected number of elements.
-    let points = json["points"].as_array().unwrap();
-    assert_eq!(points.len(), expected_points);
-    // Individual elements should be parseable base64-encoded
-    // compressed Ristretto elliptic curve points.
-    for value in points {
-        let b64point = value.as_str().unwrap();
-        let rawpoint = BASE64.decode(b64point).unwrap();
-        let _ = CompressedRistretto::from_slice(&rawpoint);
-    }
-}
+/// Base times too far back for the schedule to count the epochs
+/// since should be rejected by validation rather than panicking.
+#[test]
+fn distant_epoch_base_time() {
+    // About 1.8e9 one second epochs have passed since 1970, which
+    // still fits.
+    let config = crate::Config {
+        epoch_base_time: Some(OffsetDateTime::UNIX_EPOCH),
+        ..test_config()
+    };
+    config.validate().expect("1970 base time should be valid");
+    let schedule = crate::state::Schedule::new(&config, OffsetDateTime::UNIX_EPOCH);
+    let (epoch, end) = schedule.at(OffsetDateTime::now_utc()).unwrap();
+    assert!((EPOCH..=EPOCH * 2).contains(&epoch));
+    assert!(end > OffsetDateTime::now_utc());
 
-/// Generate a number of random base64-encoded points.
-fn make_points(count: usize) -> Vec<String> {
-    let mut points = Vec::with_capacity(count);
-    for _ in 0..count {
-        let point = RistrettoPoint::random(&mut OsRng);
-        let b64point = BASE64.encode(point.compress().as_bytes());
-        points.push(b64point);
-    }
-    points
+    // Around 1874, more than u32::MAX have.
+    let base_time = OffsetDateTime::from_unix_timestamp(-3_000_000_000).unwrap();
+    let config = crate::Config {
+        epoch_base_time: Some(base_time),
+        ..test_config()
+    };
+    let e = config.validate().unwrap_err();
+    assert!(e.to_string().contains("too far in the past"), "{e}");
+
+    // Longer epochs bring it back in range.
+    let config = crate::Config {
+        epoch_seconds: 2,
+        ..config
+    };
+    config.validate().expect("longer epochs should be valid");
 }
 
-/// Verify randomness response to a batch of points
-async fn verify_batch(points: &[String]) {
-    let app = test_app();
-    let payload = json!({ "points": points }).to_string();
+/// Replicas which don't rotate should report the epoch given
+/// by the clock and base time.
+#[tokio::test]
+async fn no_rotate() {
+    let delay = Duration::from_secs(5);
+    let config = crate::Config {
+        no_rotate: true,
+        epoch_base_time: Some(OffsetDateTime::now_utc() - delay),
+        ..test_config()
+    };
+    config.validate().expect("replica config should be valid");
+    assert!(EPOCH as u64 + delay.as_secs() < EPOCH as u64 * 2);
+    let expected_epoch = EPOCH + delay.as_secs() as u8;
+
+    // No epoch_loop is running, so the state itself never advances.
+    let server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+    assert_eq!(server.epoch, EPOCH);
+    let app = crate::app(Arc::new(RwLock::new(server)), &config);
+
+    let request = test_request("/info", None);
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let json: Value = serde_json::from_slice(body.as_ref()).unwrap();
+    assert_eq!(json["currentEpoch"], json!(expected_epoch));
+    assert!(json["nextEpochTime"].is_string());
+
+    // Evaluations use the clock-derived epoch too.
+    let payload = json!({
+        "points": make_points(1),
+        "epoch": expected_epoch
+    })
+    .to_string();
     let request = test_request("/randomness", Some(payload));
     let response = app.oneshot(request).await.unwrap();
     assert_eq!(response.status(), StatusCode::OK);
-    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
-    verify_randomness_body(body, points.len());
+
+    // Replicas can't follow a schedule without a base time.
+    let unanchored = crate::Config {
+        no_rotate: true,
+        ..test_config()
+    };
+    assert!(unanchored.validate().is_err());
 }
 
+/// Replicas should refuse requests rather than panic when the
+/// clock is before the base time.
 #[tokio::test]
-async fn point_batches() {
-    // Check that we can submit multiple points.
-    let points = make_points(5);
-    verify_batch(&points).await;
+async fn no_rotate_before_base_time() {
+    let base_time = OffsetDateTime::now_utc() + Duration::from_secs(3600);
+    let config = crate::Config {
+        no_rotate: true,
+        epoch_base_time: Some(base_time),
+        ..test_config()
+    };
+    let e = config.validate().unwrap_err();
+    assert!(e.to_string().contains("must not be in the future"), "{e}");
 
-    // Check that we can submit a reasonable number of points.
-    let points = make_points(128);
-    assert!(points.len() < crate::MAX_POINTS);
-    verify_batch(&points).await;
+    let schedule = crate::state::Schedule::new(&config, base_time);
+    assert_eq!(schedule.at(OffsetDateTime::now_utc()), None);
+    assert_eq!(schedule.window(OffsetDateTime::now_utc()), None);
+
+    // The clock can still step back past a valid base time later,
+    // which this stands in for.
+    let server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+    assert!(!server.started());
+    assert!(!server.accepts(EPOCH));
+    let app = crate::app(Arc::new(RwLock::new(server)), &config);
+
+    let payload = json!({ "points": make_points(1) }).to_string();
+    for (uri, body) in [
+        ("/info", None),
+        ("/epoch/12", None),
+        ("/ready", None),
+        ("/randomness", Some(payload.clone())),
+        ("/batch", Some(payload)),
+    ] {
+        let response = app.clone().oneshot(test_request(uri, body)).await.unwrap();
+        verify_error(
+            response,
+            StatusCode::SERVICE_UNAVAILABLE,
+            "schedule_not_started",
+        )
+        .await;
+    }
+
+    // The PEM form of `/info` is refused too.
+    let request = Request::builder()
+        .uri("/info")
+        .header("Accept", "application/x-pem-file")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    verify_error(
+        response,
+        StatusCode::SERVICE_UNAVAILABLE,
+        "schedule_not_started",
+    )
+    .await;
 }
 
+/// Replicas should stop evaluating after one pass through the
+/// epochs, rather than reuse tags under a key which is never
+/// punctured.
 #[tokio::test]
-async fn max_points() {
-    // Check that we can submit the maximum number of points.
-    let points = make_points(crate::MAX_POINTS);
-    verify_batch(&points).await;
+async fn no_rotate_one_pass() {
+    let now = OffsetDateTime::now_utc();
+    let base_time = now - Duration::from_secs(1000);
+    let config = crate::Config {
+        no_rotate: true,
+        epoch_base_time: Some(base_time),
+        ..test_config()
+    };
+    let interval = Duration::from_secs(config.epoch_seconds.into());
+    let epochs = config.epoch_list().len() as u32;
 
-    // Requests with more than the maximum number of points
-    // should be rejected.
-    let points = make_points(crate::MAX_POINTS + 1);
-    let payload = json!({ "points": points }).to_string();
-    let request = test_request("/randomness", Some(payload));
-    let response = test_app().oneshot(request).await.unwrap();
-    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    // The pass starts from the epoch the key was created in, not
+    // the base time, so it covers each tag exactly once.
+    let start = base_time + interval * 5 / 2;
+    let schedule = crate::state::Schedule::new(&config, base_time).one_pass(start);
+    assert!(!schedule.ended(start));
+    assert_eq!(schedule.remaining(start), Some(epochs as u8 - 1));
+    let last = base_time + interval * (2 * epochs + 3) / 2;
+    assert!(!schedule.ended(last));
+    assert_eq!(schedule.remaining(last), Some(0));
+    assert!(schedule.ended(last + interval));
+
+    // Once it ends, evaluations and readiness fail.
+    let mut server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+    server.schedule = Some(crate::state::Schedule::new(&config, base_time).one_pass(base_time));
+    assert!(server.exhausted());
+    let app = crate::app(Arc::new(RwLock::new(server)), &config);
+    let payload = json!({ "points": make_points(1) }).to_string();
+    for (uri, body) in [
+        ("/ready", None),
+        ("/randomness", Some(payload.clone())),
+        ("/batch", Some(payload)),
+    ] {
+        let response = app.clone().oneshot(test_request(uri, body)).await.unwrap();
+        verify_error(
+            response,
+            StatusCode::SERVICE_UNAVAILABLE,
+            "epochs_exhausted",
+        )
+        .await;
+    }
+}
+
+/// Clock which follows tokio's timer, so paused time applies
+#[derive(Clone)]
+struct TokioClock {
+    /// Wall-clock time at `start`
+    base: OffsetDateTime,
+    start: tokio::time::Instant,
+}
+
+impl crate::state::Clock for TokioClock {
+    fn now(&self) -> OffsetDateTime {
+        self.base + self.start.elapsed()
+    }
+}
+
+/// Fast-forward through several rotations without waiting.
+#[tokio::test(start_paused = true)]
+async fn epoch_fast_forward() {
+    let base_time = OffsetDateTime::from_unix_timestamp(1684125000).unwrap();
+    let config = crate::Config {
+        epoch_seconds: 3600,
+        epoch_base_time: Some(base_time),
+        ..test_config()
+    };
+    let interval = Duration::from_secs(config.epoch_seconds.into());
+    let clock = TokioClock {
+        base: base_time + interval / 2,
+        start: tokio::time::Instant::now(),
+    };
+
+    let server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+    let oprf_state = Arc::new(RwLock::new(server));
+    let background_state = oprf_state.clone();
+    let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let loop_config = config.clone();
+    tokio::spawn(async move {
+        crate::state::epoch_loop_with_clock(background_state, &loop_config, shutdown_rx, &clock)
+            .await
+    });
+
+    let advanced = metric_value(crate::telemetry::EPOCHS_ADVANCED);
+
+    // Sleeping lets the paused clock jump straight to each rotation.
+    tokio::time::sleep(Duration::from_millis(1)).await;
+    assert_eq!(oprf_state.read().unwrap().epoch, EPOCH);
+    for n in 1..=5u8 {
+        tokio::time::sleep(interval).await;
+        let s = oprf_state.read().unwrap();
+        assert_eq!(s.epoch, EPOCH + n);
+        let expected = (base_time + interval * (n as u32 + 1))
+            .format(&time::format_description::well_known::Rfc3339)
+            .unwrap();
+        assert_eq!(s.next_epoch_time().as_deref(), Some(expected.as_str()));
+    }
+    // Each rotation is counted.
+    assert!(metric_value(crate::telemetry::EPOCHS_ADVANCED) >= advanced + 5.0);
+}
+
+/// Run the epoch loop through thousands of rotations, including
+/// many key rotations, checking the state and `/info` at each one.
+///
+/// Unoptimized builds take over a minute, so this only runs when
+/// asked for with `cargo test --release soak -- --ignored`.
+#[tokio::test(start_paused = true)]
+#[ignore]
+async fn soak() {
+    const ROTATIONS: u32 = 5000;
+    let base_time = OffsetDateTime::from_unix_timestamp(1684125000).unwrap();
+    let config = crate::Config {
+        epoch_seconds: 3600,
+        epoch_grace_seconds: 60,
+        last_epoch: EPOCH + 6,
+        epoch_base_time: Some(base_time),
+        ..test_config()
+    };
+    let interval = Duration::from_secs(config.epoch_seconds.into());
+    let epochs = config.epoch_list();
+    let clock = TokioClock {
+        base: base_time + interval / 2,
+        start: tokio::time::Instant::now(),
+    };
+
+    let server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+    let oprf_state = Arc::new(RwLock::new(server));
+    let app = crate::app(oprf_state.clone(), &config);
+    let background_state = oprf_state.clone();
+    let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let loop_config = config.clone();
+    let task = tokio::spawn(async move {
+        crate::state::epoch_loop_with_clock(background_state, &loop_config, shutdown_rx, &clock)
+            .await
+    });
+
+    tokio::time::sleep(Duration::from_millis(1)).await;
+    let mut public_key = info_json(&app).await["publicKey"].clone();
+    let mut key_rotations = 0;
+    for n in 1..=ROTATIONS {
+        tokio::time::sleep(interval).await;
+        assert!(!task.is_finished(), "epoch loop stopped at rotation {n}");
+        let position = n as usize % epochs.len();
+        let epoch = epochs[position];
+        {
+            let s = oprf_state.read().unwrap();
+            assert_eq!(s.epoch, epoch, "rotation {n}");
+            assert_eq!(s.epoch_number, u64::from(n));
+            assert_eq!(s.punctured.len(), position, "rotation {n}");
+        }
+
+        let json = info_json(&app).await;
+        assert_eq!(json["currentEpoch"], json!(epoch));
+        assert_eq!(json["currentEpochNumber"], json!(n));
+        assert_eq!(json["epochsRemaining"], json!(epochs.len() - 1 - position));
+        let next = crate::state::format_rotation(base_time + interval * (n + 1));
+        assert_eq!(json["nextEpochTime"], json!(next));
+        // The key changes exactly when the epochs run out.
+        if position == 0 {
+            assert_ne!(json["publicKey"], public_key, "rotation {n}");
+            public_key = json["publicKey"].clone();
+            key_rotations += 1;
+        } else {
+            assert_eq!(json["publicKey"], public_key, "rotation {n}");
+        }
+        assert_eq!(json["epochKeys"][0]["epoch"], json!(epoch));
+        assert_eq!(json["rotating"], json!(false));
+    }
+    assert_eq!(key_rotations, ROTATIONS / epochs.len() as u32);
+}
+
+/// Clock which notes whether a rotation is flagged each time it's read
+#[derive(Clone)]
+struct RotationClock {
+    inner: TokioClock,
+    rotating: crate::state::Rotating,
+    seen: Arc<std::sync::Mutex<Vec<bool>>>,
+}
+
+impl crate::state::Clock for RotationClock {
+    fn now(&self) -> OffsetDateTime {
+        self.seen.lock().unwrap().push(self.rotating.get());
+        self.inner.now()
+    }
+}
+
+/// `/info` should report when the epoch is being advanced.
+#[tokio::test(start_paused = true)]
+async fn info_rotating() {
+    let base_time = OffsetDateTime::from_unix_timestamp(1684125000).unwrap();
+    let config = crate::Config {
+        epoch_seconds: 3600,
+        epoch_base_time: Some(base_time),
+        ..test_config()
+    };
+    let interval = Duration::from_secs(config.epoch_seconds.into());
+    let server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+    let rotating = server.rotating.clone();
+    let oprf_state = Arc::new(RwLock::new(server));
+    let app = crate::app(oprf_state.clone(), &config);
+    let clock = RotationClock {
+        inner: TokioClock {
+            base: base_time,
+            start: tokio::time::Instant::now(),
+        },
+        rotating: rotating.clone(),
+        seen: Default::default(),
+    };
+    let seen = clock.seen.clone();
+    let background_state = oprf_state.clone();
+    let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let loop_config = config.clone();
+    tokio::spawn(async move {
+        crate::state::epoch_loop_with_clock(background_state, &loop_config, shutdown_rx, &clock)
+            .await
+    });
+
+    tokio::time::sleep(Duration::from_millis(1)).await;
+    assert!(!seen.lock().unwrap().contains(&true));
+    assert_eq!(info_json(&app).await["rotating"], json!(false));
+
+    // The clock is read while the rotation holds the lock, and
+    // the flag is cleared once it's done.
+    tokio::time::sleep(interval).await;
+    assert_eq!(oprf_state.read().unwrap().epoch, EPOCH + 1);
+    assert!(seen.lock().unwrap().contains(&true));
+    assert!(!rotating.get());
+    assert_eq!(info_json(&app).await["rotating"], json!(false));
+
+    // A rotation in progress is reported.
+    let _guard = rotating.begin();
+    assert_eq!(info_json(&app).await["rotating"], json!(true));
+}
+
+/// The public key should be available as PEM, and read back to
+/// the same key as `/info` reports.
+#[tokio::test]
+async fn info_pem() {
+    let app = test_app();
+    let request = Request::builder()
+        .uri("/info")
+        .header("Accept", "application/x-pem-file")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["Content-Type"], "application/x-pem-file");
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let pem = std::str::from_utf8(&body).unwrap();
+    assert!(pem.starts_with("-----BEGIN PPOPRF PUBLIC KEY-----\n"));
+    assert!(pem.lines().all(|line| line.len() <= 64));
+
+    let bytes = crate::pem::decode(pem).expect("PEM should decode");
+    let key = ppoprf::ppoprf::ServerPublicKey::load_from_bincode(&bytes).unwrap();
+    assert_eq!(key.serialize_to_bincode().unwrap(), bytes);
+    let json = info_json(&app).await;
+    let expected = BASE64.decode(json["publicKey"].as_str().unwrap()).unwrap();
+    assert_eq!(bytes, expected);
+
+    // Truncated armor is rejected.
+    let (head, _) = pem.split_once("-----END").unwrap();
+    assert!(matches!(
+        crate::pem::decode(head),
+        Err(crate::pem::Error::MissingEnd)
+    ));
+    assert!(matches!(
+        crate::pem::decode("AAAA"),
+        Err(crate::pem::Error::MissingBegin)
+    ));
+}
+
+/// Batch responses should carry info describing the epoch their
+/// points were evaluated in.
+#[tokio::test]
+async fn batch() {
+    let app = test_app();
+    let payload = json!({ "points": make_points(2), "tags": ["a", "b"] }).to_string();
+    let request = test_request("/batch", Some(payload));
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let batch: crate::BatchResponse = serde_json::from_slice(&body).unwrap();
+    assert_eq!(batch.randomness.points.len(), 2);
+    assert_eq!(batch.randomness.tags, Some(vec![json!("a"), json!("b")]));
+    assert_eq!(batch.randomness.epoch, batch.info.current_epoch);
+    assert_eq!(
+        batch.randomness.epoch_number,
+        batch.info.current_epoch_number
+    );
+    assert_eq!(batch.info.epoch_keys[0].epoch, batch.randomness.epoch);
+    assert_eq!(batch.info.public_key, info_json(&app).await["publicKey"]);
+
+    // Requests are validated as for /randomness.
+    let payload = json!({ "points": [], "epoch": EPOCH }).to_string();
+    let request = test_request("/batch", Some(payload));
+    let response = app.clone().oneshot(request).await.unwrap();
+    verify_error(response, StatusCode::BAD_REQUEST, "no_points").await;
+
+    // The info follows the configured field case.
+    let config = crate::Config {
+        json_field_case: crate::FieldCase::SnakeCase,
+        ..test_config()
+    };
+    let payload = json!({ "points": make_points(1) }).to_string();
+    let request = test_request("/batch", Some(payload));
+    let response = test_app_with_config(&config)
+        .oneshot(request)
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["info"]["current_epoch"], json["randomness"]["epoch"]);
+    assert!(json["randomness"]["epochNumber"].is_u64());
+}
+
+/// The published epoch window should follow `epoch_loop`'s
+/// rotations, including one cut short by a scheduled key rotation.
+#[tokio::test(start_paused = true)]
+async fn epoch_window() {
+    let base_time = OffsetDateTime::from_unix_timestamp(1684125000).unwrap();
+    let interval = Duration::from_secs(3600);
+    let config = crate::Config {
+        epoch_seconds: 3600,
+        epoch_base_time: Some(base_time),
+        rotate_key_at: Some(base_time + interval * 15 / 2),
+        ..test_config()
+    };
+    // Start half way through the sixth epoch since the base time.
+    let clock = TokioClock {
+        base: base_time + interval * 11 / 2,
+        start: tokio::time::Instant::now(),
+    };
+
+    let server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+    let oprf_state = Arc::new(RwLock::new(server));
+    let app = crate::app(oprf_state.clone(), &config);
+    let background_state = oprf_state.clone();
+    let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let loop_config = config.clone();
+    tokio::spawn(async move {
+        crate::state::epoch_loop_with_clock(background_state, &loop_config, shutdown_rx, &clock)
+            .await
+    });
+
+    let at = |halves: u32| base_time + interval * halves / 2;
+    let steps = [
+        (Duration::from_millis(1), at(10), at(12)),
+        (interval * 3 / 4, at(12), at(14)),
+        // The scheduled rotation ends this epoch early...
+        (interval, at(14), at(15)),
+        // ...and the next one starts from it.
+        (interval / 2, at(15), at(17)),
+    ];
+    for (delay, start, end) in steps {
+        tokio::time::sleep(delay).await;
+        let window = oprf_state.read().unwrap().current_window();
+        assert_eq!(window, Some((start, end)));
+        let json = info_json(&app).await;
+        assert_eq!(
+            json["nextEpochTime"],
+            json!(crate::state::format_rotation(end))
+        );
+    }
+
+    // Replicas derive the same window from the clock.
+    let schedule = crate::state::Schedule::new(&config, base_time);
+    assert_eq!(schedule.window(at(11)), Some((at(10), at(12))));
+}
+
+/// Randomness responses should report the time left until the
+/// `nextEpochTime` published by `/info`.
+#[tokio::test]
+async fn epoch_time_remaining() {
+    let now = OffsetDateTime::now_utc();
+    let config = crate::Config {
+        epoch_seconds: 3600,
+        epoch_base_time: Some(now - Duration::from_secs(1000)),
+        no_rotate: true,
+        ..test_config()
+    };
+    let app = test_app_with_config(&config);
+
+    let payload = json!({ "points": make_points(1) }).to_string();
+    let request = test_request("/randomness", Some(payload));
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    let remaining = json["epochTimeRemainingSeconds"].as_u64().unwrap();
+    assert!(remaining > 0 && remaining <= 2600, "{remaining}");
+
+    let json = info_json(&app).await;
+    let next = OffsetDateTime::parse(
+        json["nextEpochTime"].as_str().unwrap(),
+        &time::format_description::well_known::Rfc3339,
+    )
+    .unwrap();
+    let until_next = (next - now).whole_seconds();
+    assert!((until_next - remaining as i64).abs() <= 2, "{until_next}");
+}
+
+/// `/info` should be cacheable until shortly before `nextEpochTime`,
+/// while evaluations are never cached.
+#[tokio::test]
+async fn cache_headers() {
+    let now = OffsetDateTime::now_utc().replace_nanosecond(0).unwrap();
+    let config = crate::Config {
+        epoch_seconds: 3600,
+        epoch_base_time: Some(now - Duration::from_secs(1000)),
+        no_rotate: true,
+        info_cache_margin_seconds: 10,
+        ..test_config()
+    };
+    let app = test_app_with_config(&config);
+
+    let request = test_request("/info", None);
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let headers = response.headers().clone();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    let next = OffsetDateTime::parse(
+        json["nextEpochTime"].as_str().unwrap(),
+        &time::format_description::well_known::Rfc3339,
+    )
+    .unwrap();
+    let expires = next - Duration::from_secs(10);
+    assert_eq!(
+        headers["Expires"],
+        httpdate::fmt_http_date(expires.into()).as_str()
+    );
+    let cache_control = headers["Cache-Control"].to_str().unwrap();
+    let max_age: i64 = cache_control
+        .strip_prefix("public, max-age=")
+        .expect("info should be cacheable")
+        .parse()
+        .unwrap();
+    assert!(
+        (max_age - (expires - now).whole_seconds()).abs() <= 2,
+        "{cache_control}"
+    );
+
+    // Once the next epoch is due, responses shouldn't be reused.
+    let request = test_request("/info", None);
+    let response = test_app().oneshot(request).await.unwrap();
+    assert_eq!(response.headers()["Cache-Control"], "no-cache");
+    assert!(response.headers().get("Expires").is_none());
+
+    // Evaluations, and failures, are never stored.
+    let payload = json!({ "points": make_points(1) }).to_string();
+    for payload in [payload.as_str(), "{"] {
+        for path in ["/randomness", "/batch"] {
+            let request = test_request(path, Some(payload.to_owned()));
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.headers()["Cache-Control"], "no-store");
+        }
+    }
+}
+
+/// A standby key should be prepared before epochs run out, then
+/// swapped in by the rotation.
+#[tokio::test(start_paused = true)]
+async fn standby_key() {
+    let base_time = OffsetDateTime::from_unix_timestamp(1684125000).unwrap();
+    let config = crate::Config {
+        epoch_seconds: 3600,
+        last_epoch: EPOCH + 1,
+        epoch_base_time: Some(base_time),
+        ..test_config()
+    };
+    let interval = Duration::from_secs(config.epoch_seconds.into());
+    let clock = TokioClock {
+        base: base_time,
+        start: tokio::time::Instant::now(),
+    };
+
+    let server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+    let oprf_state = Arc::new(RwLock::new(server));
+    let background_state = oprf_state.clone();
+    let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let loop_config = config.clone();
+    tokio::spawn(async move {
+        crate::state::epoch_loop_with_clock(background_state, &loop_config, shutdown_rx, &clock)
+            .await
+    });
+
+    // Nothing is prepared while epochs remain.
+    tokio::time::sleep(Duration::from_millis(1)).await;
+    assert!(oprf_state.read().unwrap().standby.is_none());
+
+    // The key is generated on a blocking thread, which paused
+    // time doesn't wait for.
+    tokio::time::sleep(interval).await;
+    let mut standby = None;
+    for _ in 0..100 {
+        standby = oprf_state
+            .read()
+            .unwrap()
+            .standby
+            .as_ref()
+            .map(|key| key.public_key.clone());
+        if standby.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let standby = standby.expect("standby key should be generated in the last epoch");
+    assert_eq!(oprf_state.read().unwrap().epoch, EPOCH + 1);
+    assert_ne!(oprf_state.read().unwrap().public_key, standby);
+
+    // Running out of epochs swaps it in.
+    tokio::time::sleep(interval).await;
+    {
+        let s = oprf_state.read().unwrap();
+        assert_eq!(s.epoch, EPOCH);
+        assert_eq!(s.public_key, standby);
+        assert!(s.standby.is_none());
+    }
+
+    // A standby for other epochs isn't used.
+    let mut server = OPRFServer::new(&config).unwrap();
+    let other = crate::Config {
+        last_epoch: EPOCH + 2,
+        ..config.clone()
+    };
+    let key = crate::state::OPRFKey::generate(&other).unwrap();
+    let standby = key.public_key.clone();
+    server.standby = Some(key);
+    server.rotate_key(&config);
+    assert_ne!(server.public_key, standby);
+    assert!(server.standby.is_none());
+}
+
+/// Absolute epoch numbers should keep increasing while the epoch
+/// tag wraps around at key rotations.
+#[tokio::test(start_paused = true)]
+async fn epoch_number() {
+    let base_time = OffsetDateTime::from_unix_timestamp(1684125000).unwrap();
+    let interval = Duration::from_secs(3600);
+    let config = crate::Config {
+        epoch_seconds: 3600,
+        last_epoch: EPOCH + 2,
+        epoch_base_time: Some(base_time),
+        rotate_key_at: Some(base_time + interval * 15 / 2),
+        ..test_config()
+    };
+    // Start half way through the sixth epoch since the base time.
+    let clock = TokioClock {
+        base: base_time + interval * 11 / 2,
+        start: tokio::time::Instant::now(),
+    };
+
+    let server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+    let oprf_state = Arc::new(RwLock::new(server));
+    let app = crate::app(oprf_state.clone(), &config);
+    let background_state = oprf_state.clone();
+    let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let loop_config = config.clone();
+    tokio::spawn(async move {
+        crate::state::epoch_loop_with_clock(background_state, &loop_config, shutdown_rx, &clock)
+            .await
+    });
+
+    // Epochs run out after the first, then the scheduled rotation
+    // interrupts the eighth. The tag starts over both times.
+    let steps = [
+        (Duration::from_millis(1), EPOCH + 2, 5),
+        (interval * 3 / 4, EPOCH, 6),
+        (interval, EPOCH + 1, 7),
+        (interval / 2, EPOCH, 8),
+        (interval, EPOCH + 1, 9),
+    ];
+    for (delay, epoch, number) in steps {
+        tokio::time::sleep(delay).await;
+        {
+            let s = oprf_state.read().unwrap();
+            assert_eq!(s.epoch, epoch);
+            assert_eq!(s.epoch_number, number);
+        }
+
+        let response = app
+            .clone()
+            .oneshot(test_request("/info", None))
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["currentEpoch"], json!(epoch));
+        assert_eq!(json["currentEpochNumber"], json!(number));
+
+        let payload = json!({ "points": make_points(1) }).to_string();
+        let request = test_request("/randomness", Some(payload));
+        let response = app.clone().oneshot(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["epoch"], json!(epoch));
+        assert_eq!(json["epochNumber"], json!(number));
+    }
+
+    // Replicas number epochs from the same base time.
+    let replica = crate::Config {
+        no_rotate: true,
+        epoch_base_time: Some(OffsetDateTime::now_utc() - interval * 21 / 2),
+        rotate_key_at: None,
+        ..config
+    };
+    let server = OPRFServer::new(&replica).expect("Could not initialize PPOPRF state");
+    let current = server.current_epoch();
+    assert_eq!(current, EPOCH + 1);
+    assert_eq!(server.epoch_number(current), 10);
+}
+
+/// Request randomness in a specific epoch, returning the status
+async fn epoch_request_status(app: &crate::Router, epoch: u8) -> StatusCode {
+    let payload = json!({ "points": make_points(1), "epoch": epoch }).to_string();
+    let request = test_request("/randomness", Some(payload));
+    app.clone().oneshot(request).await.unwrap().status()
+}
+
+/// The previous epoch should stay usable during its grace period.
+#[tokio::test(start_paused = true)]
+async fn epoch_grace() {
+    let base_time = OffsetDateTime::from_unix_timestamp(1684125000).unwrap();
+    let config = crate::Config {
+        epoch_seconds: 3600,
+        epoch_grace_seconds: 60,
+        epoch_base_time: Some(base_time),
+        ..test_config()
+    };
+    config.validate().expect("grace period should be valid");
+    let interval = Duration::from_secs(config.epoch_seconds.into());
+    let grace = Duration::from_secs(config.epoch_grace_seconds.into());
+    let clock = TokioClock {
+        base: base_time + interval / 2,
+        start: tokio::time::Instant::now(),
+    };
+
+    let server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+    let oprf_state = Arc::new(RwLock::new(server));
+    let app = crate::app(oprf_state.clone(), &config);
+    let background_state = oprf_state.clone();
+    let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let loop_config = config.clone();
+    tokio::spawn(async move {
+        crate::state::epoch_loop_with_clock(background_state, &loop_config, shutdown_rx, &clock)
+            .await
+    });
+    tokio::time::sleep(Duration::from_millis(1)).await;
+    assert_eq!(epoch_request_status(&app, EPOCH).await, StatusCode::OK);
+    assert_eq!(
+        epoch_request_status(&app, EPOCH + 1).await,
+        StatusCode::BAD_REQUEST
+    );
+
+    // Just after rotation both epochs are accepted.
+    tokio::time::sleep(interval / 2 + Duration::from_secs(1)).await;
+    assert_eq!(oprf_state.read().unwrap().epoch, EPOCH + 1);
+    assert_eq!(epoch_request_status(&app, EPOCH).await, StatusCode::OK);
+    assert_eq!(epoch_request_status(&app, EPOCH + 1).await, StatusCode::OK);
+
+    // Once the grace period is over the old epoch is punctured.
+    tokio::time::sleep(grace).await;
+    assert_eq!(
+        epoch_request_status(&app, EPOCH).await,
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(epoch_request_status(&app, EPOCH + 1).await, StatusCode::OK);
+    let s = oprf_state.read().unwrap();
+    assert!(s.grace_epoch.is_none());
+    let point = ppoprf::ppoprf::Point::from(BASE64.decode(&make_points(1)[0]).unwrap().as_slice());
+    assert!(s.server.eval(&point, EPOCH, false).is_err());
+
+    // The grace period must be shorter than an epoch.
+    let invalid = crate::Config {
+        epoch_grace_seconds: config.epoch_seconds,
+        ..config.clone()
+    };
+    assert!(invalid.validate().is_err());
+}
+
+/// Query the epoch endpoint, returning the validity and reason
+async fn epoch_status(app: &crate::Router, epoch: &str) -> (bool, String) {
+    let request = test_request(&format!("/epoch/{epoch}"), None);
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["epoch"].to_string(), epoch);
+    let reason = json["reason"].as_str().unwrap().to_owned();
+    (json["valid"].as_bool().unwrap(), reason)
+}
+
+#[tokio::test]
+async fn epoch_validity() {
+    let config = crate::Config {
+        epoch_seconds: 3600,
+        epoch_grace_seconds: 60,
+        ..test_config()
+    };
+    let server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+    let oprf_state = Arc::new(RwLock::new(server));
+    let app = crate::app(oprf_state.clone(), &config);
+    let current = EPOCH.to_string();
+    let next = (EPOCH + 1).to_string();
+    let last = (EPOCH + 2).to_string();
+    assert_eq!(epoch_status(&app, &current).await, (true, "current".into()));
+    assert_eq!(epoch_status(&app, &next).await, (false, "future".into()));
+
+    // Tags outside the configured range, or which aren't tags at all.
+    for epoch in ["0", &(EPOCH * 2 + 1).to_string(), "300"] {
+        assert_eq!(
+            epoch_status(&app, epoch).await,
+            (false, "out_of_range".into())
+        );
+    }
+
+    // The previous epoch is valid until its grace period ends.
+    oprf_state.write().unwrap().advance(&config);
+    assert_eq!(epoch_status(&app, &current).await, (true, "grace".into()));
+    assert_eq!(epoch_status(&app, &next).await, (true, "current".into()));
+    oprf_state.write().unwrap().advance(&config);
+    assert_eq!(
+        epoch_status(&app, &current).await,
+        (false, "punctured".into())
+    );
+    assert_eq!(epoch_status(&app, &next).await, (true, "grace".into()));
+    assert_eq!(epoch_status(&app, &last).await, (true, "current".into()));
+
+    // A grace epoch beyond the allowed lag isn't punctured, but
+    // can't be used either.
+    oprf_state.write().unwrap().max_epoch_lag = Some(0);
+    assert_eq!(epoch_status(&app, &next).await, (false, "expired".into()));
+
+    let request = test_request("/epoch/soon", None);
+    let response = app.oneshot(request).await.unwrap();
+    verify_error(response, StatusCode::BAD_REQUEST, "invalid_request").await;
+}
+
+/// An explicit epoch list needn't be a contiguous range.
+#[tokio::test]
+async fn explicit_epochs() {
+    let config = crate::Config::try_load_from(["star-randsrv", "--epochs", "12,13,15,20"])
+        .expect("epoch list should parse");
+    assert_eq!(config.epochs, Some(vec![12, 13, 15, 20]));
+    let config = crate::Config {
+        epoch_grace_seconds: 0,
+        epoch_seconds: 3600,
+        ..config
+    };
+    let server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+    let oprf_state = Arc::new(RwLock::new(server));
+    let app = crate::app(oprf_state.clone(), &config);
+
+    // Gaps in the list are out of range, and later epochs are
+    // in the future whatever their tag.
+    assert_eq!(epoch_status(&app, "12").await, (true, "current".into()));
+    assert_eq!(
+        epoch_status(&app, "14").await,
+        (false, "out_of_range".into())
+    );
+    assert_eq!(epoch_status(&app, "20").await, (false, "future".into()));
+    assert_eq!(epochs_remaining(&app).await, 3);
+
+    // Advancing steps through the list in order, then rotates the
+    // key and starts over.
+    let original = oprf_state.read().unwrap().public_key.clone();
+    for (epoch, remaining) in [(13, 2), (15, 1), (20, 0), (12, 3)] {
+        oprf_state.write().unwrap().advance(&config);
+        assert_eq!(oprf_state.read().unwrap().epoch, epoch);
+        assert_eq!(epochs_remaining(&app).await, remaining);
+
+        let payload = json!({ "points": make_points(1) }).to_string();
+        let request = test_request("/randomness", Some(payload));
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["epoch"], json!(epoch));
+    }
+    assert_ne!(oprf_state.read().unwrap().public_key, original);
+
+    // Empty lists and repeated tags are rejected.
+    for epochs in [vec![], vec![12, 13, 12]] {
+        let invalid = crate::Config {
+            epochs: Some(epochs),
+            ..config.clone()
+        };
+        assert!(invalid.validate().is_err());
+    }
+}
+
+/// The epoch loop should rotate through an explicit epoch list,
+/// puncturing the epochs skipped to match the base time.
+#[tokio::test(start_paused = true)]
+async fn explicit_epochs_rotation() {
+    let base_time = OffsetDateTime::from_unix_timestamp(1684125000).unwrap();
+    let interval = Duration::from_secs(3600);
+    let config = crate::Config {
+        epoch_seconds: 3600,
+        epochs: Some(vec![40, 7, 9, 200]),
+        epoch_base_time: Some(base_time),
+        ..test_config()
+    };
+    // Start half way through the second epoch.
+    let clock = TokioClock {
+        base: base_time + interval * 3 / 2,
+        start: tokio::time::Instant::now(),
+    };
+
+    let server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+    let oprf_state = Arc::new(RwLock::new(server));
+    let background_state = oprf_state.clone();
+    let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let loop_config = config.clone();
+    tokio::spawn(async move {
+        crate::state::epoch_loop_with_clock(background_state, &loop_config, shutdown_rx, &clock)
+            .await
+    });
+
+    tokio::time::sleep(Duration::from_millis(1)).await;
+    {
+        let s = oprf_state.read().unwrap();
+        assert_eq!(s.epoch, 7);
+        assert_eq!(s.epoch_number, 1);
+        assert_eq!(s.punctured, [40].into());
+    }
+    for (n, epoch) in [9, 200, 40, 7].into_iter().enumerate() {
+        tokio::time::sleep(interval).await;
+        let s = oprf_state.read().unwrap();
+        assert_eq!(s.epoch, epoch);
+        assert_eq!(s.epoch_number, n as u64 + 2);
+    }
+
+    // Replicas derive the same sequence from the clock.
+    let schedule = crate::state::Schedule::new(&config, base_time);
+    let epochs: Vec<u8> = (0..6)
+        .map(|n| schedule.at(base_time + interval * n).unwrap().0)
+        .collect();
+    assert_eq!(epochs, [40, 7, 9, 200, 40, 7]);
+}
+
+/// Requests for punctured epochs should be counted separately
+/// from ones for epochs which were never valid.
+#[tokio::test]
+async fn rejected_punctured() {
+    test_recorder();
+    let config = crate::Config {
+        last_epoch: EPOCH + 1,
+        ..test_config()
+    };
+    let server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+    let oprf_state = Arc::new(RwLock::new(server));
+    let app = crate::app(oprf_state.clone(), &config);
+    let request_epoch = |epoch: u8| {
+        let payload = json!({ "points": make_points(1), "epoch": epoch }).to_string();
+        app.clone()
+            .oneshot(test_request("/randomness", Some(payload)))
+    };
+
+    // Rotating punctures the previous epoch.
+    oprf_state.write().unwrap().advance(&config);
+    let rejected = metric_value(crate::telemetry::REJECTED_PUNCTURED);
+    let response = request_epoch(EPOCH).await.unwrap();
+    verify_error(response, StatusCode::BAD_REQUEST, "epoch_out_of_range").await;
+    assert!(metric_value(crate::telemetry::REJECTED_PUNCTURED) >= rejected + 1.0);
+
+    // Epochs which were never valid are rejected the same way,
+    // but not counted.
+    assert!(!oprf_state.read().unwrap().was_punctured(0));
+    let response = request_epoch(0).await.unwrap();
+    verify_error(response, StatusCode::BAD_REQUEST, "epoch_out_of_range").await;
+
+    // The last epoch is still recognised after the key rotation
+    // which follows it.
+    oprf_state.write().unwrap().advance(&config);
+    assert_eq!(oprf_state.read().unwrap().epoch, EPOCH);
+    assert!(oprf_state.read().unwrap().was_punctured(EPOCH + 1));
+    let rejected = metric_value(crate::telemetry::REJECTED_PUNCTURED);
+    let response = request_epoch(EPOCH + 1).await.unwrap();
+    verify_error(response, StatusCode::BAD_REQUEST, "epoch_out_of_range").await;
+    assert!(metric_value(crate::telemetry::REJECTED_PUNCTURED) >= rejected + 1.0);
+}
+
+/// `/info` should list every evaluable epoch with its key.
+#[tokio::test]
+async fn info_epoch_keys() {
+    let config = crate::Config {
+        epoch_seconds: 3600,
+        epoch_grace_seconds: 60,
+        ..test_config()
+    };
+    let server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+    let oprf_state = Arc::new(RwLock::new(server));
+    let app = crate::app(oprf_state.clone(), &config);
+    let epoch_keys = |json: &Value| -> Vec<(u64, String)> {
+        json["epochKeys"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| {
+                let key = entry["publicKey"].as_str().unwrap().to_owned();
+                (entry["epoch"].as_u64().unwrap(), key)
+            })
+            .collect()
+    };
+
+    // Normally only the current epoch is listed.
+    let json = info_json(&app).await;
+    let key = json["publicKey"].as_str().unwrap().to_owned();
+    assert_eq!(epoch_keys(&json), [(EPOCH.into(), key.clone())]);
+
+    // During a grace period, both sides of the boundary are.
+    oprf_state.write().unwrap().advance(&config);
+    let json = info_json(&app).await;
+    assert_eq!(json["currentEpoch"], json!(EPOCH + 1));
+    assert_eq!(
+        epoch_keys(&json),
+        [
+            (u64::from(EPOCH + 1), key.clone()),
+            (EPOCH.into(), key.clone())
+        ]
+    );
+
+    // Not once the grace epoch can't be used.
+    oprf_state.write().unwrap().max_epoch_lag = Some(0);
+    let json = info_json(&app).await;
+    assert_eq!(epoch_keys(&json), [(u64::from(EPOCH + 1), key.clone())]);
+    oprf_state.write().unwrap().max_epoch_lag = None;
+    oprf_state.write().unwrap().end_grace(&config);
+    let json = info_json(&app).await;
+    assert_eq!(epoch_keys(&json), [(u64::from(EPOCH + 1), key.clone())]);
+
+    // There's no grace period across a key rotation, so only the
+    // new key is listed.
+    while oprf_state.read().unwrap().epochs_remaining() > 0 {
+        oprf_state.write().unwrap().advance(&config);
+    }
+    oprf_state.write().unwrap().advance(&config);
+    let json = info_json(&app).await;
+    let new_key = json["publicKey"].as_str().unwrap().to_owned();
+    assert_ne!(new_key, key);
+    assert_eq!(epoch_keys(&json), [(EPOCH.into(), new_key)]);
+}
+
+/// The reported epoch should be the one each point was evaluated
+/// under, including a grace epoch requested after rotation.
+#[tokio::test]
+async fn response_epoch() {
+    let config = crate::Config {
+        epoch_seconds: 3600,
+        epoch_grace_seconds: 60,
+        ..test_config()
+    };
+    let server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+    let oprf_state = Arc::new(RwLock::new(server));
+    let app = crate::app(oprf_state.clone(), &config);
+    oprf_state.write().unwrap().advance(&config);
+    let points = make_points(3);
+
+    for (requested, expected) in [(None, EPOCH + 1), (Some(EPOCH), EPOCH)] {
+        let payload = json!({ "points": points, "epoch": requested }).to_string();
+        let request = test_request("/randomness", Some(payload));
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["epoch"], json!(expected));
+
+        // Every output matches an evaluation under that epoch.
+        let s = oprf_state.read().unwrap();
+        let outputs = json["points"].as_array().unwrap();
+        for (point, output) in points.iter().zip(outputs) {
+            let point = ppoprf::ppoprf::Point::from(BASE64.decode(point).unwrap().as_slice());
+            let evaluation = s.server.eval(&point, expected, false).unwrap();
+            assert_eq!(
+                output.as_str().unwrap(),
+                BASE64.encode(evaluation.output.as_bytes())
+            );
+        }
+    }
+
+    // Streamed responses report the same epoch in their header.
+    let payload = json!({ "points": points, "epoch": EPOCH }).to_string();
+    let mut request = test_request("/randomness", Some(payload));
+    let accept = "application/x-ndjson".parse().unwrap();
+    request.headers_mut().insert("Accept", accept);
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let header = std::str::from_utf8(&body).unwrap().lines().next().unwrap();
+    let header: Value = serde_json::from_str(header).unwrap();
+    assert_eq!(header["epoch"], json!(EPOCH));
+}
+
+/// `--rotate-key-at` should replace the key at that time, starting
+/// the epoch sequence over from there.
+#[tokio::test(start_paused = true)]
+async fn scheduled_key_rotation() {
+    let base_time = OffsetDateTime::from_unix_timestamp(1684125000).unwrap();
+    let interval = Duration::from_secs(3600);
+    let rotate_at = base_time + interval / 2 + Duration::from_secs(600);
+    let config = crate::Config {
+        epoch_seconds: 3600,
+        epoch_base_time: Some(base_time),
+        rotate_key_at: Some(rotate_at),
+        ..test_config()
+    };
+    config
+        .validate()
+        .expect("scheduled rotation should be valid");
+    let clock = TokioClock {
+        base: base_time + interval / 2,
+        start: tokio::time::Instant::now(),
+    };
+
+    let server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+    let oprf_state = Arc::new(RwLock::new(server));
+    let background_state = oprf_state.clone();
+    let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let loop_config = config.clone();
+    tokio::spawn(async move {
+        crate::state::epoch_loop_with_clock(background_state, &loop_config, shutdown_rx, &clock)
+            .await
+    });
+    tokio::time::sleep(Duration::from_millis(1)).await;
+    let key = public_key(&oprf_state);
+    assert_eq!(
+        oprf_state.read().unwrap().next_epoch_time().as_deref(),
+        Some(
+            rotate_at
+                .format(&time::format_description::well_known::Rfc3339)
+                .unwrap()
+                .as_str()
+        )
+    );
+
+    // Nothing changes until the scheduled time.
+    tokio::time::sleep(Duration::from_secs(599)).await;
+    assert_eq!(public_key(&oprf_state), key);
+
+    // Then the key is replaced before the epoch would have ended.
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_ne!(public_key(&oprf_state), key);
+    let next_epoch_time = (rotate_at + interval)
+        .format(&time::format_description::well_known::Rfc3339)
+        .unwrap();
+    {
+        let s = oprf_state.read().unwrap();
+        assert_eq!(s.epoch, EPOCH);
+        assert_eq!(s.next_epoch_time(), Some(next_epoch_time));
+    }
+
+    // Epochs now advance relative to the rotation.
+    let key = public_key(&oprf_state);
+    tokio::time::sleep(interval).await;
+    assert_eq!(oprf_state.read().unwrap().epoch, EPOCH + 1);
+    assert_eq!(public_key(&oprf_state), key);
+
+    // Replicas never rotate, so can't be scheduled to.
+    let invalid = crate::Config {
+        no_rotate: true,
+        ..config
+    };
+    assert!(invalid.validate().is_err());
+}
+
+/// Clock which panics once when asked to, failing the epoch loop
+#[derive(Clone)]
+struct FailingClock {
+    clock: TokioClock,
+    fail: Arc<AtomicBool>,
+}
+
+impl crate::state::Clock for FailingClock {
+    fn now(&self) -> OffsetDateTime {
+        if self.fail.swap(false, Ordering::SeqCst) {
+            panic!("injected clock failure");
+        }
+        self.clock.now()
+    }
+}
+
+/// Serialized public key of the current OPRF state
+fn public_key(state: &crate::OPRFState) -> Vec<u8> {
+    let s = state.read().unwrap();
+    s.server.get_public_key().serialize_to_bincode().unwrap()
+}
+
+/// If an epoch can't be punctured, the key should be rotated
+/// instead of the rotation task panicking.
+#[test]
+fn puncture_failure() {
+    test_recorder();
+    let failures = metric_value(crate::telemetry::PUNCTURE_FAILURES);
+    let config = test_config();
+    let mut server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+    let key = server.public_key.clone();
+    // Puncturing an epoch twice fails.
+    server.server.puncture(EPOCH).unwrap();
+    server.advance(&config);
+    assert_ne!(server.public_key, key);
+    assert_eq!(server.epoch, EPOCH);
+    assert!(server.punctured.is_empty());
+    assert!(metric_value(crate::telemetry::PUNCTURE_FAILURES) >= failures + 1.0);
+
+    // The same goes for the end of a grace period.
+    let config = crate::Config {
+        epoch_seconds: 60,
+        epoch_grace_seconds: 1,
+        ..config
+    };
+    let mut server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+    let key = server.public_key.clone();
+    server.advance(&config);
+    assert_eq!(server.grace_epoch, Some(EPOCH));
+    server.server.puncture(EPOCH).unwrap();
+    assert!(!server.end_grace(&config));
+    assert_ne!(server.public_key, key);
+    assert_eq!(server.epoch, EPOCH);
+    assert!(server.grace_epoch.is_none());
+    assert!(metric_value(crate::telemetry::PUNCTURE_FAILURES) >= failures + 2.0);
+}
+
+/// A failed epoch loop should be restarted or shut the server
+/// down, according to `--on-rotation-failure`.
+#[tokio::test(start_paused = true)]
+async fn epoch_loop_failure() {
+    let base_time = OffsetDateTime::from_unix_timestamp(1684125000).unwrap();
+    let config = crate::Config {
+        epoch_seconds: 3600,
+        epoch_base_time: Some(base_time),
+        ..test_config()
+    };
+    let interval = Duration::from_secs(config.epoch_seconds.into());
+    let fail = Arc::new(AtomicBool::new(false));
+    let clock = FailingClock {
+        clock: TokioClock {
+            base: base_time + interval / 2,
+            start: tokio::time::Instant::now(),
+        },
+        fail: fail.clone(),
+    };
+    test_recorder();
+    let failures = metric_value(crate::telemetry::EPOCH_LOOP_FAILURES);
+
+    let server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+    let oprf_state = Arc::new(RwLock::new(server));
+    let app = crate::app(oprf_state.clone(), &config);
+    let (shutdown_tx, _) = tokio::sync::watch::channel(false);
+    let supervisor = tokio::spawn(crate::state::supervise_epoch_loop_with_clock(
+        oprf_state.clone(),
+        config.clone(),
+        shutdown_tx.clone(),
+        clock.clone(),
+    ));
+    tokio::time::sleep(Duration::from_millis(1)).await;
+    assert_eq!(oprf_state.read().unwrap().epoch, EPOCH);
+    let original_key = public_key(&oprf_state);
+
+    // Fail the loop just after the next rotation. It should be
+    // restarted under a new key, at the scheduled epoch.
+    fail.store(true, Ordering::SeqCst);
+    tokio::time::sleep(interval).await;
+    assert!(!supervisor.is_finished());
+    assert!(!*shutdown_tx.borrow());
+    assert_eq!(
+        metric_value(crate::telemetry::EPOCH_LOOP_FAILURES),
+        failures + 1.0
+    );
+    assert_ne!(public_key(&oprf_state), original_key);
+    let expected = (base_time + interval * 2)
+        .format(&time::format_description::well_known::Rfc3339)
+        .unwrap();
+    {
+        let s = oprf_state.read().unwrap();
+        assert_eq!(s.epoch, EPOCH + 1);
+        assert_eq!(s.next_epoch_time().as_deref(), Some(expected.as_str()));
+    }
+    assert_eq!(
+        epoch_request_status(&app, EPOCH).await,
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(epoch_request_status(&app, EPOCH + 1).await, StatusCode::OK);
+
+    // The restarted loop keeps rotating, and shuts down normally.
+    tokio::time::sleep(interval).await;
+    assert_eq!(oprf_state.read().unwrap().epoch, EPOCH + 2);
+    shutdown_tx.send(true).unwrap();
+    assert!(supervisor.await.unwrap().is_ok());
+
+    // Under the exit policy the supervisor signals shutdown instead.
+    let config = crate::Config {
+        on_rotation_failure: crate::RotationFailurePolicy::Exit,
+        ..config
+    };
+    let server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+    let oprf_state = Arc::new(RwLock::new(server));
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let supervisor = tokio::spawn(crate::state::supervise_epoch_loop_with_clock(
+        oprf_state,
+        config,
+        shutdown_tx,
+        clock,
+    ));
+    tokio::time::sleep(Duration::from_millis(1)).await;
+    fail.store(true, Ordering::SeqCst);
+    tokio::time::sleep(interval).await;
+    assert!(supervisor.await.unwrap().is_err());
+    assert!(*shutdown_rx.borrow());
+    assert_eq!(
+        metric_value(crate::telemetry::EPOCH_LOOP_FAILURES),
+        failures + 2.0
+    );
+}
+
+/// Requesting the current epoch explicitly should evaluate in the
+/// epoch current at evaluation time, and report it.
+#[tokio::test]
+async fn current_epoch_request() {
+    let config = test_config();
+    let server = OPRFServer::new(&config).unwrap();
+    let oprf_state = Arc::new(RwLock::new(server));
+    let app = crate::app(oprf_state.clone(), &config);
+    let points = make_points(1);
+    let input = BASE64.decode(&points[0]).unwrap();
+    let point = ppoprf::ppoprf::Point::from(input.as_slice());
+    let payload = json!({ "points": points, "epoch": "current" }).to_string();
+
+    for expected in [EPOCH, EPOCH + 1] {
+        let request = test_request("/randomness", Some(payload.clone()));
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        let s = oprf_state.read().unwrap();
+        assert_eq!(s.epoch, expected);
+        assert_eq!(json["epoch"], json!(expected));
+        // The output is the evaluation in the reported epoch.
+        let output = s.server.eval(&point, expected, false).unwrap().output;
+        assert_eq!(json["points"][0], json!(BASE64.encode(output.as_bytes())));
+        drop(s);
+        // Requests sent before a rotation but evaluated after it
+        // get the new epoch.
+        oprf_state.write().unwrap().advance(&config);
+    }
+
+    // Other strings aren't epochs.
+    let payload = json!({ "points": make_points(1), "epoch": "previous" }).to_string();
+    let request = test_request("/randomness", Some(payload));
+    let response = app.oneshot(request).await.unwrap();
+    verify_error(
+        response,
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "invalid_request",
+    )
+    .await;
+}
+
+/// `OPTIONS` should list the methods each route supports.
+#[tokio::test]
+async fn options() {
+    let app = test_app_with_config(&crate::Config {
+        admin_token: Some("admin".into()),
+        ..test_config()
+    });
+    let get = "GET,HEAD,OPTIONS";
+    let post = "POST,OPTIONS";
+    let routes = [
+        ("/", get),
+        ("/randomness", post),
+        ("/info", get),
+        ("/batch", post),
+        ("/epoch/12", get),
+        ("/stats", get),
+        ("/verify", post),
+        ("/openapi.json", get),
+        ("/ready", get),
+        // Listing methods doesn't need the admin token.
+        ("/admin/drain", post),
+        ("/admin/undrain", post),
+    ];
+    for (path, allow) in routes {
+        let request = Request::builder()
+            .method("OPTIONS")
+            .uri(path)
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT, "{path}");
+        assert_eq!(response.headers()["Allow"], allow, "{path}");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(body.is_empty());
+    }
+
+    // Unknown paths are still not found.
+    let request = Request::builder()
+        .method("OPTIONS")
+        .uri("/nonexistent")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+/// Methods a route doesn't handle should be rejected with 405,
+/// listing the ones it does, rather than 404.
+#[tokio::test]
+async fn method_not_allowed() {
+    let app = test_app_with_config(&crate::Config {
+        admin_token: Some("admin".into()),
+        ..test_config()
+    });
+    let get = "GET,HEAD";
+    let post = "POST";
+    let routes = [
+        ("/", "POST", get),
+        ("/randomness", "GET", post),
+        ("/randomness", "PUT", post),
+        ("/info", "POST", get),
+        ("/batch", "GET", post),
+        ("/epoch/12", "DELETE", get),
+        ("/stats", "POST", get),
+        ("/verify", "GET", post),
+        ("/openapi.json", "POST", get),
+        ("/ready", "POST", get),
+        ("/admin/drain", "GET", post),
+        ("/admin/undrain", "GET", post),
+    ];
+    for (path, method, allow) in routes {
+        let request = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.headers()["Allow"], allow, "{method} {path}");
+        let json = verify_error(
+            response,
+            StatusCode::METHOD_NOT_ALLOWED,
+            "method_not_allowed",
+        )
+        .await;
+        assert_eq!(
+            json["error"]["message"],
+            format!("Method {method} isn't allowed for this endpoint")
+        );
+    }
+
+    // Unknown paths are still not found, whatever the method.
+    let request = Request::builder()
+        .method("POST")
+        .uri("/nonexistent")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+/// Running out of epochs should rotate the key, halt evaluation or
+/// shut the server down, according to `--on-epoch-exhaustion`.
+#[tokio::test(start_paused = true)]
+async fn epoch_exhaustion() {
+    let base_time = OffsetDateTime::from_unix_timestamp(1684125000).unwrap();
+    let interval = Duration::from_secs(3600);
+    let start = |policy| {
+        let config = crate::Config {
+            epoch_seconds: 3600,
+            epoch_base_time: Some(base_time),
+            last_epoch: EPOCH + 1,
+            on_epoch_exhaustion: policy,
+            ..test_config()
+        };
+        let clock = TokioClock {
+            base: base_time + interval / 2,
+            start: tokio::time::Instant::now(),
+        };
+        let server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+        let oprf_state = Arc::new(RwLock::new(server));
+        let app = crate::app(oprf_state.clone(), &config);
+        let (shutdown_tx, _) = tokio::sync::watch::channel(false);
+        let supervisor = tokio::spawn(crate::state::supervise_epoch_loop_with_clock(
+            oprf_state.clone(),
+            config,
+            shutdown_tx.clone(),
+            clock,
+        ));
+        (oprf_state, app, shutdown_tx, supervisor)
+    };
+
+    // By default the key is rotated, starting over at the first epoch.
+    let (oprf_state, app, shutdown_tx, supervisor) = start(crate::EpochExhaustionPolicy::Rotate);
+    tokio::time::sleep(interval).await;
+    assert_eq!(oprf_state.read().unwrap().epoch, EPOCH + 1);
+    let original_key = public_key(&oprf_state);
+    tokio::time::sleep(interval).await;
+    assert_eq!(oprf_state.read().unwrap().epoch, EPOCH);
+    assert_ne!(public_key(&oprf_state), original_key);
+    assert_eq!(epoch_request_status(&app, EPOCH).await, StatusCode::OK);
+    shutdown_tx.send(true).unwrap();
+    assert!(supervisor.await.unwrap().is_ok());
+
+    // Halting keeps the key, but punctures the last epoch and fails
+    // evaluation and readiness until shutdown.
+    let (oprf_state, app, shutdown_tx, supervisor) = start(crate::EpochExhaustionPolicy::Halt);
+    tokio::time::sleep(interval).await;
+    let original_key = public_key(&oprf_state);
+    tokio::time::sleep(interval).await;
+    {
+        let s = oprf_state.read().unwrap();
+        assert!(s.halted);
+        assert_eq!(s.epoch, EPOCH + 1);
+        assert!(s.punctured.contains(&(EPOCH + 1)));
+    }
+    assert_eq!(public_key(&oprf_state), original_key);
+    let payload = json!({ "points": make_points(1) }).to_string();
+    let response = app
+        .clone()
+        .oneshot(test_request("/randomness", Some(payload)))
+        .await
+        .unwrap();
+    verify_error(
+        response,
+        StatusCode::SERVICE_UNAVAILABLE,
+        "epochs_exhausted",
+    )
+    .await;
+    let response = app
+        .clone()
+        .oneshot(test_request("/ready", None))
+        .await
+        .unwrap();
+    verify_error(
+        response,
+        StatusCode::SERVICE_UNAVAILABLE,
+        "epochs_exhausted",
+    )
+    .await;
+    // Nothing changes while the server keeps running.
+    tokio::time::sleep(interval * 3).await;
+    assert!(oprf_state.read().unwrap().halted);
+    assert!(!supervisor.is_finished());
+    shutdown_tx.send(true).unwrap();
+    assert!(supervisor.await.unwrap().is_ok());
+
+    // Exiting halts too, then has the supervisor signal shutdown.
+    let (oprf_state, _, shutdown_tx, supervisor) = start(crate::EpochExhaustionPolicy::Exit);
+    let shutdown_rx = shutdown_tx.subscribe();
+    tokio::time::sleep(interval * 2).await;
+    assert!(oprf_state.read().unwrap().halted);
+    assert!(matches!(
+        supervisor.await.unwrap(),
+        Err(crate::state::RotationFailed::Exhausted)
+    ));
+    assert!(*shutdown_rx.borrow());
+}
+
+/// Log output captured for inspection
+#[derive(Clone, Default)]
+struct CapturedLog(Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for CapturedLog {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A panic in the epoch loop should be logged along with the
+/// epoch and schedule position it happened at.
+#[tokio::test(start_paused = true)]
+async fn epoch_loop_panic_report() {
+    crate::state::install_panic_hook();
+    let base_time = OffsetDateTime::from_unix_timestamp(1684125000).unwrap();
+    let config = crate::Config {
+        epoch_seconds: 3600,
+        epoch_base_time: Some(base_time),
+        on_rotation_failure: crate::RotationFailurePolicy::Exit,
+        ..test_config()
+    };
+    let interval = Duration::from_secs(config.epoch_seconds.into());
+    let fail = Arc::new(AtomicBool::new(false));
+    let clock = FailingClock {
+        clock: TokioClock {
+            base: base_time + interval / 2,
+            start: tokio::time::Instant::now(),
+        },
+        fail: fail.clone(),
+    };
+    let log = CapturedLog::default();
+    let writer = log.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    // The paused runtime runs every task on this thread.
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+    let oprf_state = Arc::new(RwLock::new(server));
+    let (shutdown_tx, _) = tokio::sync::watch::channel(false);
+    let supervisor = tokio::spawn(crate::state::supervise_epoch_loop_with_clock(
+        oprf_state,
+        config,
+        shutdown_tx,
+        clock,
+    ));
+    tokio::time::sleep(Duration::from_millis(1)).await;
+    fail.store(true, Ordering::SeqCst);
+    tokio::time::sleep(interval).await;
+    let result = supervisor.await.unwrap();
+    assert!(
+        matches!(
+            &result,
+            Err(crate::state::RotationFailed::Panicked(message))
+                if message == "injected clock failure"
+        ),
+        "{result:?}"
+    );
+
+    let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+    let reports: Vec<&str> = log
+        .lines()
+        .filter(|line| line.contains("epoch rotation panicked"))
+        .collect();
+    // The hook reports the panic once, as it happens.
+    let [report] = reports[..] else {
+        panic!("failure should be logged once: {log}");
+    };
+    assert!(report.contains("ERROR"), "{report}");
+    assert!(
+        report.contains(r#"panic="injected clock failure""#),
+        "{report}"
+    );
+    // The panic happened under the state lock while advancing, so
+    // the position is the one last published, for the first epoch.
+    assert!(report.contains(&format!("epoch={EPOCH} ")), "{report}");
+    assert!(report.contains("epoch_number=0"), "{report}");
+    assert!(report.contains("epochs_remaining=12"), "{report}");
+    let next = crate::state::format_rotation(base_time + interval);
+    assert!(
+        report.contains(&format!(r#"next_epoch_time="{next}""#)),
+        "{report}"
+    );
+}
+
+/// The grace epoch is only accepted within `--max-epoch-lag`.
+#[tokio::test]
+async fn max_epoch_lag() {
+    for (lag, expected) in [
+        (None, StatusCode::OK),
+        (Some(0), StatusCode::BAD_REQUEST),
+        (Some(1), StatusCode::OK),
+    ] {
+        let config = crate::Config {
+            epoch_seconds: 3600,
+            epoch_grace_seconds: 60,
+            max_epoch_lag: lag,
+            ..test_config()
+        };
+        let server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+        let oprf_state = Arc::new(RwLock::new(server));
+        let app = crate::app(oprf_state.clone(), &config);
+        oprf_state.write().unwrap().advance(&config);
+
+        // The previous epoch is one behind, in its grace period.
+        assert_eq!(oprf_state.read().unwrap().grace_epoch, Some(EPOCH));
+        assert_eq!(
+            epoch_request_status(&app, EPOCH).await,
+            expected,
+            "lag {lag:?}"
+        );
+        assert_eq!(epoch_request_status(&app, EPOCH + 1).await, StatusCode::OK);
+    }
+}
+
+/// Consecutive rotations should land on the absolute epoch
+/// boundaries given by the base time.
+#[tokio::test(start_paused = true)]
+async fn epoch_rotation_boundaries() {
+    use crate::state::Clock;
+
+    let base_time = OffsetDateTime::from_unix_timestamp(1684125000).unwrap();
+    let config = crate::Config {
+        epoch_seconds: 3600,
+        epoch_base_time: Some(base_time),
+        ..test_config()
+    };
+    let interval = Duration::from_secs(config.epoch_seconds.into());
+    // Start halfway through an epoch.
+    let clock = TokioClock {
+        base: base_time + interval / 2,
+        start: tokio::time::Instant::now(),
+    };
+
+    let server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+    let oprf_state = Arc::new(RwLock::new(server));
+    let background_state = oprf_state.clone();
+    let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let loop_clock = clock.clone();
+    tokio::spawn(async move {
+        crate::state::epoch_loop_with_clock(background_state, &config, shutdown_rx, &loop_clock)
+            .await
+    });
+
+    // Each rotation should happen just after its boundary, and not
+    // before.
+    let tick = Duration::from_millis(1);
+    tokio::time::sleep(interval / 2 - tick).await;
+    for n in 1..=2 {
+        let boundary = base_time + interval * n;
+        assert!(clock.now() < boundary);
+        assert_eq!(oprf_state.read().unwrap().epoch, EPOCH + n as u8 - 1);
+        tokio::time::sleep(tick * 2).await;
+        assert!(clock.now() - boundary <= tick);
+        assert_eq!(oprf_state.read().unwrap().epoch, EPOCH + n as u8);
+        tokio::time::sleep(interval - tick * 2).await;
+    }
+}
+
+/// Published rotation times should be rounded up to whole seconds,
+/// so they're never before the actual rotation.
+#[test]
+fn rotation_time_rounding() {
+    use crate::state::format_rotation;
+
+    let second = OffsetDateTime::from_unix_timestamp(1684125000).unwrap();
+    assert_eq!(format_rotation(second), "2023-05-15T04:30:00Z");
+    for nanos in [1, 1_000_000, 500_000_000, 999_999_999] {
+        let rotation = second + time::Duration::nanoseconds(nanos);
+        assert_eq!(format_rotation(rotation), "2023-05-15T04:30:01Z");
+    }
+}
+
+/// A backward clock step should re-anchor the epoch schedule
+/// instead of sleeping until the stale rotation time.
+#[test]
+fn epoch_schedule_clock_step() {
+    let now = OffsetDateTime::now_utc();
+    let interval = Duration::from_secs(5);
+
+    // A rotation within the current interval is left alone.
+    let next_rotation = now + Duration::from_secs(3);
+    let checked = crate::state::check_schedule(next_rotation, now, interval);
+    assert_eq!(checked, next_rotation);
+    let checked = crate::state::check_schedule(now + interval, now, interval);
+    assert_eq!(checked, now + interval);
+
+    // Being behind schedule is handled by the loop itself.
+    let next_rotation = now - Duration::from_secs(3);
+    let checked = crate::state::check_schedule(next_rotation, now, interval);
+    assert_eq!(checked, next_rotation);
+
+    // If the clock steps back an hour, the next rotation appears
+    // far in the future. It should be pulled in to one interval.
+    let next_rotation = now + Duration::from_secs(3600);
+    let checked = crate::state::check_schedule(next_rotation, now, interval);
+    assert_eq!(checked, now + interval);
+}
+
+/// Evaluate points with proofs, returning a /verify request payload
+fn make_verify_payload(points: &[String]) -> Value {
+    let server = OPRFServer::new(&test_config()).expect("Could not initialize PPOPRF state");
+    let mut outputs = Vec::with_capacity(points.len());
+    let mut proofs = Vec::with_capacity(points.len());
+    for b64point in points {
+        let input = BASE64.decode(b64point).unwrap();
+        let point = ppoprf::ppoprf::Point::from(input.as_slice());
+        let evaluation = server.server.eval(&point, EPOCH, true).unwrap();
+        outputs.push(BASE64.encode(evaluation.output.as_bytes()));
+        let proof = evaluation.proof.unwrap().serialize_to_bincode().unwrap();
+        proofs.push(BASE64.encode(proof));
+    }
+    let public_key = server
+        .server
+        .get_public_key()
+        .serialize_to_bincode()
+        .unwrap();
+    json!({
+        "points": points,
+        "outputs": outputs,
+        "proofs": proofs,
+        "epoch": EPOCH,
+        "publicKey": BASE64.encode(public_key),
+    })
+}
+
+/// Submit a /verify request, returning the per-point results
+async fn verify_request(payload: &Value) -> Vec<bool> {
+    let request = test_request("/verify", Some(payload.to_string()));
+    let response = test_app().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let json: Value = serde_json::from_slice(body.as_ref()).unwrap();
+    json["valid"]
+        .as_array()
+        .expect("valid should be an array")
+        .iter()
+        .map(|v| v.as_bool().unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn verify() {
+    let points = make_points(4);
+    let payload = make_verify_payload(&points);
+
+    // Untouched evaluations should all verify.
+    assert_eq!(verify_request(&payload).await, vec![true; 4]);
+
+    // Swapping proofs, substituting an output, or garbling
+    // the input should fail only the affected indices.
+    let mut tampered = payload.clone();
+    tampered["proofs"][0] = payload["proofs"][1].clone();
+    tampered["proofs"][1] = payload["proofs"][0].clone();
+    tampered["outputs"][2] = json!(make_points(1)[0]);
+    tampered["points"][3] = json!("not a point");
+    assert_eq!(verify_request(&tampered).await, vec![false; 4]);
+
+    // Verifying under the wrong epoch should fail everything.
+    let mut tampered = payload.clone();
+    tampered["epoch"] = json!(EPOCH + 1);
+    assert_eq!(verify_request(&tampered).await, vec![false; 4]);
+
+    // Mismatched array lengths are a malformed request.
+    let mut tampered = payload.clone();
+    tampered["proofs"].as_array_mut().unwrap().pop();
+    let request = test_request("/verify", Some(tampered.to_string()));
+    let response = test_app().oneshot(request).await.unwrap();
+    verify_error(response, StatusCode::BAD_REQUEST, "length_mismatch").await;
+}
+
+/// Options can be loaded from a TOML file, with the
+/// command line taking precedence.
+#[test]
+fn config_file() {
+    let path = std::env::temp_dir().join(format!("star-randsrv-{}.toml", std::process::id()));
+    std::fs::write(
+        &path,
+        r#"
+            listen = "127.0.0.1:9090"
+            epoch_seconds = 60
+            first_epoch = 3
+            epoch_base_time = "2023-05-15T04:30:00Z"
+            trust_proxy = true
+        "#,
+    )
+    .unwrap();
+    let args = [
+        "star-randsrv",
+        "--config",
+        path.to_str().unwrap(),
+        "--epoch-seconds",
+        "30",
+    ];
+    let config = crate::Config::try_load_from(args);
+    let invalid = crate::Config::try_load_from([
+        "star-randsrv",
+        "--config",
+        path.to_str().unwrap(),
+        "--last-epoch",
+        "2",
+    ]);
+    std::fs::write(&path, "epoch_secs = 10\n").unwrap();
+    let unknown =
+        crate::Config::try_load_from(["star-randsrv", "--config", path.to_str().unwrap()]);
+    std::fs::remove_file(&path).unwrap();
+
+    let config = config.expect("config file should load");
+    // Values from the file fill in the config...
+    assert_eq!(config.listen, "127.0.0.1:9090".parse().unwrap());
+    assert_eq!(config.first_epoch, 3);
+    assert!(config.trust_proxy);
+    let base_time = config.epoch_base_time.expect("base time should be set");
+    assert_eq!(base_time.unix_timestamp(), 1684125000);
+    // ...except where overridden on the command line...
+    assert_eq!(config.epoch_seconds, 30);
+    // ...while anything unmentioned keeps its default.
+    assert_eq!(config.last_epoch, 255);
+    assert!(config.prometheus_listen.is_none());
+
+    // The merged config is validated.
+    assert!(
+        invalid.is_err(),
+        "first_epoch > last_epoch should be rejected"
+    );
+    // Typos in the file are errors rather than being ignored.
+    assert!(unknown.is_err(), "unknown keys should be rejected");
+}
+
+/// Malformed listen addresses should be rejected when the
+/// config is loaded, naming the bad value.
+#[test]
+fn config_listen_address() {
+    let config = crate::Config::try_load_from([
+        "star-randsrv",
+        "--listen",
+        "[::1]:8443",
+        "--prometheus-listen",
+        "0.0.0.0:9100",
+    ])
+    .expect("addresses should parse");
+    assert_eq!(config.listen, "[::1]:8443".parse().unwrap());
+    assert_eq!(
+        config.prometheus_listen,
+        Some("0.0.0.0:9100".parse().unwrap())
+    );
+
+    for option in ["--listen", "--prometheus-listen"] {
+        let error = crate::Config::try_load_from(["star-randsrv", option, "127.0.0.1"])
+            .expect_err("an address without a port should be rejected")
+            .to_string();
+        assert!(error.contains(option), "{error}");
+        assert!(error.contains("'127.0.0.1'"), "{error}");
+        assert!(error.contains("IP address and port"), "{error}");
+    }
+
+    // Addresses in a config file are checked too.
+    let path =
+        std::env::temp_dir().join(format!("star-randsrv-listen-{}.toml", std::process::id()));
+    std::fs::write(&path, "listen = \"localhost:8080\"\n").unwrap();
+    let result = crate::Config::try_load_from(["star-randsrv", "--config", path.to_str().unwrap()]);
+    std::fs::remove_file(&path).unwrap();
+    let error = result
+        .expect_err("a hostname should be rejected")
+        .to_string();
+    assert!(error.contains("listen"), "{error}");
+    assert!(error.contains("'localhost:8080'"), "{error}");
+}
+
+#[test]
+fn config_redaction() {
+    let config = crate::Config::try_load_from([
+        "star-randsrv",
+        "--admin-token",
+        "open sesame",
+        "--response-hmac-key",
+        "hunter2",
+    ])
+    .expect("config should load");
+    assert_eq!(config.admin_token.as_ref().unwrap().expose(), "open sesame");
+
+    // Logging the config must not reveal secrets.
+    let logged = format!("{config:?}");
+    assert!(
+        !logged.contains("open sesame"),
+        "admin token leaked: {logged}"
+    );
+    assert!(!logged.contains("hunter2"), "hmac key leaked: {logged}");
+    assert!(logged.contains("<redacted>"));
+    // Other options are still shown.
+    assert!(logged.contains(&config.listen.to_string()));
+}
+
+/// The main server should still come up if the metrics address
+/// is already in use, unless metrics are required.
+#[tokio::test]
+async fn metrics_bind_failure() {
+    let occupied = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let metrics_addr = occupied.local_addr().unwrap();
+    let config = crate::Config {
+        prometheus_listen: Some(metrics_addr),
+        ..test_config()
+    };
+    let metrics_app = crate::metrics_app(test_recorder().clone(), None);
+    crate::serve_metrics(metrics_app.clone(), metrics_addr, &config)
+        .expect("metrics bind failure should be tolerated");
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = axum::Server::from_tcp(listener)
+        .unwrap()
+        .serve(test_app_with_config(&config).into_make_service());
+    tokio::spawn(server);
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"GET / HTTP/1.0\r\n\r\n").await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.0 200"), "{response}");
+    assert!(response.ends_with("STAR randomness server\n"), "{response}");
+
+    let config = crate::Config {
+        require_metrics: true,
+        ..config
+    };
+    assert!(crate::serve_metrics(metrics_app, metrics_addr, &config).is_err());
+}
+
+/// Metrics should be pushed to statsd as aggregated lines.
+#[test]
+fn statsd_metrics() {
+    use crate::statsd::StatsdRecorder;
+    use crate::telemetry;
+    use metrics::{Key, Label, Recorder};
+
+    let listener = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    listener
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let recorder = StatsdRecorder::new(listener.local_addr().unwrap()).unwrap();
+    let receive = || {
+        let mut buf = [0; 2048];
+        let len = listener
+            .recv(&mut buf)
+            .expect("should receive a metrics packet");
+        let mut lines: Vec<String> = std::str::from_utf8(&buf[..len])
+            .unwrap()
+            .lines()
+            .map(str::to_owned)
+            .collect();
+        lines.sort();
+        lines
+    };
+
+    // The same metric definitions are used as for prometheus.
+    let labels = vec![Label::new("epoch", EPOCH.to_string())];
+    let key = Key::from_parts(telemetry::EVALUATIONS_BY_EPOCH, labels);
+    recorder.register_counter(&key).increment(3);
+    recorder.register_counter(&key).increment(2);
+    let key = Key::from_name(telemetry::EPOCHS_REMAINING);
+    recorder.register_gauge(&key).set(7.0);
+    recorder.register_gauge(&key).decrement(1.0);
+    let key = Key::from_name(telemetry::REQUEST_DURATION);
+    recorder.register_histogram(&key).record(0.25);
+    recorder.flush();
+    assert_eq!(
+        receive(),
+        [
+            format!("{}:6|g", telemetry::EPOCHS_REMAINING),
+            format!("{}:5|c|#epoch:{EPOCH}", telemetry::EVALUATIONS_BY_EPOCH),
+            format!("{}:0.25|h", telemetry::REQUEST_DURATION),
+        ]
+    );
+
+    // Only changes since the last flush are sent.
+    let key = Key::from_name(telemetry::EPOCHS_ADVANCED);
+    recorder.register_counter(&key).increment(1);
+    recorder.flush();
+    assert_eq!(receive(), [format!("{}:1|c", telemetry::EPOCHS_ADVANCED)]);
+}
+
+/// Accept a single connection to a bound listener
+async fn accept(incoming: &mut hyper::server::conn::AddrIncoming) -> tokio::net::TcpStream {
+    use hyper::server::accept::Accept;
+    let addr = incoming.local_addr();
+    let _client = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let stream = std::future::poll_fn(|cx| std::pin::Pin::new(&mut *incoming).poll_accept(cx))
+        .await
+        .expect("listener should accept a connection")
+        .unwrap();
+    stream.into_inner()
+}
+
+/// Socket options should be applied to accepted connections.
+#[tokio::test]
+async fn listener_socket_options() {
+    let config = crate::Config {
+        listen: "127.0.0.1:0".parse().unwrap(),
+        tcp_backlog: 16,
+        tcp_nodelay: true,
+        ..test_config()
+    };
+    let mut incoming = crate::bind(&config).unwrap();
+    assert!(accept(&mut incoming).await.nodelay().unwrap());
+
+    // Nodelay is off unless asked for.
+    let config = crate::Config {
+        tcp_nodelay: false,
+        ..config
+    };
+    let mut incoming = crate::bind(&config).unwrap();
+    assert!(!accept(&mut incoming).await.nodelay().unwrap());
+
+    // The bound listener serves requests as usual.
+    let addr = incoming.local_addr();
+    let server = axum::Server::builder(incoming).serve(test_app().into_make_service());
+    tokio::spawn(server);
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"GET / HTTP/1.0\r\n\r\n").await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.0 200"), "{response}");
+}
+
+/// New connections should be accepted no faster than the limit.
+#[tokio::test(start_paused = true)]
+async fn accept_throttling() {
+    use hyper::server::accept::Accept;
+
+    let config = crate::Config {
+        listen: "127.0.0.1:0".parse().unwrap(),
+        max_new_connections_per_second: Some(5),
+        ..test_config()
+    };
+    let incoming = crate::bind(&config).unwrap();
+    let addr = incoming.local_addr();
+    let mut incoming =
+        crate::ThrottledIncoming::new(incoming, config.max_new_connections_per_second);
+
+    // Clients can connect while they wait in the backlog.
+    let mut clients = Vec::new();
+    for _ in 0..10 {
+        clients.push(tokio::net::TcpStream::connect(addr).await.unwrap());
+    }
+    let start = tokio::time::Instant::now();
+    let mut accepted = Vec::new();
+    for _ in 0..10 {
+        std::future::poll_fn(|cx| std::pin::Pin::new(&mut incoming).poll_accept(cx))
+            .await
+            .expect("listener should accept a connection")
+            .unwrap();
+        accepted.push(start.elapsed());
+    }
+
+    // A second's worth are accepted at once, then the rest at
+    // the limit.
+    assert!(accepted[4] < Duration::from_millis(100), "{accepted:?}");
+    for pair in accepted[4..].windows(2) {
+        assert!(
+            pair[1] - pair[0] >= Duration::from_millis(190),
+            "{accepted:?}"
+        );
+    }
+    assert!(accepted[9] >= Duration::from_secs(1), "{accepted:?}");
+}
+
+/// Start a server on a local port, returning its address
+fn spawn_server(config: &crate::Config) -> SocketAddr {
+    let config = crate::Config {
+        listen: "127.0.0.1:0".parse().unwrap(),
+        ..config.clone()
+    };
+    let incoming = crate::bind(&config).unwrap();
+    let addr = incoming.local_addr();
+    let server = crate::server(incoming, &config).serve(test_app().into_make_service());
+    tokio::spawn(server);
+    addr
+}
+
+/// The typed client should round-trip the server's responses.
+#[cfg(feature = "client")]
+#[tokio::test]
+async fn client() {
+    use crate::client::{ClientError, RandsrvClient};
+
+    let addr = spawn_server(&test_config());
+    let client = RandsrvClient::new(format!("http://{addr}/").parse().unwrap());
+
+    let info = client.info().await.expect("info should succeed");
+    assert_eq!(info.current_epoch, EPOCH);
+    assert_eq!(info.next_epoch_time.as_deref(), Some(NEXT_EPOCH_TIME));
+    assert_eq!(info.max_points, crate::MAX_POINTS);
+    let key = client.public_key().await.expect("public key should decode");
+    assert_eq!(
+        BASE64.encode(key.serialize_to_bincode().unwrap()),
+        info.public_key
+    );
+
+    let points: Vec<ppoprf::ppoprf::Point> = (0..3)
+        .map(|_| RistrettoPoint::random(&mut OsRng).into())
+        .collect();
+    let response = client.randomness(&points, None).await.unwrap();
+    assert_eq!(response.epoch, EPOCH);
+    assert_eq!(response.points.len(), points.len());
+    let response = client.randomness(&points, Some(EPOCH)).await.unwrap();
+    assert_eq!(response.epoch, EPOCH);
+
+    // Server errors are reported with their code.
+    let error = client
+        .randomness(&points, Some(EPOCH + 1))
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        error,
+        ClientError::Server { status, code, .. }
+            if status == StatusCode::BAD_REQUEST && code == "epoch_out_of_range"
+    ));
+}
+
+/// Clients with prior knowledge should be able to use h2c,
+/// but only if it's enabled.
+#[tokio::test]
+async fn http2_prior_knowledge() {
+    let client = hyper::Client::builder()
+        .http2_only(true)
+        .build_http::<Body>();
+    let payload = json!({ "points": make_points(2) }).to_string();
+    let config = crate::Config {
+        http2: true,
+        ..test_config()
+    };
+    let addr = spawn_server(&config);
+    let request = Request::builder()
+        .method("POST")
+        .uri(format!("http://{addr}/randomness"))
+        .header("Content-Type", "application/json")
+        .body(Body::from(payload.clone()))
+        .unwrap();
+    let response = client.request(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.version(), axum::http::Version::HTTP_2);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    verify_randomness_body(body, 2);
+
+    // HTTP/1.1 still works alongside.
+    let response = hyper::Client::new()
+        .get(format!("http://{addr}/").parse().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.version(), axum::http::Version::HTTP_11);
+
+    // By default the HTTP/2 preface is rejected.
+    let addr = spawn_server(&test_config());
+    let request = Request::builder()
+        .uri(format!("http://{addr}/"))
+        .body(Body::empty())
+        .unwrap();
+    assert!(client.request(request).await.is_err());
+}
+
+/// Shutting down the epoch loop should save the schedule,
+/// which a later loop resumes.
+#[tokio::test(start_paused = true)]
+async fn shutdown_saves_state() {
+    let path = std::env::temp_dir().join(format!("star-randsrv-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let now = OffsetDateTime::from_unix_timestamp(1684125000).unwrap();
+    let clock = TokioClock {
+        base: now,
+        start: tokio::time::Instant::now(),
+    };
+    let base_time = now - Duration::from_secs(3);
+    let config = crate::Config {
+        epoch_seconds: 60,
+        epoch_base_time: Some(base_time),
+        state_file: Some(path.clone()),
+        ..test_config()
+    };
+
+    let server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+    let oprf_state = Arc::new(RwLock::new(server));
+    let background_state = oprf_state.clone();
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let loop_config = config.clone();
+    let rotation = tokio::spawn(async move {
+        crate::state::epoch_loop_with_clock(background_state, &loop_config, shutdown_rx, &clock)
+            .await
+    });
+
+    // Wait for initialization, then request shutdown.
+    let pause = Duration::from_millis(10);
+    let mut tries = 0;
+    while oprf_state.read().unwrap().epoch_window.is_none() {
+        assert!(tries < 10, "timeout waiting for epoch_loop initialization");
+        tokio::time::sleep(pause).await;
+        tries += 1;
+    }
+    shutdown_tx.send(true).unwrap();
+    tokio::time::timeout(Duration::from_secs(1), rotation)
+        .await
+        .expect("epoch_loop should exit on shutdown")
+        .unwrap();
+
+    // The state file should record the schedule.
+    let saved = crate::state::SavedState::load(&path)
+        .expect("state file should be readable")
+        .expect("state file should exist");
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(saved.base_time, base_time);
+    assert_eq!(saved.epoch, EPOCH);
+    let next_epoch_time = oprf_state.read().unwrap().next_epoch_time();
+    assert_eq!(saved.next_epoch_time, next_epoch_time);
+}
+
+/// Check a randomness response body for validity
+fn verify_randomness_body(body: axum::body::Bytes, expected_points: usize) {
+    // Randomness should return a list of points and an epoch.
+    assert!(!body.is_empty());
+    let json: Value =
+        serde_json::from_slice(body.as_ref()).expect("Response body should parse as json");
+    // Top-level value should be an object.
+    assert!(json.is_object());
+    // Epoch should match test_app.
+    let epoch = json["epoch"].as_u64().unwrap();
+    assert_eq!(epoch, EPOCH as u64);
+    // Points array should have the expected number of elements.
+    let points = json["points"].as_array().unwrap();
+    assert_eq!(points.len(), expected_points);
+    // Individual elements should be parseable base64-encoded
+    // compressed Ristretto elliptic curve points.
+    for value in points {
+        let b64point = value.as_str().unwrap();
+        let rawpoint = BASE64.decode(b64point).unwrap();
+        let _ = CompressedRistretto::from_slice(&rawpoint);
+    }
+}
+
+/// Check an error response has the expected status and code
+/// Returns the parsed body for further checks.
+async fn verify_error(response: Response, status: StatusCode, code: &str) -> Value {
+    assert_eq!(response.status(), status);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let json: Value =
+        serde_json::from_slice(body.as_ref()).expect("Error body should parse as json");
+    assert_eq!(json["error"]["code"], json!(code));
+    assert!(json["error"]["message"].is_string());
+    json
+}
+
+/// Generate a number of random base64-encoded points.
+fn make_points(count: usize) -> Vec<String> {
+    let mut points = Vec::with_capacity(count);
+    for _ in 0..count {
+        let point = RistrettoPoint::random(&mut OsRng);
+        let b64point = BASE64.encode(point.compress().as_bytes());
+        points.push(b64point);
+    }
+    points
+}
+
+/// Verify randomness response to a batch of points
+async fn verify_batch(points: &[String]) {
+    let app = test_app();
+    let payload = json!({ "points": points }).to_string();
+    let request = test_request("/randomness", Some(payload));
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    verify_randomness_body(body, points.len());
+}
+
+/// Points and keys should round-trip in each base64 variant.
+#[tokio::test]
+async fn base64_variants() {
+    use crate::Base64Variant;
+
+    let points: Vec<Vec<u8>> = (0..16)
+        .map(|_| {
+            RistrettoPoint::random(&mut OsRng)
+                .compress()
+                .to_bytes()
+                .to_vec()
+        })
+        .collect();
+    // Only the standard alphabet has `+` and `/`.
+    let standard = std::iter::repeat_with(|| make_points(1))
+        .find(|points| points[0].contains(['+', '/']))
+        .unwrap();
+    for variant in [
+        Base64Variant::Standard,
+        Base64Variant::StandardNoPad,
+        Base64Variant::UrlSafe,
+        Base64Variant::UrlSafeNoPad,
+    ] {
+        let engine = variant.engine();
+        let config = crate::Config {
+            base64_variant: variant,
+            ..test_config()
+        };
+        let app = test_app_with_config(&config);
+
+        let encoded: Vec<String> = points.iter().map(|p| engine.encode(p)).collect();
+        let payload = json!({ "points": encoded }).to_string();
+        let request = test_request("/randomness", Some(payload));
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "{variant:?}");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        let outputs = json["points"].as_array().unwrap();
+        assert_eq!(outputs.len(), points.len());
+        for output in outputs {
+            let output = output.as_str().unwrap();
+            let bytes = engine.decode(output).expect("output should decode");
+            assert!(CompressedRistretto::from_slice(&bytes)
+                .unwrap()
+                .decompress()
+                .is_some());
+            // The response uses the same variant as requests.
+            assert_eq!(engine.encode(&bytes), output);
+        }
+
+        // So does the public key.
+        let json = info_json(&app).await;
+        let key = engine
+            .decode(json["publicKey"].as_str().unwrap())
+            .expect("public key should decode");
+        ppoprf::ppoprf::ServerPublicKey::load_from_bincode(&key).unwrap();
+
+        // Standard padded encoding is only accepted by the default.
+        let payload = json!({ "points": standard }).to_string();
+        let request = test_request("/randomness", Some(payload));
+        let response = app.oneshot(request).await.unwrap();
+        if variant == Base64Variant::Standard {
+            assert_eq!(response.status(), StatusCode::OK);
+        } else {
+            verify_error(response, StatusCode::BAD_REQUEST, "invalid_encoding").await;
+        }
+    }
+}
+
+#[test]
+fn evaluate_batch() {
+    use crate::eval::evaluate_batch;
+    use crate::handler::Error;
+
+    let server = OPRFServer::new(&test_config()).expect("Could not initialize PPOPRF state");
+    let points: Vec<Vec<u8>> = make_points(3)
+        .iter()
+        .map(|point| BASE64.decode(point).unwrap())
+        .collect();
+
+    // The current epoch is the default, and evaluation is deterministic.
+    let result = evaluate_batch(&server, &points, None).expect("batch should evaluate");
+    assert_eq!(result.epoch, EPOCH);
+    assert_eq!(result.points.len(), points.len());
+    let again = evaluate_batch(&server, &points, Some(EPOCH)).unwrap();
+    for (a, b) in result.points.iter().zip(&again.points) {
+        assert_eq!(a.as_bytes(), b.as_bytes());
+    }
+    let empty = evaluate_batch(&server, &[], None).unwrap();
+    assert!(empty.points.is_empty());
+
+    // Other epochs are rejected.
+    let result = evaluate_batch(&server, &points, Some(EPOCH + 1));
+    assert!(matches!(result, Err(Error::BadEpoch(epoch)) if epoch == EPOCH + 1));
+
+    // Bad points are reported by index, whether they have the
+    // wrong length or don't decompress.
+    let mut short = points.clone();
+    short[1].pop();
+    let result = evaluate_batch(&server, &short, None);
+    assert!(matches!(result, Err(Error::BadPoint(1))));
+    let mut invalid = points.clone();
+    invalid[2] = vec![0xff; ppoprf::ppoprf::COMPRESSED_POINT_LEN];
+    let result = evaluate_batch(&server, &invalid, None);
+    assert!(matches!(result, Err(Error::BadPoint(2))));
+}
+
+/// Fixed evaluation vectors
+///
+/// ppoprf always draws a fresh key from the OS, so outputs can't be
+/// recorded for arbitrary inputs. Instead the identity point, which
+/// every key maps to itself, pins the encoding end to end, and the
+/// Ristretto basepoint is checked against relations which hold for
+/// any key: evaluation commutes with scalar multiplication, the
+/// proof verifies under the published key, and the finalized output
+/// doesn't depend on the client's blinding factor.
+#[tokio::test]
+async fn evaluation_vectors() {
+    use curve25519_dalek::constants::RISTRETTO_BASEPOINT_POINT;
+    use curve25519_dalek::Scalar;
+    use ppoprf::ppoprf::Client;
+
+    const IDENTITY: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+    const BASEPOINT: &str = "e2f2ae0a6abc4e71a884a961c500515f58e30b6aa582dd8db6a65945e08d2d76";
+
+    let payload = json!({ "points": [IDENTITY], "epoch": EPOCH }).to_string();
+    let request = test_request("/randomness", Some(payload));
+    let response = test_app().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        json,
+        json!({
+            "points": [IDENTITY],
+            "epoch": EPOCH,
+            "epochNumber": 0,
+            "epochTimeRemainingSeconds": 0
+        })
+    );
+
+    let server = OPRFServer::new(&test_config()).expect("Could not initialize PPOPRF state");
+    let basepoint = RISTRETTO_BASEPOINT_POINT;
+    assert_eq!(hex::encode(basepoint.compress().as_bytes()), BASEPOINT);
+    let eval = |point: RistrettoPoint| {
+        let evaluation = server.server.eval(&point.into(), EPOCH, true).unwrap();
+        let output = RistrettoPoint::from(evaluation.output.clone());
+        assert!(Client::verify(
+            &server.server.get_public_key(),
+            &point.into(),
+            &evaluation,
+            EPOCH
+        ));
+        output
+    };
+    let output = eval(basepoint);
+    assert_ne!(output, basepoint);
+    assert_eq!(eval(basepoint), output);
+    let three = Scalar::from(3u8);
+    assert_eq!(eval(three * basepoint), three * output);
+
+    let input = b"star-randsrv evaluation vector";
+    let finalize = || {
+        let (blinded, r) = Client::blind(input);
+        let evaluation = server.server.eval(&blinded, EPOCH, false).unwrap();
+        let mut out = [0u8; 32];
+        Client::finalize(
+            input,
+            EPOCH,
+            &Client::unblind(&evaluation.output, &r),
+            &mut out,
+        );
+        out
+    };
+    assert_eq!(finalize(), finalize());
+}
+
+/// Tags should be echoed back aligned with the points.
+#[tokio::test]
+async fn point_tags() {
+    let points = make_points(3);
+    let tags = json!(["a", 7, { "id": [1, 2] }]);
+    let payload = json!({ "points": points, "tags": tags }).to_string();
+    let request = test_request("/randomness", Some(payload.clone()));
+    let response = test_app().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["tags"], tags);
+    assert_eq!(json["points"].as_array().unwrap().len(), points.len());
+
+    // Streamed responses carry each tag on its point's line.
+    let mut request = test_request("/randomness", Some(payload));
+    let accept = "application/x-ndjson".parse().unwrap();
+    request.headers_mut().insert("Accept", accept);
+    let response = test_app().oneshot(request).await.unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let lines: Vec<Value> = std::str::from_utf8(&body)
+        .unwrap()
+        .lines()
+        .skip(1)
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), points.len());
+    for (line, tag) in lines.iter().zip(tags.as_array().unwrap()) {
+        assert_eq!(&line["tag"], tag);
+    }
+
+    // Without tags, none are returned.
+    let payload = json!({ "points": points }).to_string();
+    let request = test_request("/randomness", Some(payload));
+    let response = test_app().oneshot(request).await.unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    assert!(json.get("tags").is_none());
+
+    // There must be exactly one tag per point.
+    let payload = json!({ "points": points, "tags": ["a", "b"] }).to_string();
+    let request = test_request("/randomness", Some(payload));
+    let response = test_app().oneshot(request).await.unwrap();
+    verify_error(response, StatusCode::BAD_REQUEST, "length_mismatch").await;
+}
+
+/// Shuffled results should be restorable to request order.
+#[tokio::test]
+async fn shuffle() {
+    let app = test_app();
+    let points = make_points(32);
+    let tags: Vec<usize> = (0..points.len()).collect();
+    let randomness = |payload: Value| {
+        let request = test_request("/randomness", Some(payload.to_string()));
+        let app = app.clone();
+        async move {
+            let response = app.oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            serde_json::from_slice::<crate::RandomnessResponse>(&body).unwrap()
+        }
+    };
+
+    // Results are in request order by default.
+    let ordered = randomness(json!({ "points": points })).await;
+    assert!(ordered.permutation.is_none());
+
+    let shuffled = randomness(json!({ "points": points, "tags": tags, "shuffle": true })).await;
+    let permutation = shuffled
+        .permutation
+        .expect("shuffle should return a permutation");
+    let mut indices = permutation.clone();
+    indices.sort_unstable();
+    assert_eq!(indices, tags, "permutation should cover every point once");
+    // Tags move with their points.
+    assert_eq!(
+        shuffled.tags.unwrap(),
+        json!(permutation).as_array().unwrap().clone()
+    );
+    // Undoing the permutation gives the ordered results.
+    let mut restored = vec![String::new(); points.len()];
+    for (point, &index) in shuffled.points.into_iter().zip(&permutation) {
+        restored[index] = point;
+    }
+    assert_eq!(restored, ordered.points);
+
+    // Other response formats can't carry the permutation.
+    for accept in ["application/x-ndjson", "application/octet-stream"] {
+        let payload = json!({ "points": points, "shuffle": true }).to_string();
+        let mut request = test_request("/randomness", Some(payload));
+        request
+            .headers_mut()
+            .insert("Accept", accept.parse().unwrap());
+        let response = app.clone().oneshot(request).await.unwrap();
+        verify_error(response, StatusCode::BAD_REQUEST, "invalid_request").await;
+    }
+}
+
+#[tokio::test]
+async fn point_batches() {
+    // Check that we can submit multiple points.
+    let points = make_points(5);
+    verify_batch(&points).await;
+
+    // Check that we can submit a reasonable number of points.
+    let points = make_points(128);
+    assert!(points.len() < crate::MAX_POINTS);
+    verify_batch(&points).await;
+}
+
+#[tokio::test]
+async fn request_duration() {
+    let duration = crate::telemetry::REQUEST_DURATION;
+    let ok = format!("{duration}_count{{outcome=\"ok\"}}");
+    let failed = format!("{duration}_count{{outcome=\"no_points\"}}");
+    let ok_before = metric_value(&ok);
+    let failed_before = metric_value(&failed);
+
+    let payload = json!({ "points": make_points(1) }).to_string();
+    let request = test_request("/randomness", Some(payload));
+    let response = test_app().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let payload = json!({ "points": [] }).to_string();
+    let request = test_request("/randomness", Some(payload));
+    let response = test_app().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // Each request is timed and labeled with its outcome.
+    assert!(metric_value(&ok) > ok_before);
+    assert!(metric_value(&failed) > failed_before);
+    // Durations are exported as a histogram with fine buckets.
+    let bucket = format!("{duration}_bucket{{outcome=\"ok\",le=\"0.0005\"}}");
+    assert!(
+        test_recorder().render().contains(&bucket),
+        "missing {bucket}"
+    );
+}
+
+/// Decoding requests and encoding responses should be timed
+/// separately for each format.
+#[tokio::test]
+async fn codec_duration() {
+    let decode = crate::telemetry::DECODE_DURATION;
+    let encode = crate::telemetry::ENCODE_DURATION;
+    let series: Vec<String> = [decode, encode]
+        .iter()
+        .flat_map(|name| {
+            ["json", "binary"].map(|format| format!("{name}_count{{format=\"{format}\"}}"))
+        })
+        .collect();
+    let before: Vec<f64> = series.iter().map(|series| metric_value(series)).collect();
+
+    let points = make_points(2);
+    let payload = json!({ "points": points }).to_string();
+    let request = test_request("/randomness", Some(payload));
+    let response = test_app().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let raw: Vec<Vec<u8>> = points.iter().map(|p| BASE64.decode(p).unwrap()).collect();
+    let request = binary_request("/randomness", &raw);
+    let response = test_app().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    for (series, before) in series.iter().zip(before) {
+        assert!(metric_value(series) > before, "{series} not recorded");
+    }
+    // Durations are exported as histograms with fine buckets.
+    let bucket = format!("{decode}_bucket{{format=\"json\",le=\"0.00001\"}}");
+    assert!(
+        test_recorder().render().contains(&bucket),
+        "missing {bucket}"
+    );
+}
+
+#[test]
+fn self_test() {
+    crate::eval::self_test(&test_config()).expect("self-test should pass");
+    let config =
+        crate::Config::try_load_from(["star-randsrv", "--self-test"]).expect("config should load");
+    assert!(config.self_test);
+    crate::eval::self_test(&config).expect("self-test should pass with defaults");
+}
+
+#[tokio::test]
+async fn evaluations_by_epoch() {
+    let series = format!(
+        "{}{{epoch=\"{EPOCH}\"}}",
+        crate::telemetry::EVALUATIONS_BY_EPOCH
+    );
+    let before = metric_value(&series);
+
+    let points = make_points(3);
+    let payload = json!({ "points": points }).to_string();
+    let request = test_request("/randomness", Some(payload));
+    let response = test_app().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Each evaluated point is counted against the epoch used.
+    let after = metric_value(&series);
+    assert!(
+        after - before >= points.len() as f64,
+        "expected {} more evaluations than {before}, got {after}",
+        points.len()
+    );
+}
+
+#[tokio::test]
+async fn randomness_stream() {
+    let points = make_points(5);
+    let payload = json!({ "points": points }).to_string();
+    let mut request = test_request("/randomness", Some(payload));
+    let accept = "application/x-ndjson".parse().unwrap();
+    request.headers_mut().insert("Accept", accept);
+    let app = test_app();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["Content-Type"], "application/x-ndjson");
+
+    // Collect the stream chunks as they arrive.
+    let mut body = response.into_body();
+    let mut buffer = Vec::new();
+    while let Some(chunk) = hyper::body::HttpBody::data(&mut body).await {
+        buffer.extend_from_slice(&chunk.unwrap());
+    }
+    let lines: Vec<Value> = std::str::from_utf8(&buffer)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("each line should be json"))
+        .collect();
+
+    // The header line comes first, followed by each point.
+    assert_eq!(lines.len(), points.len() + 1);
+    assert_eq!(lines[0]["epoch"], json!(EPOCH));
+    assert_eq!(lines[0]["count"], json!(points.len()));
+    for line in &lines[1..] {
+        let b64point = line["point"].as_str().unwrap();
+        let rawpoint = BASE64.decode(b64point).unwrap();
+        assert_eq!(rawpoint.len(), ppoprf::ppoprf::COMPRESSED_POINT_LEN);
+    }
+
+    // Streamed results should match a regular response.
+    let payload = json!({ "points": points }).to_string();
+    let request = test_request("/randomness", Some(payload));
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let json: Value = serde_json::from_slice(body.as_ref()).unwrap();
+    let streamed: Vec<&Value> = lines[1..].iter().map(|line| &line["point"]).collect();
+    let buffered: Vec<&Value> = json["points"].as_array().unwrap().iter().collect();
+    assert_eq!(streamed, buffered);
+}
+
+/// A stream whose epoch ends part-way through should end with the
+/// same `epoch_changed` error as a chunked batch.
+#[tokio::test]
+async fn rotation_during_stream() {
+    let config = test_config();
+    let server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+    let oprf_state = Arc::new(RwLock::new(server));
+    let app = crate::app(oprf_state.clone(), &config);
+    let points = make_points(crate::eval::CHUNK_SIZE * 2);
+    let payload = json!({ "points": points }).to_string();
+    let mut request = test_request("/randomness", Some(payload));
+    let accept = "application/x-ndjson".parse().unwrap();
+    request.headers_mut().insert("Accept", accept);
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Nothing is read yet, so the stream can't get past the first
+    // chunk before the epoch advances.
+    oprf_state.write().unwrap().advance(&config);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let lines: Vec<Value> = std::str::from_utf8(&body)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("each line should be json"))
+        .collect();
+    let (last, points) = lines[1..].split_last().unwrap();
+    assert_eq!(last["error"]["code"], json!("epoch_changed"));
+    assert_eq!(
+        last["error"]["message"],
+        json!(format!("Epoch {EPOCH} ended during evaluation"))
+    );
+    // Points are evaluated a whole chunk at a time.
+    assert_eq!(points.len() % crate::eval::CHUNK_SIZE, 0);
+    assert!(points.len() < crate::eval::CHUNK_SIZE * 2);
+}
+
+#[tokio::test]
+async fn max_points() {
+    // Check that we can submit the maximum number of points.
+    let points = make_points(crate::MAX_POINTS);
+    verify_batch(&points).await;
+
+    // Requests with more than the maximum number of points
+    // should be rejected.
+    let points = make_points(crate::MAX_POINTS + 1);
+    let payload = json!({ "points": points }).to_string();
+    let request = test_request("/randomness", Some(payload));
+    let response = test_app().oneshot(request).await.unwrap();
+    verify_error(response, StatusCode::BAD_REQUEST, "too_many_points").await;
+}
+
+/// Oversize arrays should be rejected without keeping every element.
+#[tokio::test]
+async fn oversize_arrays() {
+    use crate::handler::BoundedArray;
+
+    // Short elements pack a great many into a small body.
+    let count = 150_000;
+    let points = vec![""; count];
+    let body = json!({ "points": points, "tags": points }).to_string();
+    assert!(body.len() < 2 * 1024 * 1024);
+
+    // Only up to the limit are stored, though all are counted.
+    let array: BoundedArray<String> = serde_json::from_value(json!(points)).unwrap();
+    assert_eq!(array.len(), count);
+    assert_eq!(array.iter().count(), crate::MAX_POINTS);
+    let array: BoundedArray<String> = serde_json::from_str(r#"["a", "b"]"#).unwrap();
+    assert_eq!(array.len(), 2);
+    assert_eq!(array.into_vec(), ["a", "b"]);
+    // Elements beyond the limit must still be well-formed.
+    let mut malformed = serde_json::to_string(&points).unwrap();
+    malformed.insert_str(malformed.len() - 1, ",}");
+    assert!(serde_json::from_str::<BoundedArray<String>>(&malformed).is_err());
+
+    let request = test_request("/randomness", Some(body));
+    let response = test_app().oneshot(request).await.unwrap();
+    verify_error(response, StatusCode::BAD_REQUEST, "too_many_points").await;
+
+    // The same applies to verification.
+    let payload = json!({
+        "points": points,
+        "outputs": points,
+        "proofs": points,
+        "epoch": EPOCH,
+        "publicKey": "",
+    });
+    let request = test_request("/verify", Some(payload.to_string()));
+    let response = test_app().oneshot(request).await.unwrap();
+    verify_error(response, StatusCode::BAD_REQUEST, "too_many_points").await;
+}
+
+#[tokio::test]
+async fn no_points() {
+    // An empty batch is rejected rather than answered.
+    let payload = json!({ "points": [] }).to_string();
+    let request = test_request("/randomness", Some(payload));
+    let response = test_app().oneshot(request).await.unwrap();
+    verify_error(response, StatusCode::BAD_REQUEST, "no_points").await;
+}
+
+#[tokio::test]
+async fn invalid_points() {
+    // Errors with a specific point should report its index.
+    let mut points = make_points(3);
+    points[1] = "not base64!".to_string();
+    let payload = json!({ "points": points }).to_string();
+    let request = test_request("/randomness", Some(payload));
+    let response = test_app().oneshot(request).await.unwrap();
+    let json = verify_error(response, StatusCode::BAD_REQUEST, "invalid_encoding").await;
+    assert_eq!(json["error"]["index"], json!(1));
+
+    points[1] = BASE64.encode([0u8; 5]);
+    let payload = json!({ "points": points }).to_string();
+    let request = test_request("/randomness", Some(payload));
+    let response = test_app().oneshot(request).await.unwrap();
+    let json = verify_error(response, StatusCode::BAD_REQUEST, "invalid_point").await;
+    assert_eq!(json["error"]["index"], json!(1));
+
+    // Points of the right length must still decompress, and
+    // non-canonical encodings are rejected. The second is the
+    // field modulus, which would otherwise encode the identity.
+    let mut modulus = [0xff; 32];
+    modulus[0] = 0xed;
+    modulus[31] = 0x7f;
+    for bytes in [[0xff; 32], modulus] {
+        points[1] = BASE64.encode(bytes);
+        let payload = json!({ "points": points }).to_string();
+        let request = test_request("/randomness", Some(payload.clone()));
+        let response = test_app().oneshot(request).await.unwrap();
+        let json = verify_error(response, StatusCode::BAD_REQUEST, "invalid_point").await;
+        assert_eq!(json["error"]["index"], json!(1));
+
+        // Streams fail before they start.
+        let request = Request::builder()
+            .method("POST")
+            .uri("/randomness")
+            .header("Content-Type", "application/json")
+            .header("Accept", "application/x-ndjson")
+            .body(Body::from(payload))
+            .unwrap();
+        let response = test_app().oneshot(request).await.unwrap();
+        verify_error(response, StatusCode::BAD_REQUEST, "invalid_point").await;
+    }
+
+    // Malformed requests use the same error format.
+    let request = test_request("/randomness", Some("{\"points\": ".to_string()));
+    let response = test_app().oneshot(request).await.unwrap();
+    let json = verify_error(response, StatusCode::BAD_REQUEST, "invalid_request").await;
+    assert!(json["error"].get("index").is_none());
+}
+
+/// Requests without points should say whether the field was
+/// missing or null.
+#[tokio::test]
+async fn missing_points() {
+    for (payload, message) in [
+        (json!({ "epoch": EPOCH }), "points field is required"),
+        (
+            json!({ "points": null, "epoch": EPOCH }),
+            "points must be an array, got null",
+        ),
+    ] {
+        for path in ["/randomness", "/batch"] {
+            let request = test_request(path, Some(payload.to_string()));
+            let response = test_app().oneshot(request).await.unwrap();
+            let json = verify_error(response, StatusCode::BAD_REQUEST, "invalid_request").await;
+            assert_eq!(json["error"]["message"], message);
+        }
+    }
+
+    // Other types are still rejected by the parser.
+    let payload = json!({ "points": "AAAA" }).to_string();
+    let request = test_request("/randomness", Some(payload));
+    let response = test_app().oneshot(request).await.unwrap();
+    verify_error(
+        response,
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "invalid_request",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn content_type() {
+    // Bodies which aren't declared as json are rejected unparsed.
+    let payload = json!({ "points": make_points(1) }).to_string();
+    let request = Request::builder()
+        .method("POST")
+        .uri("/randomness")
+        .header("Content-Type", "text/plain")
+        .body(payload.clone().into())
+        .unwrap();
+    let response = test_app().oneshot(request).await.unwrap();
+    verify_error(
+        response,
+        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        "unsupported_media_type",
+    )
+    .await;
+
+    let request = Request::builder()
+        .method("POST")
+        .uri("/randomness")
+        .body(payload.into())
+        .unwrap();
+    let response = test_app().oneshot(request).await.unwrap();
+    verify_error(
+        response,
+        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        "unsupported_media_type",
+    )
+    .await;
+}
+
+/// Create a binary randomness request from raw points
+fn binary_request(uri: &str, points: &[Vec<u8>]) -> Request<Body> {
+    Request::builder()
+        .method("POST")
+        .uri(uri)
+        .header("Content-Type", "application/octet-stream")
+        .header("Accept", "application/octet-stream")
+        .body(points.concat().into())
+        .unwrap()
+}
+
+#[tokio::test]
+async fn binary_format() {
+    let app = test_app();
+    let points = make_points(3);
+    let raw: Vec<Vec<u8>> = points.iter().map(|p| BASE64.decode(p).unwrap()).collect();
+
+    // The same points as json, for comparison.
+    let payload = json!({ "points": points }).to_string();
+    let request = test_request("/randomness", Some(payload));
+    let response = app.clone().oneshot(request).await.unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    let outputs: Vec<Vec<u8>> = json["points"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|p| BASE64.decode(p.as_str().unwrap()).unwrap())
+        .collect();
+
+    // Binary responses are the epoch, a count and the raw points.
+    let request = binary_request("/randomness", &raw);
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()["Content-Type"],
+        "application/octet-stream"
+    );
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let header_len = crate::handler::BINARY_HEADER_LEN;
+    assert_eq!(body[0], EPOCH);
+    assert_eq!(body[1..header_len], 3u32.to_be_bytes());
+    assert_eq!(body[header_len..], outputs.concat());
+
+    // Either side can be binary on its own.
+    let mut request = binary_request("/randomness", &raw);
+    request.headers_mut().remove("Accept");
+    let response = app.clone().oneshot(request).await.unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(serde_json::from_slice::<Value>(&body).unwrap(), json);
+    let payload = json!({ "points": points }).to_string();
+    let mut request = test_request("/randomness", Some(payload));
+    let accept = "application/octet-stream".parse().unwrap();
+    request.headers_mut().insert("Accept", accept);
+    let response = app.clone().oneshot(request).await.unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(body[header_len..], outputs.concat());
+
+    // The epoch is given in the query.
+    let uri = format!("/randomness?epoch={EPOCH}");
+    let response = app
+        .clone()
+        .oneshot(binary_request(&uri, &raw))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let uri = format!("/randomness?epoch={}", EPOCH + 1);
+    let response = app
+        .clone()
+        .oneshot(binary_request(&uri, &raw))
+        .await
+        .unwrap();
+    verify_error(response, StatusCode::BAD_REQUEST, "epoch_out_of_range").await;
+    let response = app
+        .clone()
+        .oneshot(binary_request("/randomness?epoch=300", &raw))
+        .await
+        .unwrap();
+    verify_error(response, StatusCode::BAD_REQUEST, "invalid_epoch").await;
+
+    // Bodies must hold a whole number of valid points.
+    let mut truncated = raw.clone();
+    truncated[2].pop();
+    let request = binary_request("/randomness", &truncated);
+    let response = app.clone().oneshot(request).await.unwrap();
+    verify_error(response, StatusCode::BAD_REQUEST, "invalid_request").await;
+    let mut invalid = raw.clone();
+    invalid[1] = vec![0xff; ppoprf::ppoprf::COMPRESSED_POINT_LEN];
+    let request = binary_request("/randomness", &invalid);
+    let response = app.clone().oneshot(request).await.unwrap();
+    let json = verify_error(response, StatusCode::BAD_REQUEST, "invalid_point").await;
+    assert_eq!(json["error"]["index"], json!(1));
+    let request = binary_request("/randomness", &[]);
+    let response = app.oneshot(request).await.unwrap();
+    verify_error(response, StatusCode::BAD_REQUEST, "no_points").await;
+}
+
+#[tokio::test]
+async fn hex_encoding() {
+    let app = test_app();
+    let points = make_points(2);
+    let hex_points: Vec<String> = points
+        .iter()
+        .map(|p| hex::encode(BASE64.decode(p).unwrap()))
+        .collect();
+
+    // Hex points should get hex outputs matching the base64 ones.
+    let payload = json!({ "points": points }).to_string();
+    let request = test_request("/randomness", Some(payload));
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let base64_json: Value = serde_json::from_slice(&body).unwrap();
+
+    let payload = json!({ "points": hex_points, "encoding": "hex" }).to_string();
+    let request = test_request("/randomness", Some(payload));
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let hex_json: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(hex_json["epoch"], base64_json["epoch"]);
+    let hex_outputs = hex_json["points"].as_array().unwrap();
+    let base64_outputs = base64_json["points"].as_array().unwrap();
+    assert_eq!(hex_outputs.len(), 2);
+    for (hex_output, base64_output) in hex_outputs.iter().zip(base64_outputs) {
+        let bytes = hex::decode(hex_output.as_str().unwrap()).unwrap();
+        assert_eq!(
+            bytes,
+            BASE64.decode(base64_output.as_str().unwrap()).unwrap()
+        );
+    }
+
+    // Points must all use the requested encoding.
+    let mut mixed = hex_points.clone();
+    mixed[1] = points[1].clone();
+    let payload = json!({ "points": mixed, "encoding": "hex" }).to_string();
+    let request = test_request("/randomness", Some(payload));
+    let response = app.clone().oneshot(request).await.unwrap();
+    let json = verify_error(response, StatusCode::BAD_REQUEST, "invalid_encoding").await;
+    assert_eq!(json["error"]["index"], json!(1));
+
+    // Unknown encodings are rejected.
+    let payload = json!({ "points": points, "encoding": "base32" }).to_string();
+    let request = test_request("/randomness", Some(payload));
+    let response = app.oneshot(request).await.unwrap();
+    verify_error(
+        response,
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "invalid_request",
+    )
+    .await;
+}
+
+/// Large batches release the state lock between chunks, so an
+/// epoch rotation can interleave, failing the request cleanly.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn rotation_during_batch() {
+    let config = test_config();
+    let points = make_points(crate::MAX_POINTS);
+    let payload = json!({ "points": points }).to_string();
+    for uri in ["/randomness", "/batch"] {
+        let server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+        let oprf_state = Arc::new(RwLock::new(server));
+        let app = crate::app(oprf_state.clone(), &config);
+        let request = test_request(uri, Some(payload.clone()));
+        let response = tokio::spawn(app.oneshot(request));
+
+        // Rotate once evaluation is under way, which is when the
+        // request holds the read lock. The write lock should be
+        // granted between chunks rather than after the whole batch.
+        while oprf_state.try_write().is_ok() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        oprf_state.write().unwrap().advance(&config);
+        let response = response.await.unwrap().unwrap();
+        let json = verify_error(response, StatusCode::CONFLICT, "epoch_changed").await;
+        assert_eq!(
+            json["error"]["message"],
+            json!(format!("Epoch {EPOCH} ended during evaluation")),
+            "{uri}"
+        );
+    }
+}
+
+/// Randomness requests only take the read lock, so they proceed
+/// together rather than queueing behind one another. Another thread
+/// holds a read lock throughout, so a request which took the write
+/// lock would wait for it until the evaluation timeout.
+#[tokio::test]
+async fn concurrent_readers() {
+    // With a timeout, each evaluation runs on its own blocking thread.
+    let config = crate::Config {
+        eval_timeout_ms: Some(30_000),
+        ..test_config()
+    };
+    let server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+    let oprf_state = Arc::new(RwLock::new(server));
+    let app = crate::app(oprf_state.clone(), &config);
+    let payload = json!({ "points": make_points(64) }).to_string();
+    let request = || {
+        let request = test_request("/randomness", Some(payload.clone()));
+        app.clone().oneshot(request)
+    };
+
+    let (locked, is_locked) = std::sync::mpsc::channel();
+    let (release, released) = std::sync::mpsc::channel::<()>();
+    let reader = std::thread::spawn({
+        let oprf_state = oprf_state.clone();
+        move || {
+            let _guard = oprf_state.read().unwrap();
+            locked.send(()).unwrap();
+            let _ = released.recv();
+        }
+    });
+    is_locked.recv().unwrap();
+    let (a, b, c, d, e, f, g, h) = tokio::join!(
+        request(),
+        request(),
+        request(),
+        request(),
+        request(),
+        request(),
+        request(),
+        request(),
+    );
+    release.send(()).unwrap();
+    reader.join().unwrap();
+    for response in [a, b, c, d, e, f, g, h] {
+        assert_eq!(
+            response.unwrap().status(),
+            StatusCode::OK,
+            "requests should share the read lock"
+        );
+    }
+}
+
+/// Create a randomness request carrying an idempotency key
+fn idempotent_request(payload: &str, key: &str) -> Request<Body> {
+    let mut request = test_request("/randomness", Some(payload.to_string()));
+    request
+        .headers_mut()
+        .insert("Idempotency-Key", key.parse().unwrap());
+    request
+}
+
+/// Send a request, returning whether it was replayed and its body
+async fn replay_status(app: &crate::Router, request: Request<Body>) -> (bool, axum::body::Bytes) {
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let replayed = response.headers().contains_key("Idempotent-Replayed");
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (replayed, body)
+}
+
+#[tokio::test]
+async fn idempotency_key() {
+    let config = crate::Config {
+        idempotency_ttl_seconds: Some(1),
+        ..test_config()
+    };
+    let app = test_app_with_config(&config);
+    let payload = json!({ "points": make_points(2) }).to_string();
+
+    // A repeated request gets exactly the same bytes back.
+    let (replayed, first) = replay_status(&app, idempotent_request(&payload, "a")).await;
+    assert!(!replayed);
+    verify_randomness_body(first.clone(), 2);
+    let (replayed, second) = replay_status(&app, idempotent_request(&payload, "a")).await;
+    assert!(replayed);
+    assert_eq!(first, second);
+
+    // The key only matches requests with the same body.
+    let other = json!({ "points": make_points(2) }).to_string();
+    let (replayed, body) = replay_status(&app, idempotent_request(&other, "a")).await;
+    assert!(!replayed);
+    assert_ne!(body, first);
+    let (replayed, _) = replay_status(&app, idempotent_request(&payload, "b")).await;
+    assert!(!replayed);
+
+    // Requests without a key are never replayed.
+    let request = test_request("/randomness", Some(payload.clone()));
+    let (replayed, _) = replay_status(&app, request).await;
+    assert!(!replayed);
+
+    // Saved responses expire.
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let (replayed, _) = replay_status(&app, idempotent_request(&payload, "a")).await;
+    assert!(!replayed);
+}
+
+/// A full response cache should free a batch of the oldest
+/// responses at once, rather than one per new response.
+#[tokio::test]
+async fn idempotency_eviction() {
+    let config = crate::Config {
+        idempotency_ttl_seconds: Some(60),
+        idempotency_cache_size: 32,
+        ..test_config()
+    };
+    let app = test_app_with_config(&config);
+    let payload = json!({ "points": make_points(1) }).to_string();
+    for n in 0..32 {
+        let key = n.to_string();
+        let (replayed, _) = replay_status(&app, idempotent_request(&payload, &key)).await;
+        assert!(!replayed);
+    }
+
+    // A new response evicts the two oldest, keeping the rest.
+    let (replayed, _) = replay_status(&app, idempotent_request(&payload, "32")).await;
+    assert!(!replayed);
+    for key in ["2", "31", "32"] {
+        let (replayed, _) = replay_status(&app, idempotent_request(&payload, key)).await;
+        assert!(replayed, "{key} should still be saved");
+    }
+    for key in ["0", "1"] {
+        let (replayed, _) = replay_status(&app, idempotent_request(&payload, key)).await;
+        assert!(!replayed, "{key} should have been evicted");
+    }
+}
+
+/// Create a single-point randomness request from a given client
+fn rate_limit_request(peer: SocketAddr, forwarded_for: Option<&str>) -> Request<Body> {
+    let payload = json!({ "points": make_points(1) }).to_string();
+    let mut request = test_request("/randomness", Some(payload));
+    request.extensions_mut().insert(ConnectInfo(peer));
+    if let Some(forwarded_for) = forwarded_for {
+        let value = forwarded_for.parse().unwrap();
+        request.headers_mut().insert("X-Forwarded-For", value);
+    }
+    request
+}
+
+#[tokio::test]
+async fn rate_limit() {
+    let config = crate::Config {
+        rate_limit_per_second: Some(1),
+        ..test_config()
+    };
+    let app = test_app_with_config(&config);
+    let client: SocketAddr = "192.0.2.1:4000".parse().unwrap();
+    let other: SocketAddr = "192.0.2.2:4000".parse().unwrap();
+
+    // The first request fits within the limit.
+    let response = app
+        .clone()
+        .oneshot(rate_limit_request(client, None))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // An immediate second request should be refused.
+    let response = app
+        .clone()
+        .oneshot(rate_limit_request(client, None))
+        .await
+        .unwrap();
+    let retry_after = response.headers()["Retry-After"].to_str().unwrap();
+    assert_eq!(retry_after, "1");
+    verify_error(response, StatusCode::TOO_MANY_REQUESTS, "rate_limited").await;
+
+    // Other clients have their own budget.
+    let response = app.oneshot(rate_limit_request(other, None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+/// A full client table should free a batch of the least recently
+/// seen clients at once, rather than one per new client.
+#[test]
+fn rate_limit_eviction() {
+    let limiter = crate::ratelimit::RateLimiter::new(1, false);
+    let client = |n: u32| std::net::IpAddr::from(std::net::Ipv4Addr::from(n));
+    let start = std::time::Instant::now();
+    // Fill the table with clients which have used up their budget.
+    for n in 0..65536 {
+        let now = start + Duration::from_micros(n.into());
+        assert!(limiter.check(client(n), now).is_ok());
+    }
+
+    // A new client evicts the 4096 oldest, which start afresh.
+    let now = start + Duration::from_millis(100);
+    assert!(limiter.check(client(65536), now).is_ok());
+    assert!(limiter.check(client(0), now).is_ok());
+    assert!(limiter.check(client(4095), now).is_ok());
+    // Later clients are still tracked, and still limited.
+    assert!(limiter.check(client(4096), now).is_err());
+    assert!(limiter.check(client(65535), now).is_err());
+}
+
+#[tokio::test]
+async fn rate_limit_trust_proxy() {
+    let config = crate::Config {
+        rate_limit_per_second: Some(1),
+        trust_proxy: true,
+        ..test_config()
+    };
+    let app = test_app_with_config(&config);
+    // All requests arrive from the same proxy.
+    let proxy: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+
+    // Distinct forwarded clients shouldn't share a budget.
+    let request = rate_limit_request(proxy, Some("192.0.2.1"));
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let request = rate_limit_request(proxy, Some("192.0.2.2"));
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Only the address appended by our proxy is used, so
+    // a client can't dodge the limit by spoofing an entry.
+    let request = rate_limit_request(proxy, Some("198.51.100.7, 192.0.2.1"));
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // Without trusting the proxy, all of these would be limited
+    // together as the proxy's address.
+    let config = crate::Config {
+        rate_limit_per_second: Some(1),
+        ..test_config()
+    };
+    let app = test_app_with_config(&config);
+    let request = rate_limit_request(proxy, Some("192.0.2.1"));
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let request = rate_limit_request(proxy, Some("192.0.2.2"));
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+/// Repeated evaluation failures should open the circuit breaker,
+/// rejecting requests until the cooldown ends.
+#[tokio::test(start_paused = true)]
+async fn circuit_breaker() {
+    test_recorder();
+    let trips = metric_value(crate::telemetry::CIRCUIT_BREAKER_TRIPS);
+    let config = crate::Config {
+        circuit_breaker_threshold: Some(3),
+        circuit_breaker_cooldown_seconds: 10,
+        ..test_config()
+    };
+    let mut server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+    // Puncturing the current epoch behind the server's back makes
+    // every evaluation fail.
+    server.server.puncture(EPOCH).unwrap();
+    let oprf_state = Arc::new(RwLock::new(server));
+    let app = crate::app(oprf_state.clone(), &config);
+    let request = || {
+        let payload = json!({ "points": make_points(1) }).to_string();
+        test_request("/randomness", Some(payload))
+    };
+
+    // Client errors don't count towards the threshold.
+    for _ in 0..2 {
+        let response = app.clone().oneshot(request()).await.unwrap();
+        verify_error(response, StatusCode::BAD_REQUEST, "evaluation_failed").await;
+    }
+    let payload = json!({ "points": [] }).to_string();
+    let response = app
+        .clone()
+        .oneshot(test_request("/randomness", Some(payload)))
+        .await
+        .unwrap();
+    verify_error(response, StatusCode::BAD_REQUEST, "no_points").await;
+    let response = app.clone().oneshot(request()).await.unwrap();
+    verify_error(response, StatusCode::BAD_REQUEST, "evaluation_failed").await;
+    assert!(metric_value(crate::telemetry::CIRCUIT_BREAKER_TRIPS) >= trips + 1.0);
+
+    // Further requests are rejected without evaluation.
+    let response = app.clone().oneshot(request()).await.unwrap();
+    assert_eq!(response.headers()["Retry-After"], "10");
+    verify_error(response, StatusCode::SERVICE_UNAVAILABLE, "circuit_open").await;
+
+    // After the cooldown, one more failure reopens it.
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    let response = app.clone().oneshot(request()).await.unwrap();
+    verify_error(response, StatusCode::BAD_REQUEST, "evaluation_failed").await;
+    let response = app.clone().oneshot(request()).await.unwrap();
+    verify_error(response, StatusCode::SERVICE_UNAVAILABLE, "circuit_open").await;
+
+    // Once evaluation works again, requests succeed.
+    oprf_state.write().unwrap().rotate_key(&config);
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    let response = app.clone().oneshot(request()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app.oneshot(request()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+/// Keep the only worker of an evaluation queue busy
+/// Returns once the worker has started, along with a sender to
+/// release it and the handle of the blocking request.
+async fn occupy_worker(
+    queue: &Arc<crate::queue::EvalQueue>,
+) -> (
+    std::sync::mpsc::Sender<()>,
+    tokio::task::JoinHandle<Result<(), crate::handler::Error>>,
+) {
+    let (started_tx, started_rx) = tokio::sync::oneshot::channel();
+    let (release_tx, release_rx) = std::sync::mpsc::channel();
+    let queue = queue.clone();
+    let blocker = tokio::spawn(async move {
+        queue
+            .run(move || {
+                started_tx.send(()).unwrap();
+                release_rx.recv().unwrap();
+                Ok(())
+            })
+            .await
+    });
+    started_rx.await.unwrap();
+    (release_tx, blocker)
+}
+
+#[tokio::test]
+async fn eval_queue() {
+    use crate::handler::Error;
+    use crate::queue::EvalQueue;
+
+    const SIZE: usize = 4;
+    let queue = Arc::new(EvalQueue::new(SIZE, 1, Duration::from_secs(10)));
+    let server = OPRFServer::new(&test_config()).expect("Could not initialize PPOPRF state");
+    let oprf_state = Arc::new(RwLock::new(server));
+    let points: Vec<Vec<u8>> = make_points(3)
+        .iter()
+        .map(|point| BASE64.decode(point).unwrap())
+        .collect();
+    let (release, blocker) = occupy_worker(&queue).await;
+
+    // A burst fills the queue while the worker is busy...
+    let queued: Vec<_> = (0..SIZE)
+        .map(|_| {
+            let queue = queue.clone();
+            let state = oprf_state.clone();
+            let points = points.clone();
+            tokio::spawn(async move {
+                queue
+                    .run(move || crate::eval::evaluate_chunked(&state, &points, None))
+                    .await
+            })
+        })
+        .collect();
+    // Let the spawned requests reach the queue.
+    tokio::task::yield_now().await;
+    // ...and anything beyond it is rejected straight away.
+    let result = queue.run(|| Ok(())).await;
+    assert!(matches!(result, Err(Error::QueueFull)));
+
+    // Everything queued completes once the worker is free.
+    release.send(()).unwrap();
+    blocker.await.unwrap().unwrap();
+    for task in queued {
+        let result = task
+            .await
+            .unwrap()
+            .expect("queued evaluation should succeed");
+        assert_eq!(result.epoch, EPOCH);
+        assert_eq!(result.points.len(), points.len());
+    }
+
+    // Requests are evaluated through the queue when configured.
+    let config = crate::Config {
+        eval_queue_size: Some(SIZE as u32),
+        eval_queue_workers: Some(1),
+        ..test_config()
+    };
+    let app = test_app_with_config(&config);
+    let payload = json!({ "points": make_points(2) }).to_string();
+    let response = app
+        .oneshot(test_request("/randomness", Some(payload)))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    verify_randomness_body(body, 2);
+}
+
+#[tokio::test]
+async fn eval_queue_deadline() {
+    use crate::handler::Error;
+    use crate::queue::EvalQueue;
+
+    let queue = Arc::new(EvalQueue::new(1, 1, Duration::from_millis(10)));
+    let (release, blocker) = occupy_worker(&queue).await;
+    let waiting = tokio::spawn({
+        let queue = queue.clone();
+        async move { queue.run(|| Ok(())).await }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    release.send(()).unwrap();
+    blocker.await.unwrap().unwrap();
+
+    // Work which waited too long is rejected without running.
+    let result = waiting.await.unwrap();
+    assert!(matches!(result, Err(Error::QueueTimeout)));
+    let response = axum::response::IntoResponse::into_response(Error::QueueTimeout);
+    verify_error(response, StatusCode::SERVICE_UNAVAILABLE, "queue_timeout").await;
+}
+
+#[tokio::test]
+async fn access_log() {
+    let config = crate::Config {
+        access_log: true,
+        ..test_config()
+    };
+    let app = test_app_with_config(&config);
+    let request = rate_limit_request("192.0.2.1:4000".parse().unwrap(), None);
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let request = test_request("/info", None);
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Lines follow the common log format, plus the duration.
+    let record = crate::access::AccessRecord {
+        client: Some("192.0.2.1".parse().unwrap()),
+        time: OffsetDateTime::from_unix_timestamp(1684125000).unwrap(),
+        method: axum::http::Method::POST,
+        uri: "/randomness".parse().unwrap(),
+        version: axum::http::Version::HTTP_11,
+        status: StatusCode::OK,
+        bytes: Some(1234),
+        duration: Duration::from_micros(2500),
+    };
+    assert_eq!(
+        record.to_string(),
+        "192.0.2.1 - - [15/May/2023:04:30:00 +0000] \"POST /randomness HTTP/1.1\" 200 1234 0.002500"
+    );
+    let record = crate::access::AccessRecord {
+        client: None,
+        bytes: None,
+        ..record
+    };
+    assert_eq!(
+        record.to_string(),
+        "- - - [15/May/2023:04:30:00 +0000] \"POST /randomness HTTP/1.1\" 200 - 0.002500"
+    );
+}
+
+/// Request the metrics endpoint with an optional bearer token
+async fn metrics_status(app: &crate::Router, authorization: Option<&str>) -> StatusCode {
+    let mut builder = Request::builder().uri("/metrics");
+    if let Some(authorization) = authorization {
+        builder = builder.header("Authorization", authorization);
+    }
+    let request = builder.body(Body::empty()).unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    if status == StatusCode::UNAUTHORIZED {
+        assert_eq!(response.headers()["WWW-Authenticate"], "Bearer");
+        verify_error(response, status, "unauthorized").await;
+    }
+    status
+}
+
+#[tokio::test]
+async fn admin_token() {
+    let token = "correct horse battery staple";
+    let app = crate::metrics_app(test_recorder().clone(), Some(token));
+
+    // Only the exact token is accepted.
+    let correct = format!("Bearer {token}");
+    assert_eq!(metrics_status(&app, Some(&correct)).await, StatusCode::OK);
+    // Mismatches are rejected the same way wherever they occur.
+    for wrong in [
+        "Bearer Correct horse battery staple",
+        "Bearer correct horse battery stapler",
+        "Bearer correct horse battery stapl",
+        "Bearer ",
+        "Basic Y29ycmVjdA==",
+        token,
+    ] {
+        assert_eq!(
+            metrics_status(&app, Some(wrong)).await,
+            StatusCode::UNAUTHORIZED,
+            "{wrong:?} should be rejected"
+        );
+    }
+    assert_eq!(metrics_status(&app, None).await, StatusCode::UNAUTHORIZED);
+    assert!(crate::auth::token_matches(token, token));
+    assert!(!crate::auth::token_matches(token, ""));
+
+    // Without a token the endpoint is open as before.
+    let app = crate::metrics_app(test_recorder().clone(), None);
+    assert_eq!(metrics_status(&app, None).await, StatusCode::OK);
+}
+
+/// Fetch the stats endpoint with an optional bearer token
+async fn stats(app: &crate::Router, authorization: Option<&str>) -> Response {
+    let mut builder = Request::builder().uri("/stats");
+    if let Some(authorization) = authorization {
+        builder = builder.header("Authorization", authorization);
+    }
+    let request = builder.body(Body::empty()).unwrap();
+    app.clone().oneshot(request).await.unwrap()
+}
+
+/// Draining should fail `/ready` while still serving requests.
+#[tokio::test]
+async fn drain() {
+    let token = "correct horse battery staple";
+    let config = crate::Config {
+        admin_token: Some(token.into()),
+        ..test_config()
+    };
+    let app = test_app_with_config(&config);
+    let admin = |path: &str, authorization: Option<&str>| {
+        let mut builder = Request::builder().method("POST").uri(path);
+        if let Some(authorization) = authorization {
+            builder = builder.header("Authorization", authorization);
+        }
+        app.clone().oneshot(builder.body(Body::empty()).unwrap())
+    };
+    let ready = || app.clone().oneshot(test_request("/ready", None));
+    let bearer = format!("Bearer {token}");
+
+    assert_eq!(ready().await.unwrap().status(), StatusCode::OK);
+
+    // Only the admin can drain the instance.
+    let response = admin("/admin/drain", None).await.unwrap();
+    verify_error(response, StatusCode::UNAUTHORIZED, "unauthorized").await;
+    assert_eq!(ready().await.unwrap().status(), StatusCode::OK);
+    let response = admin("/admin/drain", Some(&bearer)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = ready().await.unwrap();
+    verify_error(response, StatusCode::SERVICE_UNAVAILABLE, "draining").await;
+
+    // Requests are still served while draining.
+    let payload = json!({ "points": make_points(1) }).to_string();
+    let request = test_request("/randomness", Some(payload));
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = admin("/admin/undrain", Some(&bearer)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(ready().await.unwrap().status(), StatusCode::OK);
+
+    // Without a token to protect them, there are no admin endpoints.
+    let app = test_app();
+    let request = Request::builder()
+        .method("POST")
+        .uri("/admin/drain")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test(start_paused = true)]
+async fn stats_endpoint() {
+    let token = "correct horse battery staple";
+    let base_time = OffsetDateTime::from_unix_timestamp(1684125000).unwrap();
+    let config = crate::Config {
+        epoch_seconds: 3600,
+        epoch_base_time: Some(base_time),
+        admin_token: Some(token.into()),
+        ..test_config()
+    };
+    let server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+    let oprf_state = Arc::new(RwLock::new(server));
+    let app = crate::app(oprf_state.clone(), &config);
+
+    // The admin token is required if configured.
+    let response = stats(&app, None).await;
+    verify_error(response, StatusCode::UNAUTHORIZED, "unauthorized").await;
+
+    for count in [1, 2] {
+        let payload = json!({ "points": make_points(count) }).to_string();
+        let request = test_request("/randomness", Some(payload));
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let authorization = format!("Bearer {token}");
+    let response = stats(&app, Some(&authorization)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    assert!(json["uptimeSeconds"].is_u64());
+    assert_eq!(json["totalRequests"], json!(2));
+    assert_eq!(json["totalPoints"], json!(3));
+    assert_eq!(json["currentEpoch"], json!(EPOCH));
+    assert_eq!(json["epochsRemaining"], json!(EPOCH));
+    assert_eq!(json["lastRotationTime"], Value::Null);
+
+    // Rotations are reported once the epoch loop makes them.
+    let clock = TokioClock {
+        base: base_time + Duration::from_secs(1800),
+        start: tokio::time::Instant::now(),
+    };
+    let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let background_state = oprf_state.clone();
+    tokio::spawn(async move {
+        crate::state::epoch_loop_with_clock(background_state, &config, shutdown_rx, &clock).await
+    });
+    tokio::time::sleep(Duration::from_secs(1801)).await;
+    let response = stats(&app, Some(&authorization)).await;
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    assert!(json["uptimeSeconds"].as_u64().unwrap() >= 1801);
+    assert_eq!(json["currentEpoch"], json!(EPOCH + 1));
+    let rotated = base_time + Duration::from_secs(3600);
+    let reported = json["lastRotationTime"].as_str().unwrap();
+    let reported =
+        OffsetDateTime::parse(reported, &time::format_description::well_known::Rfc3339).unwrap();
+    assert!(reported >= rotated && reported - rotated < Duration::from_secs(1));
+
+    // Without a token the endpoint is open.
+    let app = test_app();
+    assert_eq!(stats(&app, None).await.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn request_timeout() {
+    let config = crate::Config {
+        request_timeout_seconds: Some(1),
+        ..test_config()
+    };
+    let app = test_app_with_config(&config);
+
+    // Promise a body but never send it.
+    let (_sender, body) = Body::channel();
+    let request = Request::builder()
+        .uri("/randomness")
+        .method("POST")
+        .header("Content-Type", "application/json")
+        .body(body)
+        .unwrap();
+    let start = std::time::Instant::now();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert!(start.elapsed() >= Duration::from_secs(1));
+    verify_error(response, StatusCode::REQUEST_TIMEOUT, "request_timeout").await;
+
+    // Prompt requests are unaffected.
+    let request = test_request("/info", None);
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn eval_timeout() {
+    let config = crate::Config {
+        eval_timeout_ms: Some(50),
+        ..test_config()
+    };
+    let server = OPRFServer::new(&config).expect("Could not initialize PPOPRF state");
+    let oprf_state = Arc::new(RwLock::new(server));
+    let app = crate::app(oprf_state.clone(), &config);
+    let request = || {
+        let payload = json!({ "points": make_points(2) }).to_string();
+        test_request("/randomness", Some(payload))
+    };
+
+    // Holding the state lock from another thread stalls evaluation.
+    let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+    let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+    let holder = std::thread::spawn({
+        let oprf_state = oprf_state.clone();
+        move || {
+            let _guard = oprf_state.write().unwrap();
+            locked_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
+        }
+    });
+    locked_rx.recv().unwrap();
+    let response = app.clone().oneshot(request()).await.unwrap();
+    release_tx.send(()).unwrap();
+    holder.join().unwrap();
+    verify_error(response, StatusCode::GATEWAY_TIMEOUT, "evaluation_timeout").await;
+
+    // Prompt evaluations are unaffected.
+    let response = app.oneshot(request()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Cancelled work stops before evaluating anything more.
+    let points: Vec<Vec<u8>> = make_points(2)
+        .iter()
+        .map(|point| BASE64.decode(point).unwrap())
+        .collect();
+    let cancelled = AtomicBool::new(true);
+    let result = crate::eval::evaluate_cancellable(&oprf_state, &points, None, &cancelled);
+    assert!(matches!(result, Err(crate::handler::Error::EvalTimeout)));
+}
+
+#[tokio::test]
+async fn response_mac() {
+    let key = "shared secret";
+    let config = crate::Config {
+        response_hmac_key: Some(key.into()),
+        ..test_config()
+    };
+    let app = test_app_with_config(&config);
+    let payload = json!({ "points": make_points(2) }).to_string();
+
+    let request = test_request("/randomness", Some(payload.clone()));
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let mac = response.headers()["X-Response-MAC"]
+        .to_str()
+        .unwrap()
+        .to_owned();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    verify_randomness_body(body.clone(), 2);
+
+    // Clients with the key can check the body wasn't altered.
+    assert_eq!(crate::mac::response_mac(key.as_bytes(), &body), mac);
+    let mut tampered = body.to_vec();
+    tampered[0] ^= 1;
+    assert_ne!(crate::mac::response_mac(key.as_bytes(), &tampered), mac);
+    assert_ne!(crate::mac::response_mac(b"wrong key", &body), mac);
+
+    // Error responses are signed too.
+    let request = test_request("/randomness", Some(r#"{"points": ["!"]}"#.to_owned()));
+    let response = app.oneshot(request).await.unwrap();
+    let mac = response.headers()["X-Response-MAC"]
+        .to_str()
+        .unwrap()
+        .to_owned();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(crate::mac::response_mac(key.as_bytes(), &body), mac);
+
+    // Signing is opt-in.
+    let request = test_request("/randomness", Some(payload));
+    let response = test_app().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get("X-Response-MAC").is_none());
+}
+
+/// Create a randomness request with optional signature headers
+fn signed_request(payload: &str, client: Option<(&SigningKey, &SigningKey)>) -> Request<Body> {
+    let mut request = test_request("/randomness", Some(payload.to_owned()));
+    // The claimed key and the key actually signing may differ.
+    if let Some((claimed, signer)) = client {
+        let headers = request.headers_mut();
+        let client_key = BASE64.encode(claimed.verifying_key().as_bytes());
+        let signature = BASE64.encode(signer.sign(payload.as_bytes()).to_bytes());
+        headers.insert("X-Client-Key", client_key.parse().unwrap());
+        headers.insert("X-Signature", signature.parse().unwrap());
+    }
+    request
+}
+
+#[tokio::test]
+async fn signed_requests() {
+    let allowed = SigningKey::generate(&mut OsRng);
+    let stranger = SigningKey::generate(&mut OsRng);
+    let path = std::env::temp_dir().join(format!("star-randsrv-keys-{}.txt", std::process::id()));
+    let keys = format!(
+        "# allowed clients\n\n{}\n",
+        BASE64.encode(allowed.verifying_key().as_bytes())
+    );
+    std::fs::write(&path, keys).unwrap();
+    let config = crate::Config {
+        require_signed_requests: true,
+        client_keys: Some(path.clone()),
+        ..test_config()
+    };
+    config.validate().expect("client keys should load");
+    let app = test_app_with_config(&config);
+    std::fs::write(&path, "not a key\n").unwrap();
+    let invalid = config.validate();
+    std::fs::remove_file(&path).unwrap();
+    let payload = json!({ "points": make_points(1) }).to_string();
+
+    // Authorized clients are served.
+    let request = signed_request(&payload, Some((&allowed, &allowed)));
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    verify_randomness_body(body, 1);
+
+    // Unknown signers, forged signatures and unsigned requests aren't.
+    for client in [
+        Some((&stranger, &stranger)),
+        Some((&allowed, &stranger)),
+        None,
+    ] {
+        let request = signed_request(&payload, client);
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.headers()["WWW-Authenticate"], "Signature");
+        verify_error(response, StatusCode::UNAUTHORIZED, "unauthorized").await;
+    }
+    // Signatures cover the whole body.
+    let mut request = signed_request(&payload, Some((&allowed, &allowed)));
+    *request.body_mut() = json!({ "points": make_points(1) }).to_string().into();
+    let response = app.oneshot(request).await.unwrap();
+    verify_error(response, StatusCode::UNAUTHORIZED, "unauthorized").await;
+
+    // Signatures aren't required unless enabled.
+    let request = signed_request(&payload, None);
+    let response = test_app().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // The key file must be present and valid.
+    assert!(invalid.is_err(), "malformed key file should be rejected");
+    let missing = crate::Config {
+        require_signed_requests: true,
+        ..test_config()
+    };
+    assert!(missing.validate().is_err());
 }
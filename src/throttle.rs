@@ -0,0 +1,90 @@
+//! STAR Randomness web service
+//! Limit on the rate new connections are accepted
+
+use hyper::server::accept::Accept;
+use hyper::server::conn::{AddrIncoming, AddrStream};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::time::{Instant, Sleep};
+
+/// Token bucket limiting how fast connections are accepted
+struct Bucket {
+    /// Connections accepted per second
+    rate: f64,
+    /// Connections which may be accepted without waiting
+    tokens: f64,
+    /// Last time `tokens` was refilled
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Refill the bucket at `now`
+    /// Returns how long to wait if no connection may be accepted yet.
+    /// Bursts of up to one second's worth of connections are allowed.
+    fn refill(&mut self, now: Instant) -> Option<Duration> {
+        let elapsed = now.saturating_duration_since(self.updated);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.rate);
+        self.updated = now;
+        (self.tokens < 1.0).then(|| Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+    }
+}
+
+/// Listener which accepts new connections no faster than a set rate
+///
+/// Connections beyond the rate wait in the kernel's accept queue,
+/// sized by `--tcp-backlog`, and are refused once that fills. This
+/// sheds a flood of new connections before they hold a file
+/// descriptor or become request work. Without a rate, connections
+/// are accepted as fast as they arrive.
+pub struct ThrottledIncoming {
+    inner: AddrIncoming,
+    /// Rate limit, if any
+    bucket: Option<Bucket>,
+    /// Wait until the next connection may be accepted
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl ThrottledIncoming {
+    /// Accept up to `per_second` connections per second from `inner`
+    pub fn new(inner: AddrIncoming, per_second: Option<u32>) -> Self {
+        let bucket = per_second.map(|per_second| Bucket {
+            rate: f64::from(per_second),
+            tokens: f64::from(per_second),
+            updated: Instant::now(),
+        });
+        ThrottledIncoming {
+            inner,
+            bucket,
+            delay: None,
+        }
+    }
+}
+
+impl Accept for ThrottledIncoming {
+    type Conn = AddrStream;
+    type Error = std::io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = self.get_mut();
+        if let Some(bucket) = &mut this.bucket {
+            let now = Instant::now();
+            if let Some(wait) = bucket.refill(now) {
+                let delay = this
+                    .delay
+                    .get_or_insert_with(|| Box::pin(tokio::time::sleep(wait)));
+                delay.as_mut().reset(now + wait);
+                ready!(delay.as_mut().poll(cx));
+            }
+        }
+        let result = ready!(Pin::new(&mut this.inner).poll_accept(cx));
+        if let (Some(bucket), Some(Ok(_))) = (&mut this.bucket, &result) {
+            bucket.tokens -= 1.0;
+        }
+        Poll::Ready(result)
+    }
+}
@@ -0,0 +1,31 @@
+//! STAR Randomness web service
+//! Per-request time limits
+
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use std::time::Duration;
+use tracing::debug;
+
+use crate::handler::Error;
+
+/// Middleware aborting requests which take longer than `limit`
+/// This includes time spent waiting for the request body, so a
+/// slow or stalled client can't hold a worker indefinitely. Only
+/// the request task is cancelled; epoch rotation runs separately.
+pub async fn limit(
+    State(limit): State<Duration>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Result<Response, Error> {
+    let uri = request.uri().clone();
+    match tokio::time::timeout(limit, next.run(request)).await {
+        Ok(response) => Ok(response),
+        Err(_) => {
+            debug!("request for {uri} timed out after {limit:?}");
+            Err(Error::Timeout)
+        }
+    }
+}